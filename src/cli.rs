@@ -0,0 +1,631 @@
+//! Headless commands that drive the network without starting the user interface.
+
+use std::{
+    collections::HashMap,
+    io::{self, Write},
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use clap::Subcommand;
+use color_eyre::{
+    eyre::{eyre, WrapErr},
+    Result,
+};
+use tokio::sync::watch;
+
+use crate::{
+    common::{
+        format_bytes, format_duration, format_fingerprint, format_hash, format_meta, AcceptRule,
+        ConflictPolicy, Download, DownloadStatus, Files, LocalFile, Offer, OfferStatus,
+        OutgoingOffer, RemoteFile, SharePolicy, TextStatus,
+    },
+    network::{self, Config, NetworkHandle},
+    some_or_continue,
+};
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Share files, folders and texts until interrupted.
+    Share {
+        #[arg(required_unless_present = "text")]
+        paths: Vec<PathBuf>,
+        /// Share a text, like a URL or a token, that peers copy instead of downloading. Can be repeated.
+        #[arg(long)]
+        text: Vec<String>,
+        /// Protect the files with one-time passwords.
+        #[arg(long, conflicts_with = "allow")]
+        password: bool,
+        /// Only share with the paired peer whose fingerprint starts with this, can be repeated.
+        #[arg(long)]
+        allow: Vec<String>,
+    },
+    /// List the files that peers on the network are sharing.
+    List {
+        /// Seconds to listen for peers before printing.
+        #[arg(short, long, default_value_t = 3)]
+        wait: u64,
+    },
+    /// Download a file that a peer is sharing, or print a text it is sharing.
+    Get {
+        /// The peer, either its device name, its device id, `ip` or `ip:port`.
+        peer: String,
+        file: String,
+        #[arg(short, long, default_value = ".")]
+        output: PathBuf,
+        /// Seconds to wait for the peer to show up.
+        #[arg(short, long, default_value_t = 10)]
+        timeout: u64,
+        /// Password of a file that is protected by one.
+        #[arg(long)]
+        password: Option<String>,
+        /// What to do if the file already exists in the output folder.
+        #[arg(long, value_enum, default_value_t)]
+        conflict: ConflictPolicy,
+    },
+    /// Offer files and folders to a peer, which receives them if its user accepts them.
+    Send {
+        /// The peer, either its device name, its device id, `ip` or `ip:port`.
+        peer: String,
+        #[arg(required = true)]
+        paths: Vec<PathBuf>,
+        /// Seconds to wait for the peer to show up, unless it is given by address.
+        #[arg(short, long, default_value_t = 10)]
+        timeout: u64,
+    },
+    /// Receive the files that peers offer until interrupted, asking before each one.
+    Receive {
+        #[arg(short, long, default_value = ".")]
+        output: PathBuf,
+        /// What to do if a file already exists in the output folder.
+        #[arg(long, value_enum, default_value_t)]
+        conflict: ConflictPolicy,
+    },
+    /// Accept the files a paired peer offers without asking, or list the peers that files are accepted from.
+    Accept {
+        /// The paired peer whose fingerprint starts with this.
+        peer: Option<String>,
+        /// The folder to store the files in, in a subfolder named after the peer. Defaults to `shary` in the
+        /// Downloads folder.
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+        /// The size in megabytes of the largest file to accept without asking.
+        #[arg(long)]
+        max_size: Option<u64>,
+        /// What to do if a file already exists in the folder.
+        #[arg(long, value_enum, default_value_t)]
+        conflict: ConflictPolicy,
+        /// Ask again before accepting files from the peer.
+        #[arg(long, requires = "peer", conflicts_with_all = ["output", "max_size"])]
+        remove: bool,
+    },
+    /// Print the name of this device, or change it.
+    Name { name: Option<String> },
+    /// List the downloads that have finished, oldest first.
+    History {
+        /// Forget the downloads instead of listing them.
+        #[arg(long)]
+        clear: bool,
+    },
+}
+
+pub fn run(command: Command, config: Config) -> Result<()> {
+    let files = Arc::new(Files::load());
+    match command {
+        Command::Share {
+            paths,
+            text,
+            password,
+            allow,
+        } => {
+            let policy = if password {
+                SharePolicy::password()
+            } else if !allow.is_empty() {
+                SharePolicy::Peers(find_paired_peers(&files, &allow)?)
+            } else {
+                SharePolicy::Public
+            };
+            share(files, config, paths, text, policy)
+        }
+        Command::List { wait } => list(files, config, Duration::from_secs(wait)),
+        Command::Get {
+            peer,
+            file,
+            output,
+            timeout,
+            password,
+            conflict,
+        } => {
+            let network = network::spawn(config, files.clone())?;
+            let remote_file =
+                find_remote_file(&network, &files, &peer, &file, Duration::from_secs(timeout))?;
+            if remote_file.meta.as_ref().is_some_and(|meta| meta.is_text) {
+                return get_text(&network, &files, remote_file, password);
+            }
+            let download = Download {
+                remote_file,
+                path: output,
+                password,
+                conflict,
+            };
+            get(&network, &files, download)
+        }
+        Command::Send {
+            peer,
+            paths,
+            timeout,
+        } => send(files, config, &peer, paths, Duration::from_secs(timeout)),
+        Command::Receive { output, conflict } => receive(files, config, output, conflict),
+        Command::Accept {
+            peer,
+            output,
+            max_size,
+            conflict,
+            remove,
+        } => match peer {
+            Some(peer) => accept(&files, &peer, output, max_size, conflict, remove),
+            None => {
+                for rule in files.get_accept_rules().borrow().iter() {
+                    print_accept_rule(rule);
+                }
+                Ok(())
+            }
+        },
+        Command::Name { name } => {
+            if let Some(name) = name {
+                if name.trim().is_empty() {
+                    return Err(eyre!("the device name can not be empty"));
+                }
+                files.set_device_name(&name);
+            }
+            let device = files.get_device().borrow().clone();
+            println!("{}\t{}", device.name, device.id);
+            Ok(())
+        }
+        Command::History { clear } => {
+            if clear {
+                files.clear_history();
+            } else {
+                history(&files);
+            }
+            Ok(())
+        }
+    }
+}
+
+fn share(
+    files: Arc<Files>,
+    config: Config,
+    paths: Vec<PathBuf>,
+    texts: Vec<String>,
+    policy: SharePolicy,
+) -> Result<()> {
+    let mut local_files = vec![];
+    for path in paths {
+        let local_file = LocalFile::new(path.clone())
+            .wrap_err_with(|| format!("failed to share {}", path.display()))?;
+        local_files.push(local_file);
+    }
+    for text in texts {
+        local_files.push(LocalFile::text(text).wrap_err("failed to share text")?);
+    }
+    for mut local_file in local_files {
+        *local_file.policy_mut() = policy.clone();
+        // Texts are numbered when they are added, so the name is printed afterwards.
+        if files.add_local_file(local_file) {
+            if let Some(added) = files.get_local_files().borrow().last() {
+                println!("Sharing {}", added.name());
+            }
+        }
+    }
+    let mut local_files = files.get_local_files();
+    let mut local_hashes = files.get_local_hashes();
+    let mut printed_passwords = HashMap::new();
+    let mut printed_hashes = HashMap::new();
+    let network = network::spawn(config, files)?;
+    network.block_on(async {
+        loop {
+            let shared = local_files.borrow_and_update().clone();
+            // Passwords are replaced when they are used, so print them whenever they change.
+            for local_file in shared.iter() {
+                if let SharePolicy::Password { password, .. } = local_file.policy() {
+                    let name = local_file.name().to_owned();
+                    if printed_passwords.insert(name, password.clone()).as_ref() != Some(password) {
+                        println!("Password for {}: {}", local_file.name(), password);
+                    }
+                }
+            }
+            for (path, hash) in local_hashes.borrow_and_update().iter() {
+                let local_file = some_or_continue!(shared.iter().find(|f| f.path() == Some(path)));
+                if printed_hashes.insert(path.clone(), hash.clone()).as_ref() != Some(hash) {
+                    println!("BLAKE3 of {}: {}", local_file.name(), hash);
+                }
+            }
+            tokio::select! {
+                result = tokio::signal::ctrl_c() => return result.wrap_err("failed to wait for ctrl-c"),
+                result = local_files.changed() => result.wrap_err("local files sender closed")?,
+                result = local_hashes.changed() => result.wrap_err("local hashes sender closed")?,
+            }
+        }
+    })
+}
+
+fn accept(
+    files: &Files,
+    peer: &str,
+    output: Option<PathBuf>,
+    max_size: Option<u64>,
+    conflict: ConflictPolicy,
+    remove: bool,
+) -> Result<()> {
+    let fingerprint = find_paired_peers(files, &[peer.to_owned()])?.remove(0);
+    if remove {
+        files.remove_accept_rule(&fingerprint);
+        println!(
+            "Asking before accepting files from {}",
+            format_fingerprint(&fingerprint)
+        );
+        return Ok(());
+    }
+    let mut rule = AcceptRule::new(fingerprint);
+    if let Some(output) = output {
+        rule.folder = std::env::current_dir()
+            .wrap_err("failed to get the current folder")?
+            .join(output);
+    }
+    rule.max_size = max_size.map(|megabytes| megabytes * 1_000_000);
+    rule.conflict = conflict;
+    files.set_accept_rule(rule.clone());
+    print_accept_rule(&rule);
+    Ok(())
+}
+
+fn print_accept_rule(rule: &AcceptRule) {
+    let max_size = match rule.max_size {
+        Some(max_size) => format!("up to {}", format_bytes(max_size)),
+        None => String::from("any size"),
+    };
+    println!(
+        "{}\t{}\t{}\t{}",
+        format_fingerprint(&rule.fingerprint),
+        max_size,
+        rule.conflict,
+        rule.folder.display()
+    );
+}
+
+/// Returns the fingerprints of the paired peers that start with the given prefixes.
+fn find_paired_peers(files: &Files, prefixes: &[String]) -> Result<Vec<String>> {
+    let paired_peers = files.get_paired_peers().borrow().clone();
+    let mut fingerprints = vec![];
+    for prefix in prefixes {
+        let prefix = prefix.replace(' ', "").to_lowercase();
+        let matches: Vec<_> = paired_peers
+            .iter()
+            .filter(|p| p.fingerprint.starts_with(&prefix))
+            .collect();
+        match matches.as_slice() {
+            [peer] => fingerprints.push(peer.fingerprint.clone()),
+            [] => return Err(eyre!("no paired peer has the fingerprint {}", prefix)),
+            _ => {
+                return Err(eyre!(
+                    "more than one paired peer has the fingerprint {}",
+                    prefix
+                ))
+            }
+        }
+    }
+    Ok(fingerprints)
+}
+
+fn list(files: Arc<Files>, config: Config, wait: Duration) -> Result<()> {
+    // Subscribe first, the discovery stops when nobody receives the remote files.
+    let remote_files_rx = files.get_remote_files();
+    let network = network::spawn(config, files.clone())?;
+    network.block_on(async { tokio::time::sleep(wait).await });
+    let mut remote_files = remote_files_rx.borrow().to_vec();
+    remote_files.sort_by(|a, b| {
+        (&a.device_name, &a.device_id, &a.file).cmp(&(&b.device_name, &b.device_id, &b.file))
+    });
+    for remote_file in remote_files {
+        let trust = if !remote_file.is_compatible() {
+            "update-required"
+        } else if files.is_paired(&remote_file.fingerprint) {
+            "paired"
+        } else {
+            "unpaired"
+        };
+        let size = match &remote_file.meta {
+            Some(meta) => format_meta(meta),
+            None => String::from("-"),
+        };
+        println!(
+            "{}\t{}\t{}\t{}\t{}\t{}\t{}",
+            remote_file.device_name,
+            remote_file
+                .addrs
+                .first()
+                .map_or(String::from("-"), ToString::to_string),
+            remote_file.interface.as_deref().unwrap_or("-"),
+            format_fingerprint(&remote_file.fingerprint),
+            trust,
+            size,
+            remote_file.file
+        );
+    }
+    Ok(())
+}
+
+fn history(files: &Files) {
+    let now = SystemTime::now();
+    for transfer in files.get_history().borrow().iter() {
+        let ago = now.duration_since(transfer.finished).unwrap_or_default();
+        println!(
+            "{} ago\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+            format_duration(ago),
+            transfer.peer,
+            transfer.file,
+            transfer.size.map(format_bytes).as_deref().unwrap_or("-"),
+            format_duration(transfer.duration),
+            transfer
+                .hash
+                .as_deref()
+                .map(format_hash)
+                .as_deref()
+                .unwrap_or("-"),
+            transfer.outcome,
+            transfer.path.display()
+        );
+    }
+}
+
+fn get(network: &NetworkHandle, files: &Files, download: Download) -> Result<()> {
+    let remote_file = download.remote_file.clone();
+    if let Some(msg) = remote_file.update_required() {
+        return Err(eyre!(msg));
+    }
+    let mut statuses = files.get_download_statuses();
+    files.add_download(download);
+    let status = network.block_on(wait_for(&mut statuses, |statuses| {
+        match statuses.get(&remote_file) {
+            Some(status) if status.is_active() => None,
+            Some(status) => Some(status.clone()),
+            None => None,
+        }
+    }))?;
+    match status {
+        DownloadStatus::Failed(msg) => Err(eyre!("download failed: {}", msg)),
+        DownloadStatus::Refused(refusal) => Err(eyre!("download refused: {}", refusal)),
+        DownloadStatus::Cancelled => Err(eyre!("download cancelled")),
+        DownloadStatus::Completed(Some(hash)) => {
+            println!("Downloaded {}, verified BLAKE3 {}", remote_file.file, hash);
+            Ok(())
+        }
+        _ => {
+            println!("Downloaded {}", remote_file.file);
+            Ok(())
+        }
+    }
+}
+
+/// Fetches a text a peer shares and prints it.
+fn get_text(
+    network: &NetworkHandle,
+    files: &Files,
+    remote_file: RemoteFile,
+    password: Option<String>,
+) -> Result<()> {
+    let mut statuses = files.get_text_statuses();
+    files.fetch_text(remote_file.clone(), password);
+    let text = network.block_on(wait_for(&mut statuses, |statuses| {
+        match statuses.get(&remote_file)? {
+            TextStatus::Fetching => None,
+            TextStatus::Fetched(text) => Some(Ok(text.clone())),
+            TextStatus::Failed(msg) => Some(Err(eyre!("fetching the text failed: {}", msg))),
+            TextStatus::Refused(refusal) => {
+                Some(Err(eyre!("fetching the text was refused: {}", refusal)))
+            }
+        }
+    }))??;
+    println!("{}", text);
+    Ok(())
+}
+
+fn send(
+    files: Arc<Files>,
+    config: Config,
+    peer: &str,
+    paths: Vec<PathBuf>,
+    timeout: Duration,
+) -> Result<()> {
+    let mut local_files = vec![];
+    for path in paths {
+        let local_file = LocalFile::new(path.clone())
+            .wrap_err_with(|| format!("failed to send {}", path.display()))?;
+        local_files.push(local_file);
+    }
+    let default_port = config.port;
+    let network = network::spawn(config, files.clone())?;
+    let (name, addrs, fingerprint) = match parse_addr(peer, default_port) {
+        Some(addr) => (peer.to_owned(), vec![addr], None),
+        None => {
+            let remote_file = find_peer(&network, &files, peer, timeout)?;
+            (
+                remote_file.device_name,
+                remote_file.addrs,
+                Some(remote_file.fingerprint),
+            )
+        }
+    };
+    let count = local_files.len();
+    for local_file in local_files {
+        println!("Offering {} to {}", local_file.name(), name);
+        files.offer_file(OutgoingOffer {
+            peer: name.clone(),
+            addrs: addrs.clone(),
+            fingerprint: fingerprint.clone(),
+            local_file,
+        });
+    }
+    let mut offers = files.get_offers();
+    let offers = network.block_on(wait_for(&mut offers, |offers| {
+        let sent: Vec<Offer> = offers.iter().filter(|o| o.outgoing).cloned().collect();
+        (sent.len() == count && sent.iter().all(|o| o.status.is_finished())).then_some(sent)
+    }))?;
+    let mut unsent = 0;
+    for offer in offers {
+        match offer.status {
+            OfferStatus::Completed(_) => println!("Sent {}", offer.file),
+            OfferStatus::Failed(msg) => {
+                unsent += 1;
+                println!("Failed to send {}: {}", offer.file, msg);
+            }
+            _ => {
+                unsent += 1;
+                println!("{} declined {}", offer.peer, offer.file);
+            }
+        }
+    }
+    if unsent > 0 {
+        return Err(eyre!("{} of {} files were not sent", unsent, count));
+    }
+    Ok(())
+}
+
+fn receive(
+    files: Arc<Files>,
+    config: Config,
+    output: PathBuf,
+    conflict: ConflictPolicy,
+) -> Result<()> {
+    let mut offers = files.get_offers();
+    let network = network::spawn(config, files.clone())?;
+    println!("Receiving files into {}", output.display());
+    network.block_on(async {
+        loop {
+            let incoming: Vec<Offer> = offers
+                .borrow_and_update()
+                .iter()
+                .filter(|o| !o.outgoing)
+                .cloned()
+                .collect();
+            for offer in incoming {
+                match offer.status {
+                    OfferStatus::Pending => {
+                        let question = format!(
+                            "{} ({}) offers {} ({}). Accept? [y/N] ",
+                            offer.peer,
+                            format_fingerprint(&offer.fingerprint),
+                            offer.file,
+                            format_meta(&offer.meta)
+                        );
+                        let accepted = tokio::task::spawn_blocking(move || confirm(&question))
+                            .await
+                            .wrap_err("failed to ask for confirmation")?
+                            .wrap_err("failed to read the answer")?;
+                        files.decide_offer(offer.id, accepted.then(|| (output.clone(), conflict)));
+                    }
+                    OfferStatus::Completed(hash) => {
+                        match hash {
+                            Some(hash) => println!("Received {}, verified BLAKE3 {}", offer.file, hash),
+                            None => println!("Received {}", offer.file),
+                        }
+                        files.remove_offer(offer.id);
+                    }
+                    OfferStatus::Failed(msg) => {
+                        println!("Failed to receive {}: {}", offer.file, msg);
+                        files.remove_offer(offer.id);
+                    }
+                    _ => {}
+                }
+            }
+            tokio::select! {
+                result = tokio::signal::ctrl_c() => return result.wrap_err("failed to wait for ctrl-c"),
+                result = offers.changed() => result.wrap_err("offers sender closed")?,
+            }
+        }
+    })
+}
+
+/// Asks a yes or no question on the terminal.
+fn confirm(question: &str) -> io::Result<bool> {
+    print!("{}", question);
+    io::stdout().flush()?;
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer)?;
+    Ok(matches!(answer.trim().to_lowercase().as_str(), "y" | "yes"))
+}
+
+/// Parses `ip`, `ip:port` or `[ipv6]:port`, using `default_port` if there is no port.
+fn parse_addr(peer: &str, default_port: u16) -> Option<SocketAddr> {
+    if let Ok(addr) = peer.parse() {
+        return Some(addr);
+    }
+    let ip: IpAddr = peer
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .parse()
+        .ok()?;
+    Some(SocketAddr::new(ip, default_port))
+}
+
+/// Finds a device with at least one shared file by its name, id or address.
+fn find_peer(
+    network: &NetworkHandle,
+    files: &Files,
+    peer: &str,
+    timeout: Duration,
+) -> Result<RemoteFile> {
+    let mut remote_files = files.get_remote_files();
+    let find = wait_for(&mut remote_files, |remote_files| {
+        remote_files.iter().find(|r| matches_peer(r, peer)).cloned()
+    });
+    match network.block_on(async { tokio::time::timeout(timeout, find).await }) {
+        Ok(result) => result,
+        Err(_) => Err(eyre!("{} was not found", peer)),
+    }
+}
+
+fn find_remote_file(
+    network: &NetworkHandle,
+    files: &Files,
+    peer: &str,
+    file: &str,
+    timeout: Duration,
+) -> Result<RemoteFile> {
+    let mut remote_files = files.get_remote_files();
+    let find = wait_for(&mut remote_files, |remote_files| {
+        remote_files
+            .iter()
+            .find(|r| r.file == file && matches_peer(r, peer))
+            .cloned()
+    });
+    match network.block_on(async { tokio::time::timeout(timeout, find).await }) {
+        Ok(result) => result,
+        Err(_) => Err(eyre!("{} is not shared by {}", file, peer)),
+    }
+}
+
+fn matches_peer(remote_file: &RemoteFile, peer: &str) -> bool {
+    remote_file.device_name == peer
+        || remote_file.device_id == peer
+        || remote_file
+            .addrs
+            .iter()
+            .any(|addr| addr.to_string() == peer || addr.ip().to_string() == peer)
+}
+
+/// Waits until `f` returns [Some] for the current value of the channel.
+async fn wait_for<T, R>(
+    rx: &mut watch::Receiver<T>,
+    mut f: impl FnMut(&T) -> Option<R>,
+) -> Result<R> {
+    loop {
+        if let Some(result) = f(&rx.borrow_and_update()) {
+            return Ok(result);
+        }
+        rx.changed().await.wrap_err("channel sender closed")?;
+    }
+}
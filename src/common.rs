@@ -1,76 +1,970 @@
-use std::{net::SocketAddr, path::PathBuf, sync::Arc, collections::HashMap};
+use std::{
+    collections::HashMap,
+    fmt,
+    hash::{Hash, Hasher},
+    io,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
 
-use color_eyre::{Result, eyre::eyre};
-use tokio::sync::{broadcast, watch};
+use color_eyre::{
+    eyre::{eyre, WrapErr},
+    Result,
+};
+use serde::{Deserialize, Serialize};
+use tokio::sync::{broadcast, mpsc, watch, Mutex};
 
+use crate::{network::PROTOCOL_VERSION, storage};
+
+const PAIRED_PEERS_FILE: &str = "paired_peers.json";
+const DEVICE_FILE: &str = "device.json";
+const MANUAL_PEERS_FILE: &str = "manual_peers.json";
+const HISTORY_FILE: &str = "history.json";
+const ACCEPT_RULES_FILE: &str = "accept_rules.json";
+/// How many transfers the history keeps, older ones are forgotten.
+const MAX_HISTORY_LEN: usize = 500;
+const DEVICE_ID_LEN: usize = 16;
+const DEVICE_ID_CHARSET: &str = "0123456789abcdef";
+/// The longest device name that is accepted, so that names fit in discovery packets and the UI.
+pub const MAX_DEVICE_NAME_LEN: usize = 64;
+const PASSWORD_LEN: usize = 8;
+/// Characters used for share passwords, leaving out the ones that are easily confused.
+const PASSWORD_CHARSET: &str = "abcdefghjkmnpqrstuvwxyz23456789";
+/// How many wrong passwords one peer may try before it is refused without checking.
+const MAX_PEER_PASSWORD_FAILURES: usize = 3;
+/// How many wrong passwords are tried in total before the password is replaced.
+const MAX_PASSWORD_FAILURES: usize = 10;
+/// How many offers of one peer may wait for the user to decide, further offers are refused.
+const MAX_PEER_PENDING_OFFERS: usize = 2;
+/// How many offers may wait for the user to decide in total.
+const MAX_PENDING_OFFERS: usize = 8;
+/// The longest text that can be shared, so that it fits in one frame even when every character is escaped.
+pub const MAX_TEXT_LEN: usize = 128 * 1024;
+
+/// Something this device shares.
 #[derive(Eq, PartialEq, Clone, Debug)]
-pub struct LocalFile {
-    pub path: PathBuf,
-    pub name: String,
+pub enum LocalFile {
+    /// A file or folder, which peers download.
+    Path {
+        path: PathBuf,
+        name: String,
+        meta: FileMeta,
+        policy: SharePolicy,
+    },
+    /// A snippet like a URL or a token, which peers copy to their clipboard.
+    Text {
+        text: String,
+        name: String,
+        meta: FileMeta,
+        policy: SharePolicy,
+    },
 }
 
 impl LocalFile {
     pub fn new(path: PathBuf) -> Result<LocalFile> {
-        let file_name = path.file_name().ok_or(eyre!("no filename found for path"))?;
+        let file_name = path
+            .file_name()
+            .ok_or(eyre!("no filename found for path"))?;
         let os_str = file_name.to_str().ok_or(eyre!("filename not valid utf8"))?;
         let name = os_str.to_owned();
-        Ok(LocalFile { path, name })
+        let meta = FileMeta::read(&path).wrap_err("failed to read file metadata")?;
+        Ok(LocalFile::Path {
+            path,
+            name,
+            meta,
+            policy: SharePolicy::Public,
+        })
+    }
+
+    /// Shares a text. Its name is neutral, since a token or password in it should not show up in the list
+    /// that anyone can see, and it is numbered when it is added to [Files].
+    pub fn text(text: String) -> Result<LocalFile> {
+        if text.trim().is_empty() {
+            return Err(eyre!("the text is empty"));
+        }
+        if text.len() > MAX_TEXT_LEN {
+            return Err(eyre!(
+                "the text is larger than {}",
+                format_bytes(MAX_TEXT_LEN as u64)
+            ));
+        }
+        let modified = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .ok()
+            .map(|duration| duration.as_secs());
+        let meta = FileMeta {
+            size: text.len() as u64,
+            is_dir: false,
+            file_count: 1,
+            modified,
+            is_text: true,
+        };
+        Ok(LocalFile::Text {
+            text,
+            name: String::from("Text"),
+            meta,
+            policy: SharePolicy::Public,
+        })
+    }
+
+    /// The name peers see the share as.
+    pub fn name(&self) -> &str {
+        match self {
+            LocalFile::Path { name, .. } | LocalFile::Text { name, .. } => name,
+        }
+    }
+
+    pub fn meta(&self) -> &FileMeta {
+        match self {
+            LocalFile::Path { meta, .. } | LocalFile::Text { meta, .. } => meta,
+        }
+    }
+
+    pub fn policy(&self) -> &SharePolicy {
+        match self {
+            LocalFile::Path { policy, .. } | LocalFile::Text { policy, .. } => policy,
+        }
+    }
+
+    pub fn policy_mut(&mut self) -> &mut SharePolicy {
+        match self {
+            LocalFile::Path { policy, .. } | LocalFile::Text { policy, .. } => policy,
+        }
+    }
+
+    /// The path of a shared file or folder, [None] for a text.
+    pub fn path(&self) -> Option<&Path> {
+        match self {
+            LocalFile::Path { path, .. } => Some(path),
+            LocalFile::Text { .. } => None,
+        }
+    }
+
+    /// Whether both are the same share, a file or folder at the same path or the same text, even if their
+    /// policies differ.
+    pub fn is_same(&self, other: &LocalFile) -> bool {
+        match (self, other) {
+            (LocalFile::Path { path, .. }, LocalFile::Path { path: other, .. }) => path == other,
+            (LocalFile::Text { text, .. }, LocalFile::Text { text: other, .. }) => text == other,
+            _ => false,
+        }
     }
 }
 
-#[derive(Eq, PartialEq, Clone, Debug, Hash)]
+/// What receivers are told about a shared file or folder before they download it.
+#[derive(Eq, PartialEq, Clone, Debug, Hash, Default, Serialize, Deserialize)]
+pub struct FileMeta {
+    /// The total size of the files in bytes.
+    pub size: u64,
+    pub is_dir: bool,
+    /// The number of files, not counting folders.
+    pub file_count: u64,
+    /// The latest modification time of the files in seconds since the unix epoch.
+    pub modified: Option<u64>,
+    /// Whether the share is a text, which is fetched with its content instead of downloaded.
+    /// Left out for files, so that their metadata takes no more room in discovery than before.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub is_text: bool,
+}
+
+impl FileMeta {
+    /// Reads the metadata of a file, or of all files in a folder, like downloads list them: links to files
+    /// are followed, links to folders and entries that can not be read are left out.
+    /// This walks the whole folder, so it should not run on the UI thread.
+    pub fn read(path: &Path) -> io::Result<FileMeta> {
+        let metadata = std::fs::metadata(path)?;
+        let mut meta = FileMeta {
+            is_dir: metadata.is_dir(),
+            ..FileMeta::default()
+        };
+        if metadata.is_dir() {
+            meta.add_dir(path)?;
+        } else {
+            meta.add_file(&metadata);
+        }
+        Ok(meta)
+    }
+
+    fn add_dir(&mut self, path: &Path) -> io::Result<()> {
+        for entry in std::fs::read_dir(path)? {
+            let path = match entry {
+                Ok(entry) => entry.path(),
+                Err(err) => {
+                    tracing::warn!("Leaving out an entry of {}: {}", path.display(), err);
+                    continue;
+                }
+            };
+            match std::fs::metadata(&path) {
+                // A link to a folder could lead back to a folder that contains it.
+                Ok(metadata) if metadata.is_dir() && path.is_symlink() => {
+                    tracing::debug!("Leaving out {}, it links to a folder", path.display());
+                }
+                Ok(metadata) if metadata.is_dir() => {
+                    if let Err(err) = self.add_dir(&path) {
+                        tracing::warn!("Leaving out {}: {}", path.display(), err);
+                    }
+                }
+                Ok(metadata) => self.add_file(&metadata),
+                Err(err) => tracing::warn!("Leaving out {}: {}", path.display(), err),
+            }
+        }
+        Ok(())
+    }
+
+    fn add_file(&mut self, metadata: &std::fs::Metadata) {
+        self.size += metadata.len();
+        self.file_count += 1;
+        let modified = metadata
+            .modified()
+            .ok()
+            .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+            .map(|duration| duration.as_secs());
+        self.modified = self.modified.max(modified);
+    }
+
+    /// How long ago the files were last modified.
+    pub fn age(&self) -> Option<Duration> {
+        let modified = UNIX_EPOCH + Duration::from_secs(self.modified?);
+        SystemTime::now().duration_since(modified).ok()
+    }
+}
+
+/// Who may download a local file.
+#[derive(Eq, PartialEq, Clone, Debug, Default)]
+pub enum SharePolicy {
+    /// Anyone on the network.
+    #[default]
+    Public,
+    /// Only the paired peers with these fingerprints.
+    Peers(Vec<String>),
+    /// Anyone who knows the password. A password only works once and is replaced when it is used,
+    /// while the peer that used it keeps access, so that it can resume the download.
+    /// A password that is guessed wrong too often is replaced as well.
+    Password {
+        password: String,
+        granted: Vec<String>,
+        /// The fingerprint of the peer of every wrong guess since the password was chosen.
+        failures: Vec<String>,
+    },
+}
+
+impl SharePolicy {
+    /// Creates a password policy with a new random password.
+    pub fn password() -> SharePolicy {
+        SharePolicy::Password {
+            password: random_string::generate(PASSWORD_LEN, PASSWORD_CHARSET),
+            granted: vec![],
+            failures: vec![],
+        }
+    }
+}
+
+/// Compares the hashes of two passwords, whose comparison takes the same time wherever they differ.
+fn same_password(given: &str, expected: &str) -> bool {
+    blake3::hash(given.as_bytes()) == blake3::hash(expected.as_bytes())
+}
+
+/// Why a peer refused to send a file.
+#[derive(Eq, PartialEq, Clone, Debug, Hash, Serialize, Deserialize)]
+pub enum Refusal {
+    /// The file is only shared with some paired peers.
+    NotAllowed,
+    PasswordRequired,
+    WrongPassword,
+    /// The peer guessed the password wrong too often.
+    TooManyAttempts,
+}
+
+impl fmt::Display for Refusal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let msg = match self {
+            Refusal::NotAllowed => "the file is not shared with this device",
+            Refusal::PasswordRequired => "the file is protected by a password",
+            Refusal::WrongPassword => "the password is wrong or has already been used",
+            Refusal::TooManyAttempts => "the password was guessed wrong too often",
+        };
+        f.write_str(msg)
+    }
+}
+
+impl std::error::Error for Refusal {}
+
+/// This instance as it is shown to peers.
+#[derive(Eq, PartialEq, Clone, Debug, Serialize, Deserialize)]
+pub struct Device {
+    /// A random id that stays the same across runs and address changes.
+    pub id: String,
+    /// The name the user chose.
+    pub name: String,
+}
+
+impl Device {
+    pub fn generate() -> Device {
+        Device {
+            id: random_string::generate(DEVICE_ID_LEN, DEVICE_ID_CHARSET),
+            name: default_device_name(),
+        }
+    }
+}
+
+/// Returns the host name of the computer, or `Shary` if it is unknown.
+fn default_device_name() -> String {
+    let name = gethostname::gethostname()
+        .to_string_lossy()
+        .trim()
+        .to_owned();
+    if name.is_empty() {
+        String::from("Shary")
+    } else {
+        name
+    }
+}
+
+/// A file that a peer shares.
+/// Two remote files are equal if they are the same file of the same device, even if the device changed
+/// its address or the metadata changed.
+#[derive(Clone, Debug)]
 pub struct RemoteFile {
-    pub addr: SocketAddr,
+    /// All addresses the device is currently seen at, starting with the one it was first seen at.
+    pub addrs: Vec<SocketAddr>,
+    /// The id of the sharing device, or its address for peers from before devices had ids.
+    pub device_id: String,
+    pub device_name: String,
+    pub file: String,
+    /// The fingerprint of the public key of the peer sharing the file.
+    pub fingerprint: String,
+    /// The protocol version of the peer, `0` for peers from before versions were advertised.
+    pub version: u16,
+    pub capabilities: Vec<Capability>,
+    /// [None] for peers from before metadata was advertised.
+    pub meta: Option<FileMeta>,
+    /// The name of the network interface the device was found on, if it is known.
+    pub interface: Option<String>,
+    /// The content of a short text that came with discovery, other texts are fetched with
+    /// [Files::fetch_text].
+    pub text: Option<String>,
+}
+
+impl PartialEq for RemoteFile {
+    fn eq(&self, other: &Self) -> bool {
+        self.device_id == other.device_id && self.file == other.file
+    }
+}
+
+impl Eq for RemoteFile {}
+
+impl Hash for RemoteFile {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.device_id.hash(state);
+        self.file.hash(state);
+    }
+}
+
+impl RemoteFile {
+    /// The device that shares the file.
+    pub fn device(&self) -> RemoteDevice {
+        RemoteDevice {
+            addrs: self.addrs.clone(),
+            device_id: self.device_id.clone(),
+            device_name: self.device_name.clone(),
+            fingerprint: self.fingerprint.clone(),
+            capabilities: self.capabilities.clone(),
+        }
+    }
+
+    /// Whether the peer speaks the same protocol version as this device.
+    pub fn is_compatible(&self) -> bool {
+        self.version == PROTOCOL_VERSION
+    }
+
+    pub fn supports(&self, capability: Capability) -> bool {
+        self.capabilities.contains(&capability)
+    }
+
+    /// Explains why the peer can not be used, if it is not compatible.
+    pub fn update_required(&self) -> Option<String> {
+        if self.is_compatible() {
+            None
+        } else {
+            Some(format!(
+                "The peer uses protocol version {}, but this device uses version {}. \
+                 Update the older device to download from it.",
+                self.version, PROTOCOL_VERSION
+            ))
+        }
+    }
+}
+
+/// A device that discovery found, whether or not it shares any files.
+#[derive(Eq, PartialEq, Clone, Debug)]
+pub struct RemoteDevice {
+    /// All addresses the device is currently seen at, starting with the one it was first seen at.
+    pub addrs: Vec<SocketAddr>,
+    pub device_id: String,
+    pub device_name: String,
+    pub fingerprint: String,
+    pub capabilities: Vec<Capability>,
+}
+
+/// Optional features that a peer supports.
+#[derive(Eq, PartialEq, Clone, Copy, Debug, Hash, Serialize, Deserialize)]
+pub enum Capability {
+    /// Continuing downloads from the data the client already has.
+    Resume,
+    /// Pairing to confirm each other's keys.
+    Pair,
+    /// Sending the hashes of the files after a download, so that the client can verify them.
+    Hash,
+    /// Receiving files that the peer offers, instead of only downloading them.
+    Offer,
+    /// A capability of a newer version that this version does not know.
+    #[serde(other)]
+    Unknown,
+}
+
+/// Progress of a running download.
+#[derive(Eq, PartialEq, Clone, Debug, Hash, Default)]
+pub struct Progress {
+    pub received: u64,
+    pub total: u64,
+    /// Average bytes per second since the download started.
+    pub rate: u64,
+    pub eta: Option<Duration>,
+}
+
+impl Progress {
+    pub fn new(received: u64, total: u64, elapsed: Duration) -> Progress {
+        let seconds = elapsed.as_secs_f64();
+        let rate = if seconds > 0f64 {
+            (received as f64 / seconds) as u64
+        } else {
+            0
+        };
+        let eta = total
+            .saturating_sub(received)
+            .checked_div(rate)
+            .map(Duration::from_secs);
+        Progress {
+            received,
+            total,
+            rate,
+            eta,
+        }
+    }
+
+    /// Returns the completed fraction in the range `0..=1`.
+    pub fn fraction(&self) -> f32 {
+        if self.total == 0 {
+            0f32
+        } else {
+            (self.received as f64 / self.total as f64).min(1f64) as f32
+        }
+    }
+}
+
+/// A download the user asked for.
+#[derive(Clone, Debug)]
+pub struct Download {
+    pub remote_file: RemoteFile,
+    /// The folder the file is downloaded into.
+    pub path: PathBuf,
+    /// The password of a file that is protected by one.
+    pub password: Option<String>,
+    pub conflict: ConflictPolicy,
+}
+
+/// A local file the user asked to send to a peer.
+#[derive(Clone, Debug)]
+pub struct OutgoingOffer {
+    /// The name of the peer to show while the offer is pending.
+    pub peer: String,
+    pub addrs: Vec<SocketAddr>,
+    /// The fingerprint the peer must have, or [None] for a peer given by address whose key is not known.
+    pub fingerprint: Option<String>,
+    pub local_file: LocalFile,
+}
+
+/// A file that is offered to or by a peer.
+#[derive(Eq, PartialEq, Clone, Debug)]
+pub struct Offer {
+    pub id: u64,
+    /// The name of the other device.
+    pub peer: String,
+    pub fingerprint: String,
     pub file: String,
+    pub meta: FileMeta,
+    /// Whether this device offered the file.
+    pub outgoing: bool,
+    pub status: OfferStatus,
+}
+
+#[derive(Eq, PartialEq, Clone, Debug)]
+pub enum OfferStatus {
+    /// Waiting for the receiver to accept or decline.
+    Pending,
+    /// Accepted by the user of this device, to be stored in the folder.
+    Accepted(PathBuf, ConflictPolicy),
+    Declined,
+    /// Accepted and waiting for a running download to finish, like a queued download.
+    Queued,
+    /// The file is being sent, with the progress if this device receives it.
+    Transferring(Option<Progress>),
+    /// Paused by the user of this device, which receives it.
+    Paused(Progress),
+    /// The content hash of the file, if it is known.
+    Completed(Option<String>),
+    Failed(String),
+    Cancelled,
+}
+
+impl OfferStatus {
+    pub fn is_finished(&self) -> bool {
+        matches!(
+            self,
+            OfferStatus::Declined
+                | OfferStatus::Completed(_)
+                | OfferStatus::Failed(_)
+                | OfferStatus::Cancelled
+        )
+    }
+}
+
+/// What a download does with files that already exist.
+#[derive(
+    clap::ValueEnum, Eq, PartialEq, Clone, Copy, Debug, Hash, Default, Serialize, Deserialize,
+)]
+pub enum ConflictPolicy {
+    /// Download into a new name like `file (1).txt`.
+    #[default]
+    Rename,
+    /// Keep the existing files and only download the missing ones.
+    Skip,
+    /// Replace the existing files.
+    Overwrite,
+}
+
+impl fmt::Display for ConflictPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ConflictPolicy::Rename => "Rename",
+            ConflictPolicy::Skip => "Skip",
+            ConflictPolicy::Overwrite => "Overwrite",
+        })
+    }
 }
 
 #[derive(Eq, PartialEq, Clone, Debug, Hash)]
 pub enum DownloadStatus {
-    Running,
+    /// Waiting for one of the running downloads to finish.
+    Queued,
+    Running(Progress),
+    /// Stopped by the user with the progress so far, the files that were received are kept to resume with.
+    Paused(Progress),
+    /// The content hash of the download, if the peer sent hashes to verify it with.
+    Completed(Option<String>),
+    Failed(String),
+    /// The peer refused to send the file.
+    Refused(Refusal),
+    Cancelled,
+}
+
+impl DownloadStatus {
+    /// Whether the download has been requested and has not finished yet.
+    pub fn is_active(&self) -> bool {
+        matches!(
+            self,
+            DownloadStatus::Queued | DownloadStatus::Running(_) | DownloadStatus::Paused(_)
+        )
+    }
+}
+
+/// A text that is fetched from a peer.
+#[derive(Eq, PartialEq, Clone, Debug)]
+pub enum TextStatus {
+    Fetching,
+    Fetched(String),
+    Failed(String),
+    /// The peer refused to send the text.
+    Refused(Refusal),
+}
+
+/// How the user has asked a download to change.
+#[derive(Eq, PartialEq, Clone, Copy, Debug)]
+pub enum DownloadControl {
+    Pause,
+    Cancel,
+}
+
+/// A download or a received offer that has finished, as it is kept in the history.
+#[derive(Eq, PartialEq, Clone, Debug, Serialize, Deserialize)]
+pub struct Transfer {
+    /// The name of the device the file was downloaded from.
+    pub peer: String,
+    pub fingerprint: String,
+    pub file: String,
+    /// Where the file was stored, or the folder it was to be stored in if the download did not get that far.
+    pub path: PathBuf,
+    /// The size of the file, if the peer announced it.
+    pub size: Option<u64>,
+    pub finished: SystemTime,
+    /// How long the download ran, not counting the time it was queued.
+    pub duration: Duration,
+    pub outcome: TransferOutcome,
+    /// The content hash the download was verified with.
+    pub hash: Option<String>,
+}
+
+#[derive(Eq, PartialEq, Clone, Debug, Serialize, Deserialize)]
+pub enum TransferOutcome {
     Completed,
     Failed(String),
+    Refused(Refusal),
+    Cancelled,
+}
+
+impl fmt::Display for TransferOutcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TransferOutcome::Completed => f.write_str("completed"),
+            TransferOutcome::Failed(msg) => write!(f, "failed: {}", msg),
+            TransferOutcome::Refused(refusal) => write!(f, "refused: {}", refusal),
+            TransferOutcome::Cancelled => f.write_str("cancelled"),
+        }
+    }
+}
+
+/// Accepts the files a peer offers without asking the user.
+#[derive(Eq, PartialEq, Clone, Debug, Serialize, Deserialize)]
+pub struct AcceptRule {
+    /// The fingerprint of the peer's key.
+    pub fingerprint: String,
+    /// The folder the files are stored in, in a subfolder named after the peer.
+    pub folder: PathBuf,
+    /// The size of the largest file that is accepted, the user is asked about larger ones.
+    pub max_size: Option<u64>,
+    /// What is done with files that already exist in the folder.
+    #[serde(default)]
+    pub conflict: ConflictPolicy,
+}
+
+impl AcceptRule {
+    /// A rule that accepts any file into the Downloads folder.
+    pub fn new(fingerprint: String) -> AcceptRule {
+        let downloads = match directories_next::UserDirs::new() {
+            Some(dirs) => dirs
+                .download_dir()
+                .map(Path::to_path_buf)
+                .unwrap_or_else(|| dirs.home_dir().join("Downloads")),
+            None => std::env::temp_dir(),
+        };
+        AcceptRule {
+            fingerprint,
+            folder: downloads.join("shary"),
+            max_size: None,
+            conflict: ConflictPolicy::default(),
+        }
+    }
+
+    /// Whether the rule accepts a file the peer with the fingerprint offers.
+    pub fn accepts(&self, fingerprint: &str, meta: &FileMeta) -> bool {
+        self.fingerprint == fingerprint && self.max_size.is_none_or(|max| meta.size <= max)
+    }
+
+    /// The subfolder of [AcceptRule::folder] that the files of the peer with the name are stored in.
+    pub fn peer_folder(&self, name: &str) -> PathBuf {
+        // The name comes from the peer, so only characters that can not leave the folder are kept.
+        let name: String = name
+            .chars()
+            .filter(|c| c.is_alphanumeric() || " -_()".contains(*c))
+            .collect();
+        let name = name.trim();
+        let name = if name.is_empty() {
+            self.fingerprint.chars().take(16).collect()
+        } else {
+            name.to_owned()
+        };
+        self.folder.join(name)
+    }
+}
+
+/// A peer whose key the user has confirmed by pairing with it.
+#[derive(Eq, PartialEq, Clone, Debug, Serialize, Deserialize)]
+pub struct PairedPeer {
+    pub fingerprint: String,
+    /// The device name the peer had when it was paired, empty if it was not known or the peer was paired
+    /// before names were stored.
+    #[serde(default)]
+    pub name: String,
+}
+
+/// A pairing with a peer that is in progress or has just finished.
+#[derive(Eq, PartialEq, Clone, Debug)]
+pub struct Pairing {
+    pub fingerprint: String,
+    /// The code both devices show, known once the encrypted session is established.
+    pub code: Option<String>,
+    pub status: PairingStatus,
+}
+
+#[derive(Eq, PartialEq, Clone, Debug)]
+pub enum PairingStatus {
+    Connecting,
+    /// Waiting for the user to confirm that both devices show the same code.
+    Confirming,
+    /// Waiting for the user of the other device to confirm.
+    Waiting,
+    Paired,
+    Failed(String),
+}
+
+impl PairingStatus {
+    pub fn is_finished(&self) -> bool {
+        matches!(self, PairingStatus::Paired | PairingStatus::Failed(_))
+    }
 }
 
 pub struct Files {
+    device_tx: watch::Sender<Device>,
     local_files_tx: watch::Sender<Vec<LocalFile>>,
+    /// The content hashes of the local files that have been hashed, keyed by their path.
+    local_hashes_tx: watch::Sender<HashMap<PathBuf, String>>,
+    /// The files of all peers, combined from [Files::discovered_files_tx] and [Files::polled_files_tx].
     pub remote_files_tx: watch::Sender<Arc<Vec<RemoteFile>>>,
-    downloads_tx: broadcast::Sender<(RemoteFile, PathBuf)>,
+    /// The files of the peers that discovery finds.
+    pub discovered_files_tx: watch::Sender<Arc<Vec<RemoteFile>>>,
+    /// The devices that discovery finds, including those that share nothing.
+    pub discovered_devices_tx: watch::Sender<Arc<Vec<RemoteDevice>>>,
+    /// The files of the peers that were added by address.
+    pub polled_files_tx: watch::Sender<Arc<Vec<RemoteFile>>>,
+    /// The addresses of peers that discovery does not reach, as `host` or `host:port`.
+    manual_peers_tx: watch::Sender<Vec<String>>,
+    /// Why the manual peers that could not be polled failed.
+    peer_errors_tx: watch::Sender<HashMap<String, String>>,
+    /// The downloads that have been requested, queued so that none are lost while others are running.
+    downloads_tx: mpsc::UnboundedSender<Download>,
+    downloads_rx: Mutex<mpsc::UnboundedReceiver<Download>>,
     download_status_tx: watch::Sender<HashMap<RemoteFile, DownloadStatus>>,
+    /// Signals the running downloads that the user has paused or cancelled.
+    download_controls_tx: watch::Sender<HashMap<RemoteFile, DownloadControl>>,
+    /// The texts the user asked to fetch, with the password of a text that is protected by one.
+    text_requests_tx: mpsc::UnboundedSender<(RemoteFile, Option<String>)>,
+    text_requests_rx: Mutex<mpsc::UnboundedReceiver<(RemoteFile, Option<String>)>>,
+    text_status_tx: watch::Sender<HashMap<RemoteFile, TextStatus>>,
+    paired_peers_tx: watch::Sender<Vec<PairedPeer>>,
+    pairing_requests_tx: broadcast::Sender<(Vec<SocketAddr>, String)>,
+    pairing_tx: watch::Sender<Option<Pairing>>,
+    pairing_decision_tx: watch::Sender<Option<bool>>,
+    /// Until when the pairing requests of a peer are refused, keyed by its fingerprint.
+    pairing_cooldowns_tx: watch::Sender<HashMap<String, Instant>>,
+    /// The finished downloads, oldest first.
+    history_tx: watch::Sender<Vec<Transfer>>,
+    /// The files the user asked to send, queued like [Files::downloads_tx].
+    outgoing_offers_tx: mpsc::UnboundedSender<OutgoingOffer>,
+    outgoing_offers_rx: Mutex<mpsc::UnboundedReceiver<OutgoingOffer>>,
+    /// The offers to and from peers that are in progress or have just finished.
+    offers_tx: watch::Sender<Vec<Offer>>,
+    /// Signals the received offers that the user has paused or cancelled, keyed by their id.
+    offer_controls_tx: watch::Sender<HashMap<u64, DownloadControl>>,
+    accept_rules_tx: watch::Sender<Vec<AcceptRule>>,
+    next_offer_id: AtomicU64,
+    /// Whether changes are stored for later runs.
+    persistent: bool,
 }
 
 impl Default for Files {
     fn default() -> Self {
+        let (device_tx, _) = watch::channel(Device::generate());
         let (local_files_tx, _) = watch::channel(vec![]);
+        let (local_hashes_tx, _) = watch::channel(HashMap::new());
         let (remote_files_tx, _) = watch::channel(Arc::new(vec![]));
-        let (downloads_tx, _) = broadcast::channel(1);
+        let (discovered_files_tx, _) = watch::channel(Arc::new(vec![]));
+        let (discovered_devices_tx, _) = watch::channel(Arc::new(vec![]));
+        let (polled_files_tx, _) = watch::channel(Arc::new(vec![]));
+        let (manual_peers_tx, _) = watch::channel(vec![]);
+        let (peer_errors_tx, _) = watch::channel(HashMap::new());
+        let (downloads_tx, downloads_rx) = mpsc::unbounded_channel();
         let (download_status_tx, _) = watch::channel(HashMap::new());
+        let (download_controls_tx, _) = watch::channel(HashMap::new());
+        let (text_requests_tx, text_requests_rx) = mpsc::unbounded_channel();
+        let (text_status_tx, _) = watch::channel(HashMap::new());
+        let (paired_peers_tx, _) = watch::channel(vec![]);
+        let (pairing_requests_tx, _) = broadcast::channel(1);
+        let (pairing_tx, _) = watch::channel(None);
+        let (pairing_decision_tx, _) = watch::channel(None);
+        let (pairing_cooldowns_tx, _) = watch::channel(HashMap::new());
+        let (history_tx, _) = watch::channel(vec![]);
+        let (outgoing_offers_tx, outgoing_offers_rx) = mpsc::unbounded_channel();
+        let (offers_tx, _) = watch::channel(vec![]);
+        let (offer_controls_tx, _) = watch::channel(HashMap::new());
+        let (accept_rules_tx, _) = watch::channel(vec![]);
         Self {
+            device_tx,
             local_files_tx,
+            local_hashes_tx,
             remote_files_tx,
+            discovered_files_tx,
+            discovered_devices_tx,
+            polled_files_tx,
+            manual_peers_tx,
+            peer_errors_tx,
             downloads_tx,
+            downloads_rx: Mutex::new(downloads_rx),
             download_status_tx,
+            download_controls_tx,
+            text_requests_tx,
+            text_requests_rx: Mutex::new(text_requests_rx),
+            text_status_tx,
+            paired_peers_tx,
+            pairing_requests_tx,
+            pairing_tx,
+            pairing_decision_tx,
+            pairing_cooldowns_tx,
+            history_tx,
+            outgoing_offers_tx,
+            outgoing_offers_rx: Mutex::new(outgoing_offers_rx),
+            offers_tx,
+            offer_controls_tx,
+            accept_rules_tx,
+            next_offer_id: AtomicU64::new(0),
+            persistent: false,
         }
     }
 }
 
 impl Files {
-    pub fn add_local_file(&self, local_file: LocalFile) -> bool {
-        self.local_files_tx.send_if_modified(|local_files| {
-            if local_files.contains(&local_file) {
+    /// Creates the files with the state stored by earlier runs.
+    pub fn load() -> Files {
+        let files = Files {
+            persistent: true,
+            ..Files::default()
+        };
+        match storage::load(DEVICE_FILE) {
+            Ok(Some(device)) => {
+                files.device_tx.send_replace(device);
+            }
+            Ok(None) => files.save_device(),
+            Err(err) => tracing::error!("Failed to load device: {:?}", err),
+        }
+        match storage::load(PAIRED_PEERS_FILE) {
+            Ok(Some(paired_peers)) => {
+                files.paired_peers_tx.send_replace(paired_peers);
+            }
+            Ok(None) => {}
+            Err(err) => tracing::error!("Failed to load paired peers: {:?}", err),
+        }
+        match storage::load(MANUAL_PEERS_FILE) {
+            Ok(Some(manual_peers)) => {
+                files.manual_peers_tx.send_replace(manual_peers);
+            }
+            Ok(None) => {}
+            Err(err) => tracing::error!("Failed to load manual peers: {:?}", err),
+        }
+        match storage::load(HISTORY_FILE) {
+            Ok(Some(history)) => {
+                files.history_tx.send_replace(history);
+            }
+            Ok(None) => {}
+            Err(err) => tracing::error!("Failed to load history: {:?}", err),
+        }
+        match storage::load(ACCEPT_RULES_FILE) {
+            Ok(Some(accept_rules)) => {
+                files.accept_rules_tx.send_replace(accept_rules);
+            }
+            Ok(None) => {}
+            Err(err) => tracing::error!("Failed to load accept rules: {:?}", err),
+        }
+        files
+    }
+
+    pub fn get_device(&self) -> watch::Receiver<Device> {
+        self.device_tx.subscribe()
+    }
+
+    /// Changes the name that peers see and stores it for later runs.
+    pub fn set_device_name(&self, name: &str) -> bool {
+        let name: String = name.trim().chars().take(MAX_DEVICE_NAME_LEN).collect();
+        if name.is_empty() {
+            return false;
+        }
+        let modified = self.device_tx.send_if_modified(|device| {
+            if device.name == name {
                 false
             } else {
-                local_files.push(local_file);
+                device.name = name;
                 true
             }
+        });
+        if modified {
+            self.save_device();
+        }
+        modified
+    }
+
+    fn save_device(&self) {
+        if !self.persistent {
+            return;
+        }
+        if let Err(err) = storage::save(DEVICE_FILE, &*self.device_tx.borrow()) {
+            tracing::error!("Failed to save device: {:?}", err);
+        }
+    }
+
+    /// Shares a file, unless it is already shared. A text is renamed if another share has its name, so
+    /// that peers can tell them apart.
+    pub fn add_local_file(&self, mut local_file: LocalFile) -> bool {
+        self.local_files_tx.send_if_modified(|local_files| {
+            if local_files.iter().any(|f| f.is_same(&local_file)) {
+                return false;
+            }
+            if let LocalFile::Text { name, .. } = &mut local_file {
+                let mut i = 1;
+                *name = loop {
+                    let numbered = format!("Text {}", i);
+                    if !local_files.iter().any(|f| f.name() == numbered) {
+                        break numbered;
+                    }
+                    i += 1;
+                };
+            }
+            local_files.push(local_file);
+            true
         })
     }
 
+    pub fn get_local_hashes(&self) -> watch::Receiver<HashMap<PathBuf, String>> {
+        self.local_hashes_tx.subscribe()
+    }
+
+    pub fn get_local_hash(&self, path: &Path) -> Option<String> {
+        self.local_hashes_tx.borrow().get(path).cloned()
+    }
+
+    pub fn set_local_hash(&self, path: PathBuf, hash: String) {
+        self.local_hashes_tx.send_modify(|hashes| {
+            hashes.insert(path, hash);
+        });
+    }
+
+    /// Forgets the hashes of the paths that `f` returns false for.
+    pub fn retain_local_hashes(&self, mut f: impl FnMut(&Path) -> bool) {
+        self.local_hashes_tx.send_if_modified(|hashes| {
+            let len = hashes.len();
+            hashes.retain(|path, _| f(path));
+            hashes.len() != len
+        });
+    }
+
     pub fn remove_local_file(&self, local_file: &LocalFile) -> bool {
         self.local_files_tx.send_if_modified(|local_files| {
             if let Some((i, _)) = local_files
                 .iter()
                 .enumerate()
-                .find(|(_, f)| *f == local_file)
+                .find(|(_, f)| f.is_same(local_file))
             {
                 local_files.remove(i);
                 true
@@ -84,16 +978,422 @@ impl Files {
         self.local_files_tx.subscribe()
     }
 
+    pub fn set_share_policy(&self, local_file: &LocalFile, policy: SharePolicy) -> bool {
+        self.local_files_tx.send_if_modified(|local_files| {
+            match local_files.iter_mut().find(|f| f.is_same(local_file)) {
+                Some(local_file) if *local_file.policy() != policy => {
+                    *local_file.policy_mut() = policy;
+                    true
+                }
+                _ => false,
+            }
+        })
+    }
+
+    /// Checks whether the peer with `fingerprint` may download the local file called `name`, or returns
+    /// [None] if no such file is shared.
+    /// A correct password is used up, so the share gets a new one.
+    pub fn authorize_download(
+        &self,
+        name: &str,
+        fingerprint: &str,
+        password: Option<&str>,
+    ) -> Option<Result<LocalFile, Refusal>> {
+        let mut result = None;
+        self.local_files_tx.send_if_modified(|local_files| {
+            let local_file = match local_files.iter_mut().find(|f| f.name() == name) {
+                Some(local_file) => local_file,
+                None => return false,
+            };
+            let mut modified = false;
+            let authorized = match local_file.policy_mut() {
+                SharePolicy::Public => Ok(()),
+                SharePolicy::Peers(peers) => {
+                    if peers.iter().any(|p| p == fingerprint) && self.is_paired(fingerprint) {
+                        Ok(())
+                    } else {
+                        Err(Refusal::NotAllowed)
+                    }
+                }
+                SharePolicy::Password {
+                    password: expected,
+                    granted,
+                    failures,
+                } => {
+                    if granted.iter().any(|g| g == fingerprint) {
+                        Ok(())
+                    } else if password.is_none() {
+                        Err(Refusal::PasswordRequired)
+                    } else if failures.iter().filter(|f| *f == fingerprint).count()
+                        >= MAX_PEER_PASSWORD_FAILURES
+                    {
+                        Err(Refusal::TooManyAttempts)
+                    } else if password.is_some_and(|p| same_password(p, expected)) {
+                        *expected = random_string::generate(PASSWORD_LEN, PASSWORD_CHARSET);
+                        granted.push(fingerprint.to_owned());
+                        failures.clear();
+                        modified = true;
+                        Ok(())
+                    } else {
+                        // Fingerprints cost nothing, so a password that is guessed wrong too often by anyone
+                        // is replaced.
+                        failures.push(fingerprint.to_owned());
+                        if failures.len() >= MAX_PASSWORD_FAILURES {
+                            tracing::warn!(
+                                "Replacing the password of {}, it was guessed wrong too often",
+                                name
+                            );
+                            *expected = random_string::generate(PASSWORD_LEN, PASSWORD_CHARSET);
+                            failures.clear();
+                            modified = true;
+                        }
+                        Err(Refusal::WrongPassword)
+                    }
+                }
+            };
+            result = Some(authorized.map(|_| local_file.clone()));
+            modified
+        });
+        result
+    }
+
     pub fn get_remote_files(&self) -> watch::Receiver<Arc<Vec<RemoteFile>>> {
         self.remote_files_tx.subscribe()
     }
 
-    pub fn add_download(&self, remote_file: RemoteFile, path: PathBuf) {
-        let _ = self.downloads_tx.send((remote_file, path));
+    pub fn get_discovered_devices(&self) -> watch::Receiver<Arc<Vec<RemoteDevice>>> {
+        self.discovered_devices_tx.subscribe()
+    }
+
+    /// The name of the device with the fingerprint, if it was found on the network or shares files.
+    pub fn device_name(&self, fingerprint: &str) -> Option<String> {
+        let devices = self.discovered_devices_tx.borrow();
+        if let Some(device) = devices.iter().find(|d| d.fingerprint == fingerprint) {
+            return Some(device.device_name.clone());
+        }
+        self.remote_files_tx
+            .borrow()
+            .iter()
+            .find(|r| r.fingerprint == fingerprint)
+            .map(|r| r.device_name.clone())
+    }
+
+    /// Combines the discovered and the polled files into the remote files.
+    /// A file of a device that is both discovered and polled is only listed once, as discovered.
+    pub fn merge_remote_files(&self) {
+        let discovered = self.discovered_files_tx.borrow().clone();
+        let polled = self.polled_files_tx.borrow().clone();
+        let mut remote_files = discovered.to_vec();
+        remote_files.extend(polled.iter().filter(|p| !discovered.contains(p)).cloned());
+        self.remote_files_tx.send_replace(Arc::new(remote_files));
+    }
+
+    pub fn get_manual_peers(&self) -> watch::Receiver<Vec<String>> {
+        self.manual_peers_tx.subscribe()
+    }
+
+    /// Adds the address of a peer to poll and stores the manual peers for later runs.
+    pub fn add_manual_peer(&self, peer: &str) -> bool {
+        let peer = peer.trim();
+        if peer.is_empty() {
+            return false;
+        }
+        let modified = self.manual_peers_tx.send_if_modified(|manual_peers| {
+            if manual_peers.iter().any(|p| p == peer) {
+                false
+            } else {
+                manual_peers.push(peer.to_owned());
+                true
+            }
+        });
+        if modified {
+            self.save_manual_peers();
+        }
+        modified
+    }
+
+    pub fn remove_manual_peer(&self, peer: &str) -> bool {
+        let modified = self.manual_peers_tx.send_if_modified(|manual_peers| {
+            let len = manual_peers.len();
+            manual_peers.retain(|p| p != peer);
+            manual_peers.len() != len
+        });
+        if modified {
+            self.save_manual_peers();
+        }
+        modified
+    }
+
+    fn save_manual_peers(&self) {
+        if !self.persistent {
+            return;
+        }
+        if let Err(err) = storage::save(MANUAL_PEERS_FILE, &*self.manual_peers_tx.borrow()) {
+            tracing::error!("Failed to save manual peers: {:?}", err);
+        }
+    }
+
+    pub fn get_history(&self) -> watch::Receiver<Vec<Transfer>> {
+        self.history_tx.subscribe()
+    }
+
+    /// Adds a finished download to the history and stores it for later runs.
+    /// The stored history is read again first, so that transfers that another running shary added, or
+    /// a history it cleared, are not overwritten. It is read outside of the lock on the history, so that
+    /// the user interface does not wait for the disk.
+    pub fn add_transfer(&self, transfer: Transfer) {
+        let stored = if self.persistent {
+            match storage::load::<Vec<Transfer>>(HISTORY_FILE) {
+                Ok(stored) => Some(stored.unwrap_or_default()),
+                Err(err) => {
+                    tracing::error!("Failed to load history: {:?}", err);
+                    None
+                }
+            }
+        } else {
+            None
+        };
+        self.history_tx.send_modify(|history| {
+            if let Some(stored) = stored {
+                *history = stored;
+            }
+            history.push(transfer);
+            let len = history.len();
+            history.drain(..len.saturating_sub(MAX_HISTORY_LEN));
+        });
+        self.save_history();
+    }
+
+    pub fn clear_history(&self) {
+        let modified = self.history_tx.send_if_modified(|history| {
+            let modified = !history.is_empty();
+            history.clear();
+            modified
+        });
+        if modified {
+            self.save_history();
+        }
+    }
+
+    fn save_history(&self) {
+        if !self.persistent {
+            return;
+        }
+        let history = self.history_tx.borrow().clone();
+        if let Err(err) = storage::save(HISTORY_FILE, &history) {
+            tracing::error!("Failed to save history: {:?}", err);
+        }
+    }
+
+    /// Asks the network to offer a local file to a peer.
+    pub fn offer_file(&self, offer: OutgoingOffer) {
+        let _ = self.outgoing_offers_tx.send(offer);
+    }
+
+    /// Waits for the next file the user asked to send.
+    pub async fn next_outgoing_offer(&self) -> Option<OutgoingOffer> {
+        self.outgoing_offers_rx.lock().await.recv().await
+    }
+
+    pub fn get_offers(&self) -> watch::Receiver<Vec<Offer>> {
+        self.offers_tx.subscribe()
+    }
+
+    /// Adds an offer with a new id, which is returned.
+    pub fn add_offer(&self, mut offer: Offer) -> u64 {
+        offer.id = self.next_offer_id.fetch_add(1, Ordering::Relaxed);
+        let id = offer.id;
+        self.offers_tx.send_modify(|offers| offers.push(offer));
+        id
+    }
+
+    /// Adds an offer from a peer like [Files::add_offer], unless that peer or all peers together already
+    /// have as many offers waiting for the user to decide as are allowed.
+    pub fn add_incoming_offer(&self, mut offer: Offer) -> Option<u64> {
+        let mut id = None;
+        self.offers_tx.send_if_modified(|offers| {
+            let pending: Vec<&Offer> = offers
+                .iter()
+                .filter(|o| !o.outgoing && o.status == OfferStatus::Pending)
+                .collect();
+            let from_peer = pending
+                .iter()
+                .filter(|o| o.fingerprint == offer.fingerprint)
+                .count();
+            if from_peer >= MAX_PEER_PENDING_OFFERS || pending.len() >= MAX_PENDING_OFFERS {
+                return false;
+            }
+            offer.id = self.next_offer_id.fetch_add(1, Ordering::Relaxed);
+            id = Some(offer.id);
+            offers.push(offer);
+            true
+        });
+        id
+    }
+
+    pub fn update_offer(&self, id: u64, update: impl FnOnce(&mut Offer)) {
+        self.offers_tx
+            .send_if_modified(|offers| match offers.iter_mut().find(|o| o.id == id) {
+                Some(offer) => {
+                    update(offer);
+                    true
+                }
+                None => false,
+            });
+    }
+
+    /// Accepts a pending offer from a peer into the folder, or declines it.
+    pub fn decide_offer(&self, id: u64, decision: Option<(PathBuf, ConflictPolicy)>) {
+        let pending = self
+            .offers_tx
+            .borrow()
+            .iter()
+            .any(|o| o.id == id && !o.outgoing && o.status == OfferStatus::Pending);
+        if pending {
+            let status = match decision {
+                Some((path, conflict)) => OfferStatus::Accepted(path, conflict),
+                None => OfferStatus::Declined,
+            };
+            self.update_offer(id, |o| o.status = status);
+        }
+    }
+
+    pub fn remove_offer(&self, id: u64) {
+        self.offers_tx.send_if_modified(|offers| {
+            let len = offers.len();
+            offers.retain(|o| o.id != id);
+            offers.len() != len
+        });
+        self.set_offer_control(id, None);
+    }
+
+    pub fn pause_offer(&self, id: u64) {
+        if self.is_receiving_offer(id) {
+            self.set_offer_control(id, Some(DownloadControl::Pause));
+        }
+    }
+
+    pub fn resume_offer(&self, id: u64) {
+        self.offer_controls_tx.send_if_modified(|m| {
+            m.get(&id) == Some(&DownloadControl::Pause) && m.remove(&id).is_some()
+        });
+    }
+
+    /// Cancels a received offer and removes what has been received.
+    pub fn cancel_offer(&self, id: u64) {
+        if self.is_receiving_offer(id) {
+            self.set_offer_control(id, Some(DownloadControl::Cancel));
+        }
+    }
+
+    fn is_receiving_offer(&self, id: u64) -> bool {
+        self.offers_tx.borrow().iter().any(|o| {
+            o.id == id
+                && !o.outgoing
+                && matches!(
+                    o.status,
+                    OfferStatus::Queued | OfferStatus::Transferring(_) | OfferStatus::Paused(_)
+                )
+        })
+    }
+
+    pub fn set_offer_control(&self, id: u64, control: Option<DownloadControl>) {
+        match control {
+            Some(control) => self
+                .offer_controls_tx
+                .send_if_modified(|m| m.insert(id, control) != Some(control)),
+            None => self
+                .offer_controls_tx
+                .send_if_modified(|m| m.remove(&id).is_some()),
+        };
+    }
+
+    pub fn get_offer_controls(&self) -> watch::Receiver<HashMap<u64, DownloadControl>> {
+        self.offer_controls_tx.subscribe()
+    }
+
+    pub fn get_accept_rules(&self) -> watch::Receiver<Vec<AcceptRule>> {
+        self.accept_rules_tx.subscribe()
+    }
+
+    /// Adds or replaces the rule for a peer and stores the rules for later runs.
+    pub fn set_accept_rule(&self, rule: AcceptRule) {
+        let modified = self.accept_rules_tx.send_if_modified(|rules| {
+            match rules.iter_mut().find(|r| r.fingerprint == rule.fingerprint) {
+                Some(existing) if *existing == rule => return false,
+                Some(existing) => *existing = rule,
+                None => rules.push(rule),
+            }
+            true
+        });
+        if modified {
+            self.save_accept_rules();
+        }
+    }
+
+    pub fn remove_accept_rule(&self, fingerprint: &str) {
+        let modified = self.accept_rules_tx.send_if_modified(|rules| {
+            let len = rules.len();
+            rules.retain(|r| r.fingerprint != fingerprint);
+            rules.len() != len
+        });
+        if modified {
+            self.save_accept_rules();
+        }
+    }
+
+    /// Returns the folder to store a file that a peer offers in and the rule that accepts it without
+    /// asking, if there is one.
+    /// The folder is named after the name the peer was paired with, since the name it offers the file
+    /// with is chosen by the peer.
+    pub fn auto_accept(&self, fingerprint: &str, meta: &FileMeta) -> Option<(PathBuf, AcceptRule)> {
+        let rule = self
+            .accept_rules_tx
+            .borrow()
+            .iter()
+            .find(|rule| rule.accepts(fingerprint, meta))
+            .cloned()?;
+        let name = self
+            .paired_peers_tx
+            .borrow()
+            .iter()
+            .find(|p| p.fingerprint == fingerprint)
+            .map(|p| p.name.clone())
+            .unwrap_or_default();
+        Some((rule.peer_folder(&name), rule))
+    }
+
+    fn save_accept_rules(&self) {
+        if !self.persistent {
+            return;
+        }
+        if let Err(err) = storage::save(ACCEPT_RULES_FILE, &*self.accept_rules_tx.borrow()) {
+            tracing::error!("Failed to save accept rules: {:?}", err);
+        }
+    }
+
+    pub fn get_peer_errors(&self) -> watch::Receiver<HashMap<String, String>> {
+        self.peer_errors_tx.subscribe()
+    }
+
+    pub fn set_peer_errors(&self, peer_errors: HashMap<String, String>) {
+        self.peer_errors_tx.send_if_modified(|current| {
+            if *current == peer_errors {
+                false
+            } else {
+                *current = peer_errors;
+                true
+            }
+        });
+    }
+
+    pub fn add_download(&self, download: Download) {
+        let _ = self.downloads_tx.send(download);
     }
 
-    pub fn get_downloads(&self) -> broadcast::Receiver<(RemoteFile, PathBuf)> {
-        self.downloads_tx.subscribe()
+    /// Waits for the next download that has been requested.
+    pub async fn next_download(&self) -> Option<Download> {
+        self.downloads_rx.lock().await.recv().await
     }
 
     pub fn set_download_status(&self, remote_file: RemoteFile, status: Option<DownloadStatus>) {
@@ -109,9 +1409,264 @@ impl Files {
         };
     }
 
+    pub fn get_download_statuses(&self) -> watch::Receiver<HashMap<RemoteFile, DownloadStatus>> {
+        self.download_status_tx.subscribe()
+    }
+
     pub fn get_download_status(&self, remote_file: &RemoteFile) -> Option<DownloadStatus> {
         self.download_status_tx.borrow().get(remote_file).cloned()
     }
+
+    /// Pauses a download, which keeps what has been received until it is resumed.
+    pub fn pause_download(&self, remote_file: &RemoteFile) {
+        if self.is_downloading(remote_file) {
+            self.set_download_control(remote_file.clone(), Some(DownloadControl::Pause));
+        }
+    }
+
+    pub fn resume_download(&self, remote_file: &RemoteFile) {
+        self.download_controls_tx.send_if_modified(|m| {
+            m.get(remote_file) == Some(&DownloadControl::Pause) && m.remove(remote_file).is_some()
+        });
+    }
+
+    /// Cancels a download and removes what has been received.
+    pub fn cancel_download(&self, remote_file: &RemoteFile) {
+        if self.is_downloading(remote_file) {
+            self.set_download_control(remote_file.clone(), Some(DownloadControl::Cancel));
+        }
+    }
+
+    fn is_downloading(&self, remote_file: &RemoteFile) -> bool {
+        self.get_download_status(remote_file)
+            .is_some_and(|status| status.is_active())
+    }
+
+    pub fn set_download_control(&self, remote_file: RemoteFile, control: Option<DownloadControl>) {
+        match control {
+            Some(control) => self
+                .download_controls_tx
+                .send_if_modified(|m| m.insert(remote_file, control) != Some(control)),
+            None => self
+                .download_controls_tx
+                .send_if_modified(|m| m.remove(&remote_file).is_some()),
+        };
+    }
+
+    pub fn get_download_controls(&self) -> watch::Receiver<HashMap<RemoteFile, DownloadControl>> {
+        self.download_controls_tx.subscribe()
+    }
+
+    /// Asks the network to fetch the text of a remote file, unless it already came with discovery.
+    pub fn fetch_text(&self, remote_file: RemoteFile, password: Option<String>) {
+        match remote_file.text.clone() {
+            Some(text) => self.set_text_status(remote_file, Some(TextStatus::Fetched(text))),
+            None => {
+                self.set_text_status(remote_file.clone(), Some(TextStatus::Fetching));
+                let _ = self.text_requests_tx.send((remote_file, password));
+            }
+        }
+    }
+
+    /// Waits for the next text the user asked to fetch.
+    pub async fn next_text_request(&self) -> Option<(RemoteFile, Option<String>)> {
+        self.text_requests_rx.lock().await.recv().await
+    }
+
+    pub fn set_text_status(&self, remote_file: RemoteFile, status: Option<TextStatus>) {
+        match status {
+            Some(status) => self.text_status_tx.send_modify(|m| {
+                m.insert(remote_file, status);
+            }),
+            None => {
+                self.text_status_tx
+                    .send_if_modified(|m| m.remove(&remote_file).is_some());
+            }
+        }
+    }
+
+    pub fn get_text_statuses(&self) -> watch::Receiver<HashMap<RemoteFile, TextStatus>> {
+        self.text_status_tx.subscribe()
+    }
+
+    pub fn get_text_status(&self, remote_file: &RemoteFile) -> Option<TextStatus> {
+        self.text_status_tx.borrow().get(remote_file).cloned()
+    }
+
+    pub fn get_paired_peers(&self) -> watch::Receiver<Vec<PairedPeer>> {
+        self.paired_peers_tx.subscribe()
+    }
+
+    pub fn is_paired(&self, fingerprint: &str) -> bool {
+        self.paired_peers_tx
+            .borrow()
+            .iter()
+            .any(|p| p.fingerprint == fingerprint)
+    }
+
+    /// Adds a paired peer, or updates the name of a peer that was paired before, and stores the paired
+    /// peers for later runs.
+    pub fn add_paired_peer(&self, paired_peer: PairedPeer) {
+        let modified = self.paired_peers_tx.send_if_modified(|paired_peers| {
+            match paired_peers
+                .iter_mut()
+                .find(|p| p.fingerprint == paired_peer.fingerprint)
+            {
+                Some(existing) if *existing == paired_peer => false,
+                Some(existing) => {
+                    *existing = paired_peer;
+                    true
+                }
+                None => {
+                    paired_peers.push(paired_peer);
+                    true
+                }
+            }
+        });
+        if modified && self.persistent {
+            if let Err(err) = storage::save(PAIRED_PEERS_FILE, &*self.paired_peers_tx.borrow()) {
+                tracing::error!("Failed to save paired peers: {:?}", err);
+            }
+        }
+    }
+
+    /// Asks the network to pair with the peer at `addrs` that advertises `fingerprint`.
+    pub fn pair(&self, addrs: Vec<SocketAddr>, fingerprint: String) {
+        let _ = self.pairing_requests_tx.send((addrs, fingerprint));
+    }
+
+    pub fn get_pairing_requests(&self) -> broadcast::Receiver<(Vec<SocketAddr>, String)> {
+        self.pairing_requests_tx.subscribe()
+    }
+
+    pub fn get_pairing(&self) -> watch::Receiver<Option<Pairing>> {
+        self.pairing_tx.subscribe()
+    }
+
+    /// Starts showing a pairing, unless another pairing is still in progress.
+    pub fn start_pairing(&self, pairing: Pairing) -> bool {
+        let started = self.pairing_tx.send_if_modified(|current| match current {
+            Some(current) if !current.status.is_finished() => false,
+            _ => {
+                *current = Some(pairing);
+                true
+            }
+        });
+        if started {
+            self.pairing_decision_tx.send_replace(None);
+        }
+        started
+    }
+
+    pub fn update_pairing(&self, update: impl FnOnce(&mut Pairing)) {
+        self.pairing_tx.send_if_modified(|pairing| match pairing {
+            Some(pairing) => {
+                update(pairing);
+                true
+            }
+            None => false,
+        });
+    }
+
+    /// Removes a finished pairing.
+    pub fn clear_pairing(&self) {
+        self.pairing_tx.send_if_modified(|pairing| match pairing {
+            Some(p) if p.status.is_finished() => {
+                *pairing = None;
+                true
+            }
+            _ => false,
+        });
+    }
+
+    /// Confirms or rejects the code of the pairing in progress.
+    pub fn decide_pairing(&self, accepted: bool) {
+        self.pairing_decision_tx.send_replace(Some(accepted));
+    }
+
+    pub fn get_pairing_decision(&self) -> watch::Receiver<Option<bool>> {
+        self.pairing_decision_tx.subscribe()
+    }
+
+    /// Refuses the pairing requests of the peer with `fingerprint` for `duration`.
+    pub fn refuse_pairing(&self, fingerprint: String, duration: Duration) {
+        let now = Instant::now();
+        self.pairing_cooldowns_tx.send_modify(|cooldowns| {
+            cooldowns.retain(|_, until| *until > now);
+            cooldowns.insert(fingerprint, now + duration);
+        });
+    }
+
+    /// Whether the pairing requests of the peer with `fingerprint` are refused.
+    pub fn is_pairing_refused(&self, fingerprint: &str) -> bool {
+        self.pairing_cooldowns_tx
+            .borrow()
+            .get(fingerprint)
+            .is_some_and(|until| *until > Instant::now())
+    }
+}
+
+/// Formats a number of bytes for display, e.g. `1.5 MB`.
+pub fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KB", "MB", "GB", "TB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1000f64 && unit < UNITS.len() - 1 {
+        value /= 1000f64;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} {}", bytes, UNITS[unit])
+    } else {
+        format!("{:.1} {}", value, UNITS[unit])
+    }
+}
+
+/// Formats a duration for display, e.g. `3m 20s`.
+pub fn format_duration(duration: Duration) -> String {
+    let seconds = duration.as_secs();
+    if seconds >= 86400 {
+        format!("{}d {}h", seconds / 86400, seconds % 86400 / 3600)
+    } else if seconds >= 3600 {
+        format!("{}h {}m", seconds / 3600, seconds % 3600 / 60)
+    } else if seconds >= 60 {
+        format!("{}m {}s", seconds / 60, seconds % 60)
+    } else {
+        format!("{}s", seconds)
+    }
+}
+
+/// Formats the size and contents of a file for display, e.g. `1.5 MB, 12 files`.
+pub fn format_meta(meta: &FileMeta) -> String {
+    if meta.is_text {
+        format!("Text, {}", format_bytes(meta.size))
+    } else if meta.is_dir {
+        let files = if meta.file_count == 1 {
+            "file"
+        } else {
+            "files"
+        };
+        format!("{}, {} {}", format_bytes(meta.size), meta.file_count, files)
+    } else {
+        format_bytes(meta.size)
+    }
+}
+
+/// Formats the start of a key fingerprint for display, e.g. `1a2b 3c4d 5e6f 7a8b`.
+pub fn format_fingerprint(fingerprint: &str) -> String {
+    fingerprint
+        .chars()
+        .take(16)
+        .collect::<Vec<_>>()
+        .chunks(4)
+        .map(|chunk| chunk.iter().collect::<String>())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Shortens a hash like a fingerprint, the full hash is shown on hover.
+pub fn format_hash(hash: &str) -> String {
+    format_fingerprint(hash)
 }
 
 #[macro_export]
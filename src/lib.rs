@@ -1,4 +1,6 @@
+pub mod cli;
 pub mod common;
 pub mod logging;
 pub mod network;
-pub mod ui;
\ No newline at end of file
+pub mod storage;
+pub mod ui;
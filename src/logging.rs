@@ -7,6 +7,7 @@ pub fn initialize() -> Result<()> {
     color_eyre::install()?;
     let subscriber = FmtSubscriber::builder()
         .with_max_level(Level::TRACE)
+        .with_writer(std::io::stderr)
         .finish();
     tracing::subscriber::set_global_default(subscriber)
         .wrap_err("setting default tracing subscriber failed")?;
@@ -6,12 +6,27 @@ use clap::Parser;
 use color_eyre::Result;
 use tracing::{event, Level};
 
-use shary::{common::Files, ui, network, logging};
+use shary::{cli, common::Files, logging, network, ui};
 
 #[derive(Parser, Debug)]
 struct Args {
     #[arg(short, long, default_value_t = 17671)]
     port: u16,
+    /// How peers are found on the network.
+    #[arg(long, value_enum, default_value_t)]
+    discovery: network::DiscoveryBackend,
+    /// Only discover peers on this network interface, can be repeated.
+    #[arg(long = "interface")]
+    interfaces: Vec<String>,
+    /// Also get the files of the peer at `host` or `host:port`, for peers that are not discovered,
+    /// can be repeated.
+    #[arg(long = "peer")]
+    peers: Vec<String>,
+    /// How many downloads run at once, further downloads wait until one of them finishes.
+    #[arg(long, default_value_t = 4, value_parser = clap::value_parser!(u16).range(1..))]
+    max_downloads: u16,
+    #[command(subcommand)]
+    command: Option<cli::Command>,
 }
 
 fn main() -> Result<()> {
@@ -19,9 +34,21 @@ fn main() -> Result<()> {
     let args = Args::parse();
     event!(Level::INFO, ?args);
 
-    let files = Arc::new(Files::default());
+    let config = network::Config {
+        port: args.port,
+        discovery: args.discovery,
+        interfaces: args.interfaces,
+        peers: args.peers,
+        max_downloads: args.max_downloads.into(),
+    };
 
-    let _network = network::spawn(args.port, files.clone())?;
+    if let Some(command) = args.command {
+        return cli::run(command, config);
+    }
+
+    let files = Arc::new(Files::load());
+
+    let _network = network::spawn(config, files.clone())?;
 
     ui::run(files);
     Ok(())
@@ -1,29 +1,88 @@
+mod archive;
 mod discovery;
+mod hashing;
+mod interfaces;
+mod mdns;
+mod offers;
+mod pairing;
+mod peers;
+mod protocol;
+mod secure;
 mod server;
 #[cfg(test)]
 mod test;
+mod texts;
 
-use self::discovery::{run_discovery_receiver, run_discovery_sender};
-use self::server::{run_file_server, run_file_download};
+use self::discovery::Discovery;
+pub use self::discovery::DiscoveryBackend;
+use self::hashing::run_local_file_hashing;
+use self::offers::run_outgoing_offers;
+use self::pairing::run_pairing_requests;
+use self::peers::{run_peer_polling, run_remote_files_merge};
+pub use self::protocol::{CAPABILITIES, PROTOCOL_VERSION};
+use self::secure::Identity;
+use self::server::{run_file_download, run_file_server};
+use self::texts::run_text_requests;
 use crate::common::Files;
+use color_eyre::eyre::{eyre, Context};
 use color_eyre::Result;
-use color_eyre::eyre::Context;
 use const_str::ip_addr;
-use tokio::runtime::Runtime;
-use std::net::Ipv4Addr;
-use std::net::SocketAddrV4;
+use parking_lot::Mutex;
+use std::future::Future;
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
 use std::sync::Arc;
 use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio::runtime::Runtime;
+use tokio::sync::Semaphore;
 
 const IPV4_MULTICAST_ADDR: Ipv4Addr = ip_addr!(v4, "224.0.0.139");
+/// The link-local scope of the same group as [IPV4_MULTICAST_ADDR].
+const IPV6_MULTICAST_ADDR: Ipv6Addr = ip_addr!(v6, "ff02::139");
+/// How long connecting to one address of a peer may take before the next address is tried.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(3);
+/// How many addresses that were connected to are remembered.
+const MAX_REACHED: usize = 256;
+
+/// The addresses that connections last succeeded to, oldest first.
+static REACHED: Mutex<Vec<SocketAddr>> = parking_lot::const_mutex(Vec::new());
 
-pub fn spawn(port: u16, files: Arc<Files>) -> Result<NetworkHandle> {
+/// How the network is set up, from the command line arguments.
+#[derive(Clone, Debug)]
+pub struct Config {
+    pub port: u16,
+    pub discovery: DiscoveryBackend,
+    /// The names of the network interfaces to discover peers on, or empty to use all of them.
+    pub interfaces: Vec<String>,
+    /// Peers to poll in addition to the manual peers that are stored, as `host` or `host:port`.
+    pub peers: Vec<String>,
+    /// How many downloads run at once, further downloads are queued.
+    pub max_downloads: usize,
+}
+
+pub fn spawn(config: Config, files: Arc<Files>) -> Result<NetworkHandle> {
     let runtime = tokio::runtime::Builder::new_multi_thread()
         .enable_all()
         .build()
         .wrap_err("failed to create tokio runtime")?;
 
-    let network = Arc::new(Network::new(port, files));
+    let identity = match Identity::load_or_generate() {
+        Ok(identity) => identity,
+        Err(err) => {
+            tracing::error!("Failed to load identity, using a temporary one: {:?}", err);
+            Identity::generate()?
+        }
+    };
+    let discovery = config.discovery.create(config.port, config.interfaces)?;
+    let network = Arc::new(Network::new(
+        config.port,
+        discovery,
+        config.peers,
+        config.max_downloads,
+        files,
+        identity,
+    ));
     {
         let network = Arc::clone(&network);
         runtime.spawn(async move {
@@ -34,42 +93,136 @@ pub fn spawn(port: u16, files: Arc<Files>) -> Result<NetworkHandle> {
             }
         });
     }
-    Ok(NetworkHandle { _runtime: runtime, _network: network })
+    Ok(NetworkHandle {
+        runtime,
+        _network: network,
+    })
 }
 
 pub struct NetworkHandle {
-    _runtime: Runtime,
+    runtime: Runtime,
     _network: Arc<Network>,
 }
 
+impl NetworkHandle {
+    /// Runs a future to completion on the network runtime.
+    pub fn block_on<F: Future>(&self, future: F) -> F::Output {
+        self.runtime.block_on(future)
+    }
+}
+
 struct Network {
     port: u16,
+    discovery: Box<dyn Discovery>,
+    peers: Vec<String>,
     files: Arc<Files>,
+    identity: Arc<Identity>,
+    /// Limits how many downloads run at once, kept when the network is restarted.
+    downloads: Arc<Semaphore>,
 }
 
 impl Network {
-    fn new(port: u16, files: Arc<Files>) -> Network {
+    fn new(
+        port: u16,
+        discovery: Box<dyn Discovery>,
+        peers: Vec<String>,
+        max_downloads: usize,
+        files: Arc<Files>,
+        identity: Identity,
+    ) -> Network {
         Network {
             port,
+            discovery,
+            peers,
             files,
+            identity: Arc::new(identity),
+            downloads: Arc::new(Semaphore::new(max_downloads.max(1))),
         }
     }
 
     async fn run(&self) -> Result<()> {
-        let send_handle = run_discovery_sender(
-            self.files.get_local_files(),
-            SocketAddrV4::new(IPV4_MULTICAST_ADDR, self.port),
+        let send_handle = self.discovery.advertise(&self.files, &self.identity);
+
+        let recv_handle = self.discovery.browse(&self.files, &self.identity);
+
+        let poll_handle = run_peer_polling(&self.files, &self.identity, &self.peers, self.port);
+
+        let merge_handle = run_remote_files_merge(&self.files);
+
+        let server_handle = run_file_server(
+            self.port,
+            Arc::clone(&self.files),
+            Arc::clone(&self.identity),
+            Arc::clone(&self.downloads),
+        );
+
+        let download_handle = run_file_download(
+            Arc::clone(&self.files),
+            Arc::clone(&self.identity),
+            Arc::clone(&self.downloads),
         );
 
-        let recv_handle =
-            run_discovery_receiver(&self.files.remote_files_tx, self.port, IPV4_MULTICAST_ADDR);
+        let pairing_handle = run_pairing_requests(&self.files, &self.identity);
 
-        let server_handle = run_file_server(self.port, self.files.get_local_files());
+        let hashing_handle = run_local_file_hashing(&self.files);
 
-        let download_handle = run_file_download(&self.files);
+        let offers_handle =
+            run_outgoing_offers(Arc::clone(&self.files), Arc::clone(&self.identity));
 
-        tokio::try_join!(send_handle, recv_handle, server_handle, download_handle)?;
+        let texts_handle = run_text_requests(Arc::clone(&self.files), Arc::clone(&self.identity));
+
+        tokio::try_join!(
+            send_handle,
+            recv_handle,
+            poll_handle,
+            merge_handle,
+            server_handle,
+            download_handle,
+            pairing_handle,
+            hashing_handle,
+            offers_handle,
+            texts_handle
+        )?;
 
         Ok(())
     }
 }
+
+/// Connects to the first of the addresses of a peer that can be reached.
+/// The addresses that reached a peer before, and then the addresses of the same family, are tried first.
+async fn connect(addrs: &[SocketAddr]) -> Result<TcpStream> {
+    let mut addrs = addrs.to_vec();
+    {
+        let reached = REACHED.lock();
+        let families: Vec<bool> = addrs
+            .iter()
+            .filter(|addr| reached.contains(addr))
+            .map(SocketAddr::is_ipv6)
+            .collect();
+        addrs.sort_by_key(|addr| (!reached.contains(addr), !families.contains(&addr.is_ipv6())));
+    }
+    let mut last_err = None;
+    for addr in addrs {
+        let result = match tokio::time::timeout(CONNECT_TIMEOUT, TcpStream::connect(addr)).await {
+            Ok(result) => result.wrap_err_with(|| format!("failed to connect to {}", addr)),
+            Err(_) => Err(io::Error::from(io::ErrorKind::TimedOut))
+                .wrap_err_with(|| format!("failed to connect to {}", addr)),
+        };
+        let mut reached = REACHED.lock();
+        reached.retain(|reached| *reached != addr);
+        match result {
+            Ok(stream) => {
+                if reached.len() == MAX_REACHED {
+                    reached.remove(0);
+                }
+                reached.push(addr);
+                return Ok(stream);
+            }
+            Err(err) => {
+                tracing::debug!("{:?}", err);
+                last_err = Some(err);
+            }
+        }
+    }
+    Err(last_err.unwrap_or_else(|| eyre!("no address to connect to")))
+}
@@ -0,0 +1,569 @@
+//! This module contains functions to write and unpack the tar archives that files are transferred as.
+//!
+//! Archives come from peers and are not trusted, so every entry is checked to stay inside the folder of
+//! the share before it is unpacked.
+
+use std::{
+    collections::{HashMap, HashSet},
+    io::{self, SeekFrom},
+    path::{Component, Path, PathBuf},
+    sync::Arc,
+};
+
+use parking_lot::Mutex;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWrite};
+use tokio_stream::StreamExt;
+
+use super::hashing::{self, HashingReader};
+use crate::common::ConflictPolicy;
+
+const BLOCK_SIZE: u64 = 512;
+/// Paths longer than this are preceded by an extra GNU long name entry.
+const MAX_HEADER_PATH_LEN: usize = 100;
+/// The most files that the overhead of a [Limit] allows for. The file count comes from the peer, so without
+/// a bound the overhead, and with it the archive, could be any size.
+const MAX_LIMITED_FILES: u64 = 100_000;
+
+/// The paths that running downloads will move their share to, so that concurrent downloads with
+/// [ConflictPolicy::Rename] choose different names.
+static RESERVED: Mutex<Vec<PathBuf>> = parking_lot::const_mutex(Vec::new());
+
+/// A file or directory that is part of an archive.
+#[derive(Debug, Clone)]
+pub struct Entry {
+    /// The path of the entry inside the archive.
+    pub path: PathBuf,
+    /// The path of the entry on the local file system.
+    pub source: PathBuf,
+    pub is_dir: bool,
+    pub size: u64,
+    /// The number of leading bytes that are left out because the receiver already has them.
+    pub offset: u64,
+}
+
+/// Lists the entries of an archive that contains `source` under `name`.
+/// Directories are listed before their contents. Like [crate::common::FileMeta::read], links to files are
+/// followed, and links to folders and entries inside `source` that can not be read are left out.
+pub async fn entries(name: &str, source: &Path) -> io::Result<Vec<Entry>> {
+    let mut entries = vec![];
+    let mut stack = vec![(PathBuf::from(name), source.to_path_buf())];
+    while let Some((path, source)) = stack.pop() {
+        let is_root = entries.is_empty();
+        let metadata = match tokio::fs::metadata(&source).await {
+            Ok(metadata) => metadata,
+            Err(err) if !is_root => {
+                tracing::warn!("Leaving out {}: {}", source.display(), err);
+                continue;
+            }
+            Err(err) => return Err(err),
+        };
+        if metadata.is_dir() {
+            // A link to a folder could lead back to a folder that contains it.
+            if !is_root && tokio::fs::symlink_metadata(&source).await?.is_symlink() {
+                tracing::debug!("Leaving out {}, it links to a folder", source.display());
+                continue;
+            }
+            let mut dir = match tokio::fs::read_dir(&source).await {
+                Ok(dir) => dir,
+                Err(err) if !is_root => {
+                    tracing::warn!("Leaving out {}: {}", source.display(), err);
+                    continue;
+                }
+                Err(err) => return Err(err),
+            };
+            while let Some(child) = dir.next_entry().await? {
+                stack.push((path.join(child.file_name()), child.path()));
+            }
+            entries.push(Entry {
+                path,
+                source,
+                is_dir: true,
+                size: 0,
+                offset: 0,
+            });
+        } else {
+            entries.push(Entry {
+                path,
+                source,
+                is_dir: false,
+                size: metadata.len(),
+                offset: 0,
+            });
+        }
+    }
+    Ok(entries)
+}
+
+/// Returns the platform independent form of an archive path, used to refer to entries over the network.
+pub fn entry_key(path: &Path) -> String {
+    path.components()
+        .map(|c| c.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// Leaves out the files, or the parts of files, that the receiver reports as `delivered`.
+/// Returns the offsets that the partially delivered files continue from.
+pub fn resume(entries: &mut Vec<Entry>, delivered: &HashMap<String, u64>) -> HashMap<String, u64> {
+    let mut resumed = HashMap::new();
+    entries.retain_mut(|entry| {
+        if entry.is_dir {
+            return true;
+        }
+        let key = entry_key(&entry.path);
+        match delivered.get(&key) {
+            Some(&offset) if offset == entry.size => false,
+            Some(&offset) if offset < entry.size => {
+                entry.offset = offset;
+                resumed.insert(key, offset);
+                true
+            }
+            _ => true,
+        }
+    });
+    resumed
+}
+
+/// How much a peer may send for a share whose size it announced.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limit {
+    /// The most bytes of file content that are unpacked.
+    pub size: u64,
+    /// The most files, links included, that are unpacked.
+    pub file_count: u64,
+}
+
+impl Limit {
+    /// An upper bound of the bytes [write] produces for the files, allowing for a folder next to every
+    /// file and paths of up to 4 KiB, for at most [MAX_LIMITED_FILES] files.
+    pub fn archive_size(&self) -> u64 {
+        // A header, the padding of the data and a long name entry for each entry.
+        let entry_overhead = (3 + 4096 / BLOCK_SIZE) * BLOCK_SIZE;
+        let entries = 2 * self.file_count.min(MAX_LIMITED_FILES) + 1;
+        self.size
+            .saturating_add(entries * entry_overhead)
+            .saturating_add(2 * BLOCK_SIZE)
+    }
+}
+
+/// Returns the number of bytes [write] produces for the entries.
+pub fn archive_size(entries: &[Entry]) -> u64 {
+    let entries_size: u64 = entries
+        .iter()
+        .map(|entry| {
+            let path_len = entry.path.as_os_str().len();
+            let long_name_size = if path_len > MAX_HEADER_PATH_LEN {
+                BLOCK_SIZE + padded(path_len as u64 + 1)
+            } else {
+                0
+            };
+            long_name_size + BLOCK_SIZE + padded(entry.size - entry.offset)
+        })
+        .sum();
+    // The archive is terminated by two empty blocks.
+    entries_size + 2 * BLOCK_SIZE
+}
+
+fn padded(size: u64) -> u64 {
+    size.div_ceil(BLOCK_SIZE) * BLOCK_SIZE
+}
+
+/// Writes the entries as a tar archive and returns the writer when done, with the hashes of the files,
+/// keyed by [entry_key]. The files are hashed while they are written, including the leading bytes that
+/// are left out.
+pub async fn write<W>(writer: W, entries: &[Entry]) -> io::Result<(W, HashMap<String, String>)>
+where
+    W: AsyncWrite + Unpin + Send + 'static,
+{
+    let mut builder = tokio_tar::Builder::new(writer);
+    let mut hashes = HashMap::new();
+    for entry in entries {
+        if entry.is_dir {
+            builder.append_dir(&entry.path, &entry.source).await?;
+            continue;
+        }
+        let mut file = tokio::fs::File::open(&entry.source).await?;
+        let mut hasher = blake3::Hasher::new();
+        if entry.offset > 0 {
+            hashing::hash_reader(&mut (&mut file).take(entry.offset), &mut hasher).await?;
+            file.seek(SeekFrom::Start(entry.offset)).await?;
+        }
+        let mut header = tokio_tar::Header::new_gnu();
+        header.set_metadata(&file.metadata().await?);
+        header.set_size(entry.size - entry.offset);
+        let reader = HashingReader::new(file.take(entry.size - entry.offset), &mut hasher);
+        builder
+            .append_data(&mut header, &entry.path, reader)
+            .await?;
+        hashes.insert(
+            entry_key(&entry.path),
+            hasher.finalize().to_hex().to_string(),
+        );
+    }
+    Ok((builder.into_inner().await?, hashes))
+}
+
+/// Where a download is unpacked.
+/// The share is unpacked into a hidden staging folder next to where it belongs, and only moved there
+/// when the download is complete, so that a failed download does not leave partial files behind.
+#[derive(Debug, Clone)]
+pub struct Target {
+    /// The folder the share is downloaded into.
+    pub dst: PathBuf,
+    /// The name of the share, which every entry of the archive must be inside of.
+    pub name: String,
+    /// The name the share is stored as in `dst`, which differs from `name` if it was renamed.
+    pub root: String,
+    /// Tells the staging folders of different transfers apart.
+    pub id: String,
+    pub conflict: ConflictPolicy,
+    _reservation: Option<Arc<Reservation>>,
+}
+
+/// Releases a path in [RESERVED] when the last clone of the target that reserved it is dropped.
+#[derive(Debug)]
+struct Reservation(PathBuf);
+
+impl Reservation {
+    /// Reserves `path`, unless another download already did.
+    fn new(path: PathBuf) -> Option<Reservation> {
+        let mut reserved = RESERVED.lock();
+        if reserved.contains(&path) {
+            return None;
+        }
+        reserved.push(path.clone());
+        Some(Reservation(path))
+    }
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        RESERVED.lock().retain(|path| *path != self.0);
+    }
+}
+
+impl Target {
+    /// Chooses where the share is unpacked, with a new name if it already exists and the policy is
+    /// [ConflictPolicy::Rename] or another running download will store a share there.
+    /// The staging folder is named after `id`. One that was left behind by an earlier run with the same
+    /// id is kept, see [Target::staged_files].
+    pub async fn new(
+        dst: &Path,
+        name: &str,
+        id: &str,
+        conflict: ConflictPolicy,
+    ) -> io::Result<Target> {
+        let mut components = Path::new(name).components();
+        if !matches!(
+            (components.next(), components.next()),
+            (Some(Component::Normal(_)), None)
+        ) {
+            return Err(invalid(format!("{} is not a valid file name", name)));
+        }
+        if id.is_empty() || id.contains(|c: char| !c.is_ascii_alphanumeric()) {
+            return Err(invalid(format!("{} is not a valid transfer id", id)));
+        }
+        let mut root = name.to_owned();
+        let mut reservation = None;
+        if conflict == ConflictPolicy::Rename {
+            let (stem, extension) = match name.rfind('.') {
+                Some(i) if i > 0 => name.split_at(i),
+                _ => (name, ""),
+            };
+            let mut n = 1;
+            loop {
+                let path = dst.join(&root);
+                if tokio::fs::symlink_metadata(&path).await.is_err() {
+                    reservation = Reservation::new(path);
+                    if reservation.is_some() {
+                        break;
+                    }
+                }
+                root = format!("{} ({}){}", stem, n, extension);
+                n += 1;
+            }
+        }
+        Ok(Target {
+            dst: dst.to_path_buf(),
+            name: name.to_owned(),
+            root,
+            id: id.to_owned(),
+            conflict,
+            _reservation: reservation.map(Arc::new),
+        })
+    }
+
+    /// Returns the archive paths of the files in the staging folder, which an earlier run that failed
+    /// or was interrupted left behind, so that the download can continue where that run stopped.
+    pub async fn staged_files(&self) -> io::Result<Vec<PathBuf>> {
+        let mut files = vec![];
+        let mut stack = vec![(PathBuf::from(&self.name), self.staging())];
+        while let Some((path, local)) = stack.pop() {
+            let metadata = match tokio::fs::symlink_metadata(&local).await {
+                Ok(metadata) => metadata,
+                Err(err) if err.kind() == io::ErrorKind::NotFound => continue,
+                Err(err) => return Err(err),
+            };
+            if metadata.is_dir() {
+                let mut dir = tokio::fs::read_dir(&local).await?;
+                while let Some(child) = dir.next_entry().await? {
+                    stack.push((path.join(child.file_name()), child.path()));
+                }
+            } else if metadata.is_file() {
+                files.push(path);
+            }
+        }
+        Ok(files)
+    }
+
+    /// The hidden folder, or file for a single file, that the share is unpacked into.
+    pub fn staging(&self) -> PathBuf {
+        self.dst
+            .join(format!(".{}.{}.shary-partial", self.root, self.id))
+    }
+
+    /// The path in the staging folder of an archive path, which must be a relative path inside the
+    /// share.
+    pub fn staging_path(&self, path: &Path) -> io::Result<PathBuf> {
+        self.join(self.staging(), path)
+    }
+
+    /// The path an archive path is moved to when the download is complete.
+    pub fn final_path(&self, path: &Path) -> io::Result<PathBuf> {
+        self.join(self.dst.join(&self.root), path)
+    }
+
+    fn join(&self, mut local: PathBuf, path: &Path) -> io::Result<PathBuf> {
+        let mut components = path.components();
+        match components.next() {
+            Some(Component::Normal(first)) if first == self.name.as_str() => {}
+            _ => {
+                return Err(invalid(format!(
+                    "{} is outside of {}",
+                    path.display(),
+                    self.name
+                )))
+            }
+        }
+        for component in components {
+            match component {
+                Component::Normal(component) => local.push(component),
+                Component::CurDir => {}
+                _ => {
+                    return Err(invalid(format!(
+                        "{} is outside of {}",
+                        path.display(),
+                        self.name
+                    )))
+                }
+            }
+        }
+        Ok(local)
+    }
+
+    /// Moves the unpacked share from the staging folder to where it belongs, merging it with the
+    /// folders that already exist there.
+    pub async fn finish(&self) -> io::Result<()> {
+        let staging = self.staging();
+        if tokio::fs::symlink_metadata(&staging).await.is_err() {
+            return Ok(()); // Every file was skipped
+        }
+        // Files are moved without following links, so that a link that exists where the share belongs
+        // is replaced instead of written through.
+        let mut stack = vec![(staging.clone(), self.dst.join(&self.root))];
+        while let Some((src, dst)) = stack.pop() {
+            let src_is_dir = tokio::fs::symlink_metadata(&src).await?.is_dir();
+            match tokio::fs::symlink_metadata(&dst).await {
+                Err(_) => tokio::fs::rename(&src, &dst).await?,
+                Ok(existing) if existing.is_dir() && src_is_dir => {
+                    let mut dir = tokio::fs::read_dir(&src).await?;
+                    while let Some(child) = dir.next_entry().await? {
+                        stack.push((child.path(), dst.join(child.file_name())));
+                    }
+                }
+                Ok(_) if self.conflict == ConflictPolicy::Skip => {}
+                Ok(existing) if existing.is_dir() => {
+                    return Err(invalid(format!("{} is a folder", dst.display())));
+                }
+                Ok(_) => {
+                    tokio::fs::remove_file(&dst).await?;
+                    tokio::fs::rename(&src, &dst).await?;
+                }
+            }
+        }
+        self.discard().await
+    }
+
+    /// Removes the staging folder and everything that was unpacked into it.
+    pub async fn discard(&self) -> io::Result<()> {
+        let staging = self.staging();
+        match tokio::fs::symlink_metadata(&staging).await {
+            Ok(metadata) if metadata.is_dir() => tokio::fs::remove_dir_all(&staging).await,
+            Ok(_) => tokio::fs::remove_file(&staging).await,
+            Err(_) => Ok(()),
+        }
+    }
+}
+
+/// Unpacks a tar archive into the staging folder of the target.
+/// Entries outside of the share and links that point outside of it are refused. Files that already
+/// exist where the share belongs are skipped if the conflict policy of the target says so.
+/// Files listed in `resumed` are appended to from the given offset instead of being replaced.
+/// The archive paths of all files that were written to are added to `written`.
+/// Unpacking fails once the archive holds more than the `limit` allows.
+pub async fn unpack<R>(
+    reader: R,
+    target: &Target,
+    resumed: &HashMap<String, u64>,
+    written: &mut HashSet<PathBuf>,
+    limit: Option<Limit>,
+) -> io::Result<()>
+where
+    R: AsyncRead + Unpin + Send + Sync,
+{
+    tokio::fs::create_dir_all(&target.dst).await?;
+    let dst = tokio::fs::canonicalize(&target.dst).await?;
+    let staging = dst.join(target.staging().file_name().unwrap_or_default());
+    let mut archive = tokio_tar::Archive::new(reader);
+    let mut entries = archive.entries()?;
+    let (mut size, mut file_count) = (0u64, 0u64);
+    while let Some(entry) = entries.next().await {
+        let mut entry = entry?;
+        let path = entry.path()?.into_owned();
+        let local = target.staging_path(&path)?;
+        let kind = entry.header().entry_type();
+        if let Some(limit) = limit {
+            if !kind.is_dir() {
+                size = size.saturating_add(entry.header().size()?);
+                file_count += 1;
+            }
+            if size > limit.size || file_count > limit.file_count {
+                return Err(invalid(format!(
+                    "{} holds more than the {} bytes in {} files that were announced",
+                    target.name, limit.size, limit.file_count
+                )));
+            }
+        }
+
+        // The parent is resolved, so that links that were unpacked can not lead outside either. Its
+        // deepest existing folder is checked before the missing folders are created in it.
+        let parent = local.parent().unwrap_or(&target.dst);
+        let outside = || invalid(format!("{} is outside of {}", path.display(), target.name));
+        let mut existing = parent;
+        while tokio::fs::symlink_metadata(existing).await.is_err() {
+            existing = existing.parent().ok_or_else(outside)?;
+        }
+        let resolved = tokio::fs::canonicalize(existing).await?;
+        if resolved != dst && !resolved.starts_with(&staging) {
+            return Err(outside());
+        }
+        tokio::fs::create_dir_all(parent).await?;
+        let parent = tokio::fs::canonicalize(parent).await?;
+        if parent != dst && !parent.starts_with(&staging) {
+            return Err(outside());
+        }
+        let local = parent.join(local.file_name().unwrap_or_default());
+
+        let ours = written.contains(&path);
+        if !ours && !kind.is_dir() && target.conflict == ConflictPolicy::Skip {
+            let final_path = target.final_path(&path)?;
+            if tokio::fs::symlink_metadata(&final_path).await.is_ok() {
+                tracing::debug!("Skipping {}, it already exists", final_path.display());
+                continue;
+            }
+        }
+        // Only this download writes to the staging folder, existing files are from an earlier attempt.
+        let existing = tokio::fs::symlink_metadata(&local).await.ok();
+        if existing.as_ref().is_some_and(|e| e.is_dir()) && !kind.is_dir() {
+            return Err(invalid(format!("{} is a folder", local.display())));
+        }
+
+        if kind.is_dir() {
+            if existing.as_ref().is_some_and(|e| !e.is_dir()) {
+                tokio::fs::remove_file(&local).await?;
+            }
+            entry.unpack(&local).await?;
+        } else if kind.is_symlink() {
+            let link = entry
+                .link_name()?
+                .ok_or_else(|| invalid(format!("{} has no link target", path.display())))?;
+            // The depth is taken from where the link is actually created, since the archive path can
+            // go through links that were unpacked before it.
+            let depth = parent
+                .strip_prefix(&staging)
+                .map_or(0, |folder| folder.components().count() + 1);
+            if !link_inside(depth, &link) {
+                return Err(invalid(format!(
+                    "{} links outside of {}",
+                    path.display(),
+                    target.name
+                )));
+            }
+            if existing.is_some() {
+                tokio::fs::remove_file(&local).await?;
+            }
+            entry.unpack(&local).await?;
+        } else if kind.is_hard_link() {
+            let link = entry
+                .link_name()?
+                .ok_or_else(|| invalid(format!("{} has no link target", path.display())))?;
+            let source = target.staging_path(&link)?;
+            if !tokio::fs::symlink_metadata(&source).await?.is_file() {
+                return Err(invalid(format!(
+                    "{} links to something that is not a file",
+                    path.display()
+                )));
+            }
+            if existing.is_some() {
+                tokio::fs::remove_file(&local).await?;
+            }
+            tokio::fs::hard_link(&source, &local).await?;
+        } else if kind.is_file() || kind.is_contiguous() {
+            written.insert(path.clone());
+            match resumed.get(&entry_key(&path)) {
+                Some(&offset) if ours && existing.as_ref().is_some_and(|e| e.is_file()) => {
+                    let mut file = tokio::fs::OpenOptions::new()
+                        .write(true)
+                        .open(&local)
+                        .await?;
+                    file.set_len(offset).await?;
+                    file.seek(SeekFrom::Start(offset)).await?;
+                    tokio::io::copy(&mut entry, &mut file).await?;
+                }
+                // Unpacking replaces an existing file instead of writing through it.
+                _ => {
+                    entry.unpack(&local).await?;
+                }
+            }
+        } else {
+            tracing::warn!(
+                "Skipping {}, it is not a file, folder or link",
+                path.display()
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Whether a symlink that is `depth` folders deep in the share, counting the share, and points to `link`
+/// stays inside the share. The link may only go up with leading `..`, so that it can not go up from a
+/// folder that is itself a link, and the share itself can not be a link.
+fn link_inside(mut depth: usize, link: &Path) -> bool {
+    if depth == 0 {
+        return false;
+    }
+    let mut descended = false;
+    for component in link.components() {
+        match component {
+            Component::Normal(_) => descended = true,
+            Component::CurDir => {}
+            Component::ParentDir if !descended && depth > 1 => depth -= 1,
+            _ => return false,
+        }
+    }
+    true
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
@@ -1,172 +1,727 @@
-//! This module contains functions to send and receive device discovery data.
-
-use std::{
-    cell::Cell,
-    collections::HashMap,
-    net::{Ipv4Addr, SocketAddr, SocketAddrV4},
-    sync::Arc,
-    time::{Duration, Instant},
-};
-
-use crate::common::{LocalFile, RemoteFile};
-use bytes::{BufMut, BytesMut};
-use color_eyre::{eyre::WrapErr, Result};
-use serde::{Deserialize, Serialize};
-use tokio::{net::UdpSocket, sync::watch, time::timeout};
-
-/// Contains the information about the files that someone else is sharing.
-#[derive(Debug)]
-pub struct RemoteFiles {
-    pub files: Vec<String>,
-    pub addr: SocketAddr,
-}
-
-/// Periodically sends the current local files to the supplied socket address.
-/// If nothing fails, the function will never return.
-/// If the connected sender is dropped, this function will return [Ok(())].
-pub async fn run_discovery_sender(
-    mut files_rx: watch::Receiver<Vec<LocalFile>>,
-    addr: SocketAddrV4,
-) -> Result<()> {
-    let bind_addr = SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0);
-    let socket = UdpSocket::bind(bind_addr)
-        .await
-        .wrap_err("failed to bind socket")?;
-    socket
-        .set_multicast_loop_v4(false)
-        .wrap_err("failed to set multicast looping to off")?;
-    socket
-        .connect(addr)
-        .await
-        .wrap_err("failed to connect socket")?;
-    let mut buf = BytesMut::new();
-    loop {
-        let update_buffer = if buf.is_empty() {
-            true
-        } else {
-            match files_rx.has_changed() {
-                Ok(changed) => changed,
-                Err(_) => return Ok(()), // files_rx is closed and we can stop
-            }
-        };
-        if update_buffer {
-            tracing::debug!("Writing discovery files to send buffer.");
-            buf.clear();
-            let files: Vec<String> = files_rx.borrow_and_update().iter().map(|l| l.name.clone()).collect();
-            let packet = Packet { files };
-            let mut writer = buf.writer();
-            let json_result = serde_json::to_writer(&mut writer, &packet);
-            buf = writer.into_inner();
-            if let Err(error) = json_result {
-                buf.clear();
-                tracing::error!("Failed to format packet to json: {}", error);
-            }
-        }
-        if !buf.is_empty() {
-            socket
-                .send(&buf)
-                .await
-                .wrap_err("failed to write discovery files to socket")?;
-        }
-        tokio::time::sleep(Duration::from_secs(1)).await;
-    }
-}
-
-/// Receives remote files using a multicast address.
-/// If nothing fails, the function will never return.
-/// If the connected receiver is dropped, this function will return [Ok(())].
-pub async fn run_discovery_receiver(
-    files_tx: &watch::Sender<Arc<Vec<RemoteFile>>>,
-    port: u16,
-    multicast_addr: Ipv4Addr,
-) -> Result<()> {
-    let bind_addr = SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, port);
-    let socket = UdpSocket::bind(bind_addr)
-        .await
-        .wrap_err("failed to bind socket to addr")?;
-    socket
-        .set_multicast_loop_v4(false)
-        .wrap_err("failed to set multicast looping to false")?;
-    socket
-        .join_multicast_v4(multicast_addr, Ipv4Addr::UNSPECIFIED)
-        .wrap_err("failed to join multicast")?;
-
-    let mut db: HashMap<SocketAddr, (Vec<String>, Cell<Instant>)> = HashMap::new();
-    let mut buf = vec![0;64000];
-
-    fn map_remote_files(
-        db: &HashMap<SocketAddr, (Vec<String>, Cell<Instant>)>,
-    ) -> Arc<Vec<RemoteFile>> {
-        Arc::new(
-            db.iter()
-                .flat_map(|(addr, (files, _))| {
-                    files.iter().map(|f| RemoteFile {
-                        addr: *addr,
-                        file: f.clone(),
-                    })
-                })
-                .collect(),
-        )
-    }
-
-    loop {
-        timeout(Duration::from_secs(1), socket.readable())
-            .await
-            .ok();
-
-        // Handle timeouts
-        let timeout_addrs: Vec<SocketAddr> = db
-            .iter()
-            .filter_map(|(addr, (_, time))| {
-                if time.get().elapsed() > Duration::from_secs(5) {
-                    Some(*addr)
-                } else {
-                    None
-                }
-            })
-            .collect();
-        for addr in timeout_addrs.iter() {
-            db.remove(addr);
-        }
-        if !timeout_addrs.is_empty() && files_tx.send(map_remote_files(&db)).is_err() {
-            return Ok(());
-        }
-
-        let (size, mut addr) = match socket.try_recv_from(&mut buf) {
-            Ok(result) => result,
-            Err(err) => {
-                if err.kind() != tokio::io::ErrorKind::WouldBlock {
-                    tracing::error!("Failed to read from discovery socket: {}", err);
-                }
-                continue;
-            }
-        };
-        addr.set_port(port);
-        let result: Result<Packet, serde_json::Error> = serde_json::from_slice(&buf[0..size]);
-        match result {
-            Err(err) => {
-                tracing::error!("Failed to parse discovery json: {}", err);
-                continue;
-            }
-            Ok(packet) => {
-                tracing::debug!("Received from {addr}: {:?}", packet);
-                if let Some((files, time)) = db.get(&addr) {
-                    if files == &packet.files {
-                        time.replace(Instant::now());
-                        continue;
-                    }
-                }
-                db.insert(addr, (packet.files, Cell::new(Instant::now())));
-            }
-        }
-
-        if files_tx.send(map_remote_files(&db)).is_err() {
-            return Ok(());
-        }
-    }
-}
-
-#[derive(Debug, Clone, Serialize, Deserialize)]
-struct Packet {
-    files: Vec<String>,
-}
+//! This module contains functions to send and receive device discovery data.
+//!
+//! Packets are sent to an IPv4 and an IPv6 multicast group, so a device that is reachable over both is
+//! known by both addresses.
+//! The multicast discovery is one [Discovery] backend, [super::mdns] is the other.
+
+use std::{
+    collections::HashMap,
+    future::{poll_fn, Future},
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6},
+    pin::Pin,
+    sync::Arc,
+    task::Poll,
+    time::{Duration, Instant},
+};
+
+use super::interfaces::{self, Interface};
+use super::mdns::MdnsDiscovery;
+use super::peers::fetch_listing;
+use super::protocol::{
+    listed_files, listing_revision, text_hash, ListedFile, Listing, CAPABILITIES, PROTOCOL_VERSION,
+};
+use super::secure::Identity;
+use super::{IPV4_MULTICAST_ADDR, IPV6_MULTICAST_ADDR};
+use crate::common::{
+    Capability, Device, FileMeta, Files, LocalFile, RemoteDevice, RemoteFile, SharePolicy,
+};
+use color_eyre::{
+    eyre::{eyre, WrapErr},
+    Result,
+};
+use serde::{Deserialize, Serialize};
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::{
+    io::ReadBuf,
+    net::UdpSocket,
+    sync::{mpsc, watch},
+    time::timeout,
+};
+
+/// How long an address of a device is used after the last packet from it.
+const PEER_TIMEOUT: Duration = Duration::from_secs(5);
+/// The largest packet that is sent, so that it is not fragmented on any network.
+pub const MAX_PACKET_LEN: usize = 1200;
+/// The longest text that is sent in the packet, longer texts are fetched by receivers.
+const MAX_PACKET_TEXT_LEN: usize = 256;
+/// How long a device may take to send its listing.
+pub(super) const LISTING_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long to wait before fetching a listing again after it failed.
+pub(super) const LISTING_RETRY_DELAY: Duration = Duration::from_secs(5);
+
+pub type DiscoveryFuture<'a> = Pin<Box<dyn Future<Output = Result<()>> + Send + 'a>>;
+
+/// A way to tell peers about the local files and to find the files of peers.
+pub trait Discovery: Send + Sync {
+    /// Advertises the local files, the device and the key fingerprint of `identity` until an error occurs.
+    /// If the local files or the device senders are dropped, the future completes with [Ok(())].
+    fn advertise<'a>(
+        &'a self,
+        files: &'a Files,
+        identity: &'a Arc<Identity>,
+    ) -> DiscoveryFuture<'a>;
+
+    /// Publishes the files of the peers that are found to the discovered files until an error occurs.
+    /// If the discovered files receivers are dropped, the future completes with [Ok(())].
+    fn browse<'a>(&'a self, files: &'a Files, identity: &'a Arc<Identity>) -> DiscoveryFuture<'a>;
+}
+
+/// The available [Discovery] backends.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DiscoveryBackend {
+    /// JSON packets sent to the shary multicast groups.
+    #[default]
+    Multicast,
+    /// A `_shary._tcp` DNS-SD service announced with mDNS.
+    Mdns,
+}
+
+impl DiscoveryBackend {
+    /// Creates the backend, which only uses the named interfaces, or all of them if there are none.
+    pub fn create(self, port: u16, interfaces: Vec<String>) -> Result<Box<dyn Discovery>> {
+        // Fail on unknown interface names now, instead of every time the network restarts.
+        interfaces::list(&interfaces)?;
+        Ok(match self {
+            DiscoveryBackend::Multicast => Box::new(MulticastDiscovery { port, interfaces }),
+            DiscoveryBackend::Mdns => Box::new(MdnsDiscovery::new(port, interfaces)?),
+        })
+    }
+}
+
+/// Discovery with [run_discovery_sender] and [run_discovery_receiver] on the shary multicast groups.
+pub struct MulticastDiscovery {
+    pub port: u16,
+    /// The names of the interfaces to use, or empty to use all of them.
+    pub interfaces: Vec<String>,
+}
+
+impl Discovery for MulticastDiscovery {
+    fn advertise<'a>(
+        &'a self,
+        files: &'a Files,
+        identity: &'a Arc<Identity>,
+    ) -> DiscoveryFuture<'a> {
+        Box::pin(async move {
+            let fingerprint = identity.fingerprint();
+            // Listed on every run, so that interfaces that came up since the last run are used.
+            let interfaces = interfaces::list(&self.interfaces)?;
+            let send_v4 = run_discovery_sender(
+                files.get_local_files(),
+                files.get_device(),
+                SocketAddr::from((IPV4_MULTICAST_ADDR, self.port)),
+                &interfaces,
+                fingerprint.clone(),
+            );
+
+            // Many networks have no IPv6, so the IPv4 discovery keeps running without it.
+            let send_v6 = async {
+                let result = run_discovery_sender(
+                    files.get_local_files(),
+                    files.get_device(),
+                    SocketAddr::from((IPV6_MULTICAST_ADDR, self.port)),
+                    &interfaces,
+                    fingerprint.clone(),
+                )
+                .await;
+                if let Err(err) = result {
+                    tracing::warn!("IPv6 discovery is unavailable: {:?}", err);
+                }
+                Ok(())
+            };
+
+            tokio::try_join!(send_v4, send_v6)?;
+            Ok(())
+        })
+    }
+
+    fn browse<'a>(&'a self, files: &'a Files, identity: &'a Arc<Identity>) -> DiscoveryFuture<'a> {
+        Box::pin(async move {
+            let interfaces = interfaces::list(&self.interfaces)?;
+            let multicast_addrs = [
+                IpAddr::V4(IPV4_MULTICAST_ADDR),
+                IpAddr::V6(IPV6_MULTICAST_ADDR),
+            ];
+            let identity = Arc::clone(identity);
+            run_discovery_receiver(
+                &files.discovered_files_tx,
+                &files.discovered_devices_tx,
+                self.port,
+                &multicast_addrs,
+                &interfaces,
+                identity,
+            )
+            .await
+        })
+    }
+}
+
+/// Periodically sends the current local files, the device and the key fingerprint to the supplied socket
+/// address, on each of the interfaces if the address is a multicast address.
+/// If nothing fails, the function will never return.
+/// If the connected sender is dropped, this function will return [Ok(())].
+pub async fn run_discovery_sender(
+    mut files_rx: watch::Receiver<Vec<LocalFile>>,
+    mut device_rx: watch::Receiver<Device>,
+    addr: SocketAddr,
+    interfaces: &[Interface],
+    fingerprint: String,
+) -> Result<()> {
+    let mut sockets = vec![];
+    if addr.ip().is_multicast() && !interfaces.is_empty() {
+        for interface in interfaces {
+            match bind_sender(addr, Some(interface)).await {
+                Ok(interface_sockets) => sockets.extend(interface_sockets),
+                Err(err) => {
+                    tracing::warn!("Failed to send discovery on {}: {:?}", interface.name, err)
+                }
+            }
+        }
+        if sockets.is_empty() {
+            return Err(eyre!("failed to bind a discovery socket on any interface"));
+        }
+    } else {
+        sockets = bind_sender(addr, None).await?;
+    }
+    let mut buf = vec![];
+    loop {
+        let update_buffer = if buf.is_empty() {
+            true
+        } else {
+            match (files_rx.has_changed(), device_rx.has_changed()) {
+                (Ok(files_changed), Ok(device_changed)) => files_changed || device_changed,
+                _ => return Ok(()), // a receiver is closed and we can stop
+            }
+        };
+        if update_buffer {
+            tracing::debug!("Writing discovery beacon to send buffer.");
+            let local_files = files_rx.borrow_and_update().clone();
+            let device = device_rx.borrow_and_update().clone();
+            buf = match beacon(&device, &local_files, &fingerprint) {
+                Ok(beacon) => beacon,
+                Err(error) => {
+                    tracing::error!("Failed to format packet to json: {:?}", error);
+                    vec![]
+                }
+            };
+        }
+        if !buf.is_empty() {
+            // An interface that goes down should not stop the discovery on the others.
+            let mut errors = vec![];
+            for socket in &sockets {
+                if let Err(err) = socket.send(&buf).await {
+                    tracing::debug!(
+                        "Failed to send discovery from {:?}: {}",
+                        socket.local_addr(),
+                        err
+                    );
+                    errors.push(err);
+                }
+            }
+            if errors.len() == sockets.len() {
+                if let Some(err) = errors.pop() {
+                    return Err(err).wrap_err("failed to write discovery files to socket");
+                }
+            }
+        }
+        tokio::time::sleep(Duration::from_secs(1)).await;
+    }
+}
+
+/// The packet that announces the device and the revision of its files, as json.
+/// Only the revision of the files is sent, receivers ask for the listing when it changes, so the packet
+/// fits in one datagram however many files are shared. Short texts that anyone may have are sent along, as
+/// many as fit. A device name that is too long is shortened.
+pub fn beacon(device: &Device, local_files: &[LocalFile], fingerprint: &str) -> Result<Vec<u8>> {
+    let texts = local_files
+        .iter()
+        .filter_map(|local_file| match local_file {
+            LocalFile::Text {
+                text,
+                name,
+                policy: SharePolicy::Public,
+                ..
+            } if text.len() <= MAX_PACKET_TEXT_LEN => Some(PacketText {
+                name: name.clone(),
+                text: text.clone(),
+            }),
+            _ => None,
+        })
+        .collect();
+    let mut packet = Packet {
+        device_id: device.id.clone(),
+        device_name: device.name.clone(),
+        files: vec![],
+        meta: vec![],
+        fingerprint: fingerprint.to_string(),
+        version: PROTOCOL_VERSION,
+        capabilities: CAPABILITIES.to_vec(),
+        revision: Some(listing_revision(&listed_files(local_files))),
+        texts,
+    };
+    loop {
+        let json = serde_json::to_vec(&packet).wrap_err("failed to format packet to json")?;
+        if json.len() <= MAX_PACKET_LEN {
+            return Ok(json);
+        }
+        if packet.texts.pop().is_some() {
+            continue;
+        }
+        let mut excess = json.len() - MAX_PACKET_LEN;
+        while excess > 0 {
+            match packet.device_name.pop() {
+                Some(c) => excess = excess.saturating_sub(c.len_utf8()),
+                None => {
+                    return Err(eyre!(
+                        "discovery packet of {} bytes is too large",
+                        json.len()
+                    ))
+                }
+            }
+        }
+    }
+}
+
+/// Binds the sockets that send to the address, one for each IPv4 address of the interface, or one that
+/// sends on the default interface.
+async fn bind_sender(addr: SocketAddr, interface: Option<&Interface>) -> Result<Vec<UdpSocket>> {
+    let sockets = match (addr, interface) {
+        (SocketAddr::V4(_), Some(interface)) => interface
+            .ipv4_addrs()
+            .map(|ip| {
+                let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))
+                    .wrap_err("failed to create socket")?;
+                socket
+                    .set_multicast_if_v4(&ip)
+                    .wrap_err("failed to set multicast interface")?;
+                socket
+                    .bind(&SocketAddrV4::new(ip, 0).into())
+                    .wrap_err("failed to bind socket")?;
+                Ok(socket)
+            })
+            .collect::<Result<Vec<_>>>()?,
+        (SocketAddr::V6(_), Some(interface)) if interface.has_ipv6() => {
+            let socket = Socket::new(Domain::IPV6, Type::DGRAM, Some(Protocol::UDP))
+                .wrap_err("failed to create socket")?;
+            socket
+                .set_multicast_if_v6(interface.index)
+                .wrap_err("failed to set multicast interface")?;
+            socket
+                .bind(&SocketAddrV6::new(Ipv6Addr::UNSPECIFIED, 0, 0, 0).into())
+                .wrap_err("failed to bind socket")?;
+            vec![socket]
+        }
+        (_, Some(_)) => vec![],
+        (SocketAddr::V4(_), None) => {
+            let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))
+                .wrap_err("failed to create socket")?;
+            socket
+                .bind(&SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0).into())
+                .wrap_err("failed to bind socket")?;
+            vec![socket]
+        }
+        (SocketAddr::V6(_), None) => {
+            let socket = Socket::new(Domain::IPV6, Type::DGRAM, Some(Protocol::UDP))
+                .wrap_err("failed to create socket")?;
+            socket
+                .bind(&SocketAddrV6::new(Ipv6Addr::UNSPECIFIED, 0, 0, 0).into())
+                .wrap_err("failed to bind socket")?;
+            vec![socket]
+        }
+    };
+
+    let mut udp_sockets = vec![];
+    for socket in sockets {
+        match addr {
+            SocketAddr::V4(_) => socket
+                .set_multicast_loop_v4(false)
+                .wrap_err("failed to set multicast looping to off")?,
+            SocketAddr::V6(_) => socket
+                .set_multicast_loop_v6(false)
+                .wrap_err("failed to set multicast looping to off")?,
+        }
+        socket
+            .set_nonblocking(true)
+            .wrap_err("failed to set socket to non-blocking")?;
+        let socket = UdpSocket::from_std(socket.into()).wrap_err("failed to register socket")?;
+        socket
+            .connect(addr)
+            .await
+            .wrap_err("failed to connect socket")?;
+        udp_sockets.push(socket);
+    }
+    Ok(udp_sockets)
+}
+
+/// Receives remote files using the supplied multicast addresses, joined on each of the interfaces or on
+/// the default interface if there are none. The devices, including those that share nothing, are sent to
+/// `devices_tx`.
+/// The files of a device are fetched from it with `identity` whenever its beacon has a new revision.
+/// Addresses that can not be used are skipped, as long as at least one can be used.
+/// If nothing fails, the function will never return.
+/// If the connected receiver is dropped, this function will return [Ok(())].
+pub async fn run_discovery_receiver(
+    files_tx: &watch::Sender<Arc<Vec<RemoteFile>>>,
+    devices_tx: &watch::Sender<Arc<Vec<RemoteDevice>>>,
+    port: u16,
+    multicast_addrs: &[IpAddr],
+    interfaces: &[Interface],
+    identity: Arc<Identity>,
+) -> Result<()> {
+    let mut sockets = vec![];
+    for multicast_addr in multicast_addrs {
+        match bind_receiver(port, *multicast_addr, interfaces).await {
+            Ok(socket) => sockets.push(socket),
+            Err(err) => tracing::warn!("Discovery on {} is unavailable: {:?}", multicast_addr, err),
+        }
+    }
+    if sockets.is_empty() {
+        return Err(eyre!("failed to bind any discovery socket"));
+    }
+
+    // Keyed by device id, so that a device that changes its address is still the same device.
+    let mut db: HashMap<String, Peer> = HashMap::new();
+    let mut buf = vec![0; 64000];
+    let (listings_tx, mut listings_rx) = mpsc::unbounded_channel();
+
+    fn map_remote_files(
+        db: &HashMap<String, Peer>,
+        interfaces: &[Interface],
+    ) -> Arc<Vec<RemoteFile>> {
+        Arc::new(
+            db.iter()
+                .flat_map(|(device_id, peer)| {
+                    let packet = &peer.packet;
+                    let addrs: Vec<SocketAddr> = peer.addrs.iter().map(|(addr, _)| *addr).collect();
+                    let interface = interfaces::find(interfaces, &addrs[0]).map(|i| i.name.clone());
+                    peer.files()
+                        .into_iter()
+                        .map(move |(file, meta, text)| RemoteFile {
+                            addrs: addrs.clone(),
+                            device_id: device_id.clone(),
+                            device_name: packet.device_name.clone(),
+                            file,
+                            fingerprint: packet.fingerprint.clone(),
+                            version: packet.version,
+                            capabilities: packet.capabilities.clone(),
+                            meta,
+                            interface: interface.clone(),
+                            text,
+                        })
+                })
+                .collect(),
+        )
+    }
+
+    loop {
+        let mut changed = false;
+        tokio::select! {
+            received = timeout(Duration::from_secs(1), recv_any(&sockets, &mut buf)) => match received {
+                Ok(Ok((size, mut addr))) => {
+                    addr.set_port(port);
+                    let result: Result<Packet, serde_json::Error> = serde_json::from_slice(&buf[0..size]);
+                    match result {
+                        Err(err) => tracing::error!("Failed to parse discovery json: {}", err),
+                        Ok(mut packet) => {
+                            tracing::debug!("Received from {addr}: {:?}", packet);
+                            if packet.device_id.is_empty() {
+                                packet.device_id = addr.to_string();
+                            }
+                            if packet.device_name.is_empty() {
+                                packet.device_name = addr.ip().to_string();
+                            }
+                            changed |= update_peer(&mut db, addr, packet);
+                        }
+                    }
+                }
+                Ok(Err(err)) => tracing::error!("Failed to read from discovery socket: {}", err),
+                Err(_) => {} // No packet within the timeout
+            },
+            Some((device_id, result)) = listings_rx.recv() => {
+                if let Some(peer) = db.get_mut(&device_id) {
+                    peer.fetching = false;
+                    match result {
+                        Ok(listing) => {
+                            let listing: Listing = listing;
+                            peer.listing = Some((listing.revision, listing.files));
+                            changed = true;
+                        }
+                        Err(err) => {
+                            tracing::warn!("Failed to fetch the files of {}: {:?}", device_id, err);
+                            peer.retry_at = Some(Instant::now() + LISTING_RETRY_DELAY);
+                        }
+                    }
+                }
+            }
+        }
+
+        // Handle timeouts
+        db.retain(|_, peer| {
+            let len = peer.addrs.len();
+            peer.addrs
+                .retain(|(_, time)| time.elapsed() <= PEER_TIMEOUT);
+            changed |= peer.addrs.len() != len;
+            !peer.addrs.is_empty()
+        });
+
+        for (device_id, peer) in db.iter_mut().filter(|(_, peer)| peer.needs_listing()) {
+            peer.fetching = true;
+            let device_id = device_id.clone();
+            let addrs: Vec<SocketAddr> = peer.addrs.iter().map(|(addr, _)| *addr).collect();
+            let fingerprint = peer.packet.fingerprint.clone();
+            let identity = Arc::clone(&identity);
+            let listings_tx = listings_tx.clone();
+            tokio::spawn(async move {
+                let result = match timeout(LISTING_TIMEOUT, fetch_listing(&identity, &addrs)).await
+                {
+                    Ok(Ok((_, remote_fingerprint, _))) if remote_fingerprint != fingerprint => {
+                        Err(eyre!("peer key does not match the advertised fingerprint"))
+                    }
+                    Ok(result) => result.map(|(_, _, listing)| listing),
+                    Err(_) => Err(eyre!("the peer did not answer in time")),
+                };
+                let _ = listings_tx.send((device_id, result));
+            });
+        }
+
+        if changed {
+            let devices = db
+                .iter()
+                .map(|(device_id, peer)| RemoteDevice {
+                    addrs: peer.addrs.iter().map(|(addr, _)| *addr).collect(),
+                    device_id: device_id.clone(),
+                    device_name: peer.packet.device_name.clone(),
+                    fingerprint: peer.packet.fingerprint.clone(),
+                    capabilities: peer.packet.capabilities.clone(),
+                })
+                .collect();
+            devices_tx.send_replace(Arc::new(devices));
+            if files_tx.send(map_remote_files(&db, interfaces)).is_err() {
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// What the receiver knows about a device.
+struct Peer {
+    packet: Packet,
+    /// The addresses the device sent from, first seen first, with the time of the last packet.
+    addrs: Vec<(SocketAddr, Instant)>,
+    /// The last listing fetched from the device, with its revision.
+    listing: Option<(u64, Vec<ListedFile>)>,
+    /// Whether a listing is being fetched.
+    fetching: bool,
+    /// When to try again after fetching the listing failed.
+    retry_at: Option<Instant>,
+}
+
+impl Peer {
+    fn new(packet: Packet, addr: SocketAddr) -> Peer {
+        Peer {
+            packet,
+            addrs: vec![(addr, Instant::now())],
+            listing: None,
+            fetching: false,
+            retry_at: None,
+        }
+    }
+
+    /// Whether the device announces a revision that has not been fetched yet.
+    fn needs_listing(&self) -> bool {
+        let outdated = match self.packet.revision {
+            Some(revision) => self.listing.as_ref().map(|(r, _)| *r) != Some(revision),
+            None => false,
+        };
+        outdated && !self.fetching && self.retry_at.is_none_or(|at| Instant::now() >= at)
+    }
+
+    /// The files with their metadata and the texts that came with the packet, from the listing or, for
+    /// peers that send their files in their packets, from the packet.
+    fn files(&self) -> Vec<(String, Option<FileMeta>, Option<String>)> {
+        match (&self.packet.revision, &self.listing) {
+            (Some(_), Some((_, files))) => files
+                .iter()
+                .map(|f| {
+                    // Anyone can send a packet, so only texts that match the hash in the listing, which
+                    // comes over an authenticated connection, are taken from it.
+                    let text = self
+                        .packet
+                        .texts
+                        .iter()
+                        .find(|t| {
+                            f.meta.is_text
+                                && t.name == f.name
+                                && f.hash.as_deref() == Some(text_hash(&t.text).as_str())
+                        })
+                        .map(|t| t.text.clone());
+                    (f.name.clone(), Some(f.meta.clone()), text)
+                })
+                .collect(),
+            (Some(_), None) => vec![],
+            (None, _) => self
+                .packet
+                .files
+                .iter()
+                .enumerate()
+                .map(|(i, f)| (f.clone(), self.packet.meta.get(i).cloned(), None))
+                .collect(),
+        }
+    }
+}
+
+/// Records a packet and returns whether the remote files changed.
+fn update_peer(db: &mut HashMap<String, Peer>, addr: SocketAddr, packet: Packet) -> bool {
+    let peer = match db.get_mut(&packet.device_id) {
+        Some(peer) => peer,
+        None => {
+            db.insert(packet.device_id.clone(), Peer::new(packet, addr));
+            return true;
+        }
+    };
+    // Anyone can send the id of another device, so packets with a different key are not from the device
+    // that is known. A device with a new key is listed again once the entry of its old key times out.
+    if peer.packet.fingerprint != packet.fingerprint {
+        tracing::debug!(
+            "Ignoring a packet from {} with the id of another device",
+            addr
+        );
+        return false;
+    }
+    let mut changed = false;
+    if peer.packet != packet {
+        if peer.packet.revision != packet.revision {
+            peer.retry_at = None;
+        }
+        peer.packet = packet;
+        changed = true;
+    }
+    match peer.addrs.iter_mut().find(|(known, _)| *known == addr) {
+        Some((_, time)) => *time = Instant::now(),
+        None => {
+            peer.addrs.push((addr, Instant::now()));
+            changed = true;
+        }
+    }
+    changed
+}
+
+async fn bind_receiver(
+    port: u16,
+    multicast_addr: IpAddr,
+    interfaces: &[Interface],
+) -> Result<UdpSocket> {
+    match multicast_addr {
+        IpAddr::V4(multicast_addr) => {
+            let bind_addr = SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, port);
+            let socket = UdpSocket::bind(bind_addr)
+                .await
+                .wrap_err("failed to bind socket to addr")?;
+            socket
+                .set_multicast_loop_v4(false)
+                .wrap_err("failed to set multicast looping to false")?;
+            let mut joined = 0;
+            for interface in interfaces {
+                for ip in interface.ipv4_addrs() {
+                    match socket.join_multicast_v4(multicast_addr, ip) {
+                        Ok(()) => joined += 1,
+                        Err(err) => tracing::warn!(
+                            "Failed to join multicast on {}: {}",
+                            interface.name,
+                            err
+                        ),
+                    }
+                }
+            }
+            if joined == 0 {
+                socket
+                    .join_multicast_v4(multicast_addr, Ipv4Addr::UNSPECIFIED)
+                    .wrap_err("failed to join multicast")?;
+            }
+            Ok(socket)
+        }
+        IpAddr::V6(multicast_addr) => {
+            // Only IPv6, so that the socket does not take the port of the IPv4 socket.
+            let socket = Socket::new(Domain::IPV6, Type::DGRAM, Some(Protocol::UDP))
+                .wrap_err("failed to create socket")?;
+            socket
+                .set_only_v6(true)
+                .wrap_err("failed to set socket to IPv6 only")?;
+            socket
+                .set_nonblocking(true)
+                .wrap_err("failed to set socket to non-blocking")?;
+            let bind_addr = SocketAddrV6::new(Ipv6Addr::UNSPECIFIED, port, 0, 0);
+            socket
+                .bind(&bind_addr.into())
+                .wrap_err("failed to bind socket to addr")?;
+            let socket =
+                UdpSocket::from_std(socket.into()).wrap_err("failed to register socket")?;
+            socket
+                .set_multicast_loop_v6(false)
+                .wrap_err("failed to set multicast looping to false")?;
+            let mut joined = 0;
+            for interface in interfaces.iter().filter(|i| i.has_ipv6()) {
+                match socket.join_multicast_v6(&multicast_addr, interface.index) {
+                    Ok(()) => joined += 1,
+                    Err(err) => {
+                        tracing::warn!("Failed to join multicast on {}: {}", interface.name, err)
+                    }
+                }
+            }
+            if joined == 0 {
+                socket
+                    .join_multicast_v6(&multicast_addr, 0)
+                    .wrap_err("failed to join multicast")?;
+            }
+            Ok(socket)
+        }
+    }
+}
+
+/// Receives a packet from whichever socket has one first.
+async fn recv_any(sockets: &[UdpSocket], buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+    poll_fn(|cx| {
+        for socket in sockets {
+            let mut read_buf = ReadBuf::new(buf);
+            if let Poll::Ready(result) = socket.poll_recv_from(cx, &mut read_buf) {
+                return Poll::Ready(result.map(|addr| (read_buf.filled().len(), addr)));
+            }
+        }
+        Poll::Pending
+    })
+    .await
+}
+
+/// The fields that were added after the first version default to empty values, so that older peers are
+/// still listed and can be told apart by their version.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct Packet {
+    #[serde(default)]
+    device_id: String,
+    #[serde(default)]
+    device_name: String,
+    files: Vec<String>,
+    /// The metadata of each of the files, in the same order.
+    #[serde(default)]
+    meta: Vec<FileMeta>,
+    /// The fingerprint of the key that the sender uses for encrypted transfers.
+    #[serde(default)]
+    fingerprint: String,
+    #[serde(default)]
+    version: u16,
+    #[serde(default)]
+    capabilities: Vec<Capability>,
+    /// The [listing_revision] of the files, which are fetched from the peer instead of sent in the packet.
+    /// [None] for peers that send their files in the packet.
+    #[serde(default)]
+    revision: Option<u64>,
+    /// The short public texts, so that receivers have them without fetching them.
+    #[serde(default)]
+    texts: Vec<PacketText>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct PacketText {
+    name: String,
+    text: String,
+}
@@ -0,0 +1,134 @@
+//! This module computes the BLAKE3 hashes that downloads are verified with.
+//!
+//! The hash of a share is the hash of its file for a single file, so that it can be compared with the
+//! output of `b3sum`. For a folder it is the hash of the `b3sum` style lines of all its files, sorted by
+//! their path.
+
+use std::{
+    collections::HashMap,
+    io,
+    path::Path,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use color_eyre::{eyre::WrapErr, Result};
+use tokio::io::{AsyncRead, AsyncReadExt, ReadBuf};
+
+use super::archive;
+use crate::{common::Files, some_or_continue};
+
+const BUF_LEN: usize = 64 * 1024;
+
+/// Hashes the contents of a file.
+pub async fn hash_file(path: &Path) -> io::Result<String> {
+    let mut file = tokio::fs::File::open(path).await?;
+    let mut hasher = blake3::Hasher::new();
+    hash_reader(&mut file, &mut hasher).await?;
+    Ok(hasher.finalize().to_hex().to_string())
+}
+
+/// Adds everything the reader reads to the hash.
+pub async fn hash_reader(
+    reader: &mut (impl AsyncRead + Unpin),
+    hasher: &mut blake3::Hasher,
+) -> io::Result<()> {
+    let mut buf = vec![0; BUF_LEN];
+    loop {
+        let len = reader.read(&mut buf).await?;
+        if len == 0 {
+            return Ok(());
+        }
+        hasher.update(&buf[..len]);
+    }
+}
+
+/// Wraps a reader and adds the bytes that are read through it to a hash, so that a file is hashed while
+/// it is sent.
+pub struct HashingReader<'a, R> {
+    inner: R,
+    hasher: &'a mut blake3::Hasher,
+}
+
+impl<'a, R> HashingReader<'a, R> {
+    pub fn new(inner: R, hasher: &'a mut blake3::Hasher) -> Self {
+        HashingReader { inner, hasher }
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for HashingReader<'_, R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let filled = buf.filled().len();
+        let this = &mut *self;
+        let result = Pin::new(&mut this.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(())) = result {
+            this.hasher.update(&buf.filled()[filled..]);
+        }
+        result
+    }
+}
+
+/// Hashes the files of the entries, keyed by [archive::entry_key].
+pub async fn hash_entries(entries: &[archive::Entry]) -> io::Result<HashMap<String, String>> {
+    let mut hashes = HashMap::new();
+    for entry in entries.iter().filter(|e| !e.is_dir) {
+        hashes.insert(
+            archive::entry_key(&entry.path),
+            hash_file(&entry.source).await?,
+        );
+    }
+    Ok(hashes)
+}
+
+/// The hash of a share with the given file hashes, which is a folder if `is_dir` is set.
+pub fn content_hash(hashes: &HashMap<String, String>, is_dir: bool) -> String {
+    if !is_dir {
+        if let Some(hash) = hashes.values().next() {
+            return hash.clone();
+        }
+    }
+    let mut hashes: Vec<_> = hashes.iter().collect();
+    hashes.sort_unstable();
+    let lines: String = hashes
+        .into_iter()
+        .map(|(key, hash)| format!("{}  {}\n", hash, key))
+        .collect();
+    blake3::hash(lines.as_bytes()).to_hex().to_string()
+}
+
+/// Hashes every local file when it is shared, so that the user can compare the hash with the one the
+/// receiver shows.
+/// If nothing fails, the function will never return.
+pub async fn run_local_file_hashing(files: &Files) -> Result<()> {
+    let mut local_files_rx = files.get_local_files();
+    loop {
+        let local_files = local_files_rx.borrow_and_update().clone();
+        files.retain_local_hashes(|path| local_files.iter().any(|f| f.path() == Some(path)));
+        for local_file in local_files {
+            // Texts are sent in a single frame, without hashes to verify.
+            let path = some_or_continue!(local_file.path());
+            if files.get_local_hash(path).is_some() {
+                continue;
+            }
+            let hash = async {
+                let entries = archive::entries(local_file.name(), path).await?;
+                hash_entries(&entries).await
+            };
+            match hash.await {
+                Ok(hashes) => {
+                    let hash = content_hash(&hashes, local_file.meta().is_dir);
+                    files.set_local_hash(path.to_path_buf(), hash);
+                }
+                Err(err) => tracing::warn!("Failed to hash {}: {}", local_file.name(), err),
+            }
+        }
+        local_files_rx
+            .changed()
+            .await
+            .wrap_err("local files sender closed")?;
+    }
+}
@@ -0,0 +1,104 @@
+//! This module finds the network interfaces that discovery joins and sends on.
+//!
+//! Joining a multicast group without an interface only joins it on the default interface of the OS, so
+//! peers behind other interfaces, like a VPN or a bridge, would never be seen.
+
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+
+use color_eyre::{
+    eyre::{eyre, WrapErr},
+    Result,
+};
+use network_interface::{NetworkInterface, NetworkInterfaceConfig};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Interface {
+    pub name: String,
+    pub index: u32,
+    /// The addresses of the interface, with their netmasks.
+    pub addrs: Vec<(IpAddr, Option<IpAddr>)>,
+}
+
+impl Interface {
+    pub fn ipv4_addrs(&self) -> impl Iterator<Item = Ipv4Addr> + '_ {
+        self.addrs.iter().filter_map(|(ip, _)| match ip {
+            IpAddr::V4(ip) => Some(*ip),
+            IpAddr::V6(_) => None,
+        })
+    }
+
+    pub fn has_ipv6(&self) -> bool {
+        self.addrs.iter().any(|(ip, _)| ip.is_ipv6())
+    }
+
+    /// Whether the address is on the same subnet as one of the addresses of the interface.
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        self.addrs
+            .iter()
+            .any(|(addr, netmask)| match (addr, netmask, ip) {
+                (IpAddr::V4(addr), Some(IpAddr::V4(netmask)), IpAddr::V4(ip)) => {
+                    let netmask = u32::from(*netmask);
+                    u32::from(*addr) & netmask == u32::from(ip) & netmask
+                }
+                (IpAddr::V6(addr), Some(IpAddr::V6(netmask)), IpAddr::V6(ip)) => {
+                    let netmask = u128::from(*netmask);
+                    u128::from(*addr) & netmask == u128::from(ip) & netmask
+                }
+                _ => *addr == ip,
+            })
+    }
+}
+
+/// Lists the interfaces with the given names, or all interfaces that are not loopback if no names are
+/// given.
+pub fn list(names: &[String]) -> Result<Vec<Interface>> {
+    let all: Vec<Interface> = NetworkInterface::show()
+        .wrap_err("failed to list network interfaces")?
+        .into_iter()
+        .map(|interface| Interface {
+            name: interface.name,
+            index: interface.index,
+            addrs: interface
+                .addr
+                .into_iter()
+                .map(|addr| (addr.ip(), addr.netmask()))
+                .collect(),
+        })
+        .collect();
+
+    let mut interfaces = if names.is_empty() {
+        all.into_iter()
+            .filter(|i| !i.addrs.is_empty() && !i.addrs.iter().all(|(ip, _)| ip.is_loopback()))
+            .collect()
+    } else {
+        let mut interfaces = vec![];
+        for name in names {
+            match all.iter().find(|i| i.name == *name) {
+                Some(interface) => interfaces.push(interface.clone()),
+                None => {
+                    let mut available: Vec<&str> = all.iter().map(|i| i.name.as_str()).collect();
+                    available.sort_unstable();
+                    return Err(eyre!(
+                        "no network interface is named {}, the interfaces are {}",
+                        name,
+                        available.join(", ")
+                    ));
+                }
+            }
+        }
+        interfaces
+    };
+    interfaces.sort_by_key(|i| i.index);
+    Ok(interfaces)
+}
+
+/// Finds the interface that packets from the address arrive on.
+pub fn find<'a>(interfaces: &'a [Interface], addr: &SocketAddr) -> Option<&'a Interface> {
+    match addr {
+        // Link-local addresses are only unique together with their interface.
+        SocketAddr::V6(addr) if addr.scope_id() != 0 => {
+            interfaces.iter().find(|i| i.index == addr.scope_id())
+        }
+        _ => interfaces.iter().find(|i| i.contains(addr.ip())),
+    }
+}
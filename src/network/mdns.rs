@@ -0,0 +1,391 @@
+//! This module contains a discovery backend that announces a `_shary._tcp` DNS-SD service with mDNS, so that
+//! devices can be found on networks that block the shary multicast groups and with tools like
+//! `avahi-browse`.
+//!
+//! Every device registers one service named by its device id. The TXT record holds the device name, the key
+//! fingerprint, the protocol version, the capabilities and the [listing_revision] of the shared files. Like
+//! with multicast discovery, browsers fetch the listing over TCP whenever the revision changes.
+//! For peers from before the revision was announced, the TXT record also holds as many files as fit, as
+//! `f<i>` for the name and `m<i>` for the json metadata of the i:th file.
+
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+};
+
+use super::discovery::{Discovery, DiscoveryFuture, LISTING_RETRY_DELAY, LISTING_TIMEOUT};
+use super::interfaces::{self, Interface};
+use super::peers::fetch_listing;
+use super::protocol::{listed_files, listing_revision, Listing, CAPABILITIES, PROTOCOL_VERSION};
+use super::secure::Identity;
+use crate::common::{Device, FileMeta, Files, LocalFile, RemoteDevice, RemoteFile};
+use color_eyre::{
+    eyre::{eyre, WrapErr},
+    Result,
+};
+use mdns_sd::{IfKind, ServiceDaemon, ServiceEvent, ServiceInfo};
+use tokio::{sync::mpsc, time::timeout};
+
+pub const SERVICE_TYPE: &str = "_shary._tcp.local.";
+/// The longest string in a TXT record, including the key and the `=`.
+const MAX_TXT_LEN: usize = 255;
+/// How many bytes of files are put in the TXT record, so that the announcement fits in one packet.
+const MAX_FILES_LEN: usize = 1300;
+
+pub struct MdnsDiscovery {
+    port: u16,
+    /// The names of the interfaces to use, or empty to use all of them.
+    interfaces: Vec<String>,
+    daemon: ServiceDaemon,
+}
+
+impl MdnsDiscovery {
+    pub fn new(port: u16, interfaces: Vec<String>) -> Result<MdnsDiscovery> {
+        let daemon = ServiceDaemon::new().wrap_err("failed to start mDNS daemon")?;
+        if !interfaces.is_empty() {
+            daemon
+                .disable_interface(IfKind::All)
+                .wrap_err("failed to disable mDNS interfaces")?;
+            let kinds: Vec<IfKind> = interfaces.iter().cloned().map(IfKind::Name).collect();
+            daemon
+                .enable_interface(kinds)
+                .wrap_err("failed to enable mDNS interfaces")?;
+        }
+        Ok(MdnsDiscovery {
+            port,
+            interfaces,
+            daemon,
+        })
+    }
+}
+
+impl Discovery for MdnsDiscovery {
+    fn advertise<'a>(
+        &'a self,
+        files: &'a Files,
+        identity: &'a Arc<Identity>,
+    ) -> DiscoveryFuture<'a> {
+        Box::pin(async move {
+            let fingerprint = identity.fingerprint();
+            let mut files_rx = files.get_local_files();
+            let mut device_rx = files.get_device();
+            loop {
+                let local_files = files_rx.borrow_and_update().clone();
+                let device = device_rx.borrow_and_update().clone();
+                let properties = txt_properties(&device, &local_files, &fingerprint);
+                let host_name = format!("{}.local.", device.id);
+                // Registering the same name again replaces the announced record.
+                let info = ServiceInfo::new(
+                    SERVICE_TYPE,
+                    &device.id,
+                    &host_name,
+                    (),
+                    self.port,
+                    properties,
+                )
+                .wrap_err("failed to create mDNS service")?
+                .enable_addr_auto();
+                let fullname = info.get_fullname().to_string();
+                tracing::debug!("Registering mDNS service {}", fullname);
+                self.daemon
+                    .register(info)
+                    .wrap_err("failed to register mDNS service")?;
+
+                let changed = tokio::select! {
+                    result = files_rx.changed() => result,
+                    result = device_rx.changed() => result,
+                };
+                if changed.is_err() {
+                    // A sender is closed and we can stop
+                    if let Err(err) = self.daemon.unregister(&fullname) {
+                        tracing::warn!("Failed to unregister mDNS service: {}", err);
+                    }
+                    return Ok(());
+                }
+            }
+        })
+    }
+
+    fn browse<'a>(&'a self, files: &'a Files, identity: &'a Arc<Identity>) -> DiscoveryFuture<'a> {
+        Box::pin(async move {
+            let events = self
+                .daemon
+                .browse(SERVICE_TYPE)
+                .wrap_err("failed to browse mDNS services")?;
+            let interfaces = interfaces::list(&self.interfaces)?;
+            let device_id = files.get_device().borrow().id.clone();
+            let mut services: HashMap<String, Service> = HashMap::new();
+            let (listings_tx, mut listings_rx) = mpsc::unbounded_channel();
+            loop {
+                let mut changed = false;
+                tokio::select! {
+                    event = events.recv_async() => match event.map_err(|_| eyre!("the mDNS daemon stopped"))? {
+                        ServiceEvent::ServiceResolved(info) => {
+                            tracing::debug!("Resolved mDNS service {}", info.get_fullname());
+                            if info.get_property_val_str("id") != Some(device_id.as_str()) {
+                                let fullname = info.get_fullname().to_string();
+                                let listing = services.remove(&fullname).and_then(|s| s.listing);
+                                services.insert(fullname, Service {
+                                    info,
+                                    listing,
+                                    fetching: false,
+                                });
+                                changed = true;
+                            }
+                        }
+                        ServiceEvent::ServiceRemoved(_, fullname) => changed = services.remove(&fullname).is_some(),
+                        _ => {}
+                    },
+                    Some((fullname, result)) = listings_rx.recv() => {
+                        if let Some(service) = services.get_mut(&fullname) {
+                            service.fetching = false;
+                            match result {
+                                Ok(listing) => {
+                                    let listing: Listing = listing;
+                                    service.listing = Some(listing);
+                                    changed = true;
+                                }
+                                Err(err) => tracing::warn!("Failed to fetch the files of {}: {:?}", fullname, err),
+                            }
+                        }
+                    }
+                }
+
+                for (fullname, service) in services
+                    .iter_mut()
+                    .filter(|(_, service)| service.needs_listing())
+                {
+                    service.fetching = true;
+                    let fullname = fullname.clone();
+                    let addrs = service_addrs(&service.info);
+                    let fingerprint = service
+                        .info
+                        .get_property_val_str("fp")
+                        .unwrap_or_default()
+                        .to_string();
+                    let identity = Arc::clone(identity);
+                    let listings_tx = listings_tx.clone();
+                    tokio::spawn(async move {
+                        let result = match timeout(
+                            LISTING_TIMEOUT,
+                            fetch_listing(&identity, &addrs),
+                        )
+                        .await
+                        {
+                            Ok(Ok((_, remote_fingerprint, _)))
+                                if remote_fingerprint != fingerprint =>
+                            {
+                                Err(eyre!("peer key does not match the advertised fingerprint"))
+                            }
+                            Ok(result) => result.map(|(_, _, listing)| listing),
+                            Err(_) => Err(eyre!("the peer did not answer in time")),
+                        };
+                        // A listing that failed is fetched again once the result is back, so wait before
+                        // sending it.
+                        if result.is_err() {
+                            tokio::time::sleep(LISTING_RETRY_DELAY).await;
+                        }
+                        let _ = listings_tx.send((fullname, result));
+                    });
+                }
+
+                if changed {
+                    let devices = services
+                        .values()
+                        .filter_map(|s| service_device(&s.info))
+                        .collect();
+                    files.discovered_devices_tx.send_replace(Arc::new(devices));
+                    let remote_files = services
+                        .values()
+                        .flat_map(|service| service.remote_files(&interfaces))
+                        .collect();
+                    if files
+                        .discovered_files_tx
+                        .send(Arc::new(remote_files))
+                        .is_err()
+                    {
+                        if let Err(err) = self.daemon.stop_browse(SERVICE_TYPE) {
+                            tracing::warn!("Failed to stop browsing mDNS services: {}", err);
+                        }
+                        return Ok(());
+                    }
+                }
+            }
+        })
+    }
+}
+
+/// What the browser knows about a resolved service.
+struct Service {
+    info: ServiceInfo,
+    /// The last listing fetched from the device.
+    listing: Option<Listing>,
+    /// Whether a listing is being fetched.
+    fetching: bool,
+}
+
+impl Service {
+    /// Whether the service announces a revision that has not been fetched yet.
+    fn needs_listing(&self) -> bool {
+        let outdated = match revision(&self.info) {
+            Some(revision) => self.listing.as_ref().map(|l| l.revision) != Some(revision),
+            None => false,
+        };
+        outdated && !self.fetching
+    }
+
+    /// The files from the listing or, for peers that do not announce a revision, from the TXT record.
+    fn remote_files(&self, interfaces: &[Interface]) -> Vec<RemoteFile> {
+        match (revision(&self.info), &self.listing) {
+            (Some(_), Some(listing)) => {
+                let files = listing
+                    .files
+                    .iter()
+                    .map(|f| (f.name.clone(), Some(f.meta.clone())));
+                service_files(&self.info, interfaces, files.collect())
+            }
+            (Some(_), None) => vec![],
+            (None, _) => remote_files(&self.info, interfaces),
+        }
+    }
+}
+
+/// The TXT record of the service. Browsers fetch the files by the revision, the files that are put in the
+/// record for older peers are left out when they do not fit.
+pub fn txt_properties(
+    device: &Device,
+    local_files: &[LocalFile],
+    fingerprint: &str,
+) -> HashMap<String, String> {
+    let mut properties = HashMap::new();
+    let revision = listing_revision(&listed_files(local_files));
+    properties.insert(String::from("rev"), revision.to_string());
+    properties.insert(String::from("id"), device.id.clone());
+    properties.insert(String::from("name"), device.name.clone());
+    properties.insert(String::from("fp"), fingerprint.to_string());
+    properties.insert(String::from("v"), PROTOCOL_VERSION.to_string());
+    properties.insert(
+        String::from("caps"),
+        serde_json::to_string(CAPABILITIES).unwrap_or_default(),
+    );
+
+    let mut len = 0;
+    let mut i = 0;
+    for local_file in local_files {
+        let name = (format!("f{}", i), local_file.name().to_owned());
+        let meta = (
+            format!("m{}", i),
+            serde_json::to_string(local_file.meta()).unwrap_or_default(),
+        );
+        let name_len = name.0.len() + name.1.len() + 1;
+        let meta_len = meta.0.len() + meta.1.len() + 1;
+        if name_len > MAX_TXT_LEN || meta_len > MAX_TXT_LEN {
+            tracing::warn!("{} has too long a name for mDNS", local_file.name());
+            continue;
+        }
+        if len + name_len + meta_len > MAX_FILES_LEN {
+            tracing::debug!(
+                "Only {} of {} files fit in the mDNS record",
+                i,
+                local_files.len()
+            );
+            break;
+        }
+        len += name_len + meta_len;
+        properties.extend([name, meta]);
+        i += 1;
+    }
+    properties
+}
+
+/// The [listing_revision] a resolved service announces, or [None] for peers that only put their files in
+/// the TXT record.
+pub fn revision(info: &ServiceInfo) -> Option<u64> {
+    info.get_property_val_str("rev")?.parse().ok()
+}
+
+/// The files in the TXT record of a resolved service, which is reached on the addresses of the service.
+pub fn remote_files(info: &ServiceInfo, interfaces: &[Interface]) -> Vec<RemoteFile> {
+    let files = (0..)
+        .map_while(|i| {
+            info.get_property_val_str(&format!("f{}", i))
+                .map(|file| (i, file))
+        })
+        .map(|(i, file)| {
+            let meta = info
+                .get_property_val_str(&format!("m{}", i))
+                .and_then(|meta| serde_json::from_str::<FileMeta>(meta).ok());
+            (file.to_string(), meta)
+        });
+    service_files(info, interfaces, files.collect())
+}
+
+/// The addresses of a resolved service.
+fn service_addrs(info: &ServiceInfo) -> Vec<SocketAddr> {
+    let mut ips: Vec<IpAddr> = info.get_addresses().iter().copied().collect();
+    // IPv4 first, since link-local IPv6 addresses can not be connected to without a scope.
+    ips.sort_by_key(|ip| (ip.is_ipv6(), *ip));
+    ips.into_iter()
+        .map(|ip| SocketAddr::new(ip, info.get_port()))
+        .collect()
+}
+
+/// The device of a resolved service, or [None] if the service has no address.
+fn service_device(info: &ServiceInfo) -> Option<RemoteDevice> {
+    let addrs = service_addrs(info);
+    let addr = *addrs.first()?;
+    let property = |key: &str| {
+        info.get_property_val_str(key)
+            .unwrap_or_default()
+            .to_string()
+    };
+    let device_id = match property("id") {
+        id if id.is_empty() => addr.to_string(),
+        id => id,
+    };
+    let device_name = match property("name") {
+        name if name.is_empty() => addr.ip().to_string(),
+        name => name,
+    };
+    Some(RemoteDevice {
+        addrs,
+        device_id,
+        device_name,
+        fingerprint: property("fp"),
+        capabilities: serde_json::from_str(&property("caps")).unwrap_or_default(),
+    })
+}
+
+/// The given files with their metadata as files of the device of a resolved service.
+fn service_files(
+    info: &ServiceInfo,
+    interfaces: &[Interface],
+    files: Vec<(String, Option<FileMeta>)>,
+) -> Vec<RemoteFile> {
+    let device = match service_device(info) {
+        Some(device) => device,
+        None => return vec![],
+    };
+    let version = info
+        .get_property_val_str("v")
+        .and_then(|v| v.parse().ok())
+        .unwrap_or_default();
+    let interface = interfaces::find(interfaces, &device.addrs[0]).map(|i| i.name.clone());
+
+    files
+        .into_iter()
+        .map(|(file, meta)| RemoteFile {
+            addrs: device.addrs.clone(),
+            device_id: device.device_id.clone(),
+            device_name: device.device_name.clone(),
+            file,
+            fingerprint: device.fingerprint.clone(),
+            version,
+            capabilities: device.capabilities.clone(),
+            meta,
+            interface: interface.clone(),
+            text: None,
+        })
+        .collect()
+}
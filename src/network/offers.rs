@@ -0,0 +1,349 @@
+//! This module contains pushing files to peers, which receive them only if their users accept them.
+//!
+//! A file is offered with a [Request::Offer]. Once the user of the peer accepts it, it is sent over the
+//! same session as the file server sends a download, and the peer unpacks it as a download. Offers that
+//! match one of the user's [crate::common::AcceptRule]s are accepted without asking.
+
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, Instant, SystemTime},
+};
+
+use color_eyre::{
+    eyre::{eyre, WrapErr},
+    Result,
+};
+
+use tokio::sync::Semaphore;
+
+use super::{
+    archive, hashing,
+    protocol::{self, DownloadRequest, ErrorResponse, OfferRequest, Request, Response},
+    secure::{self, Identity, SecureStream},
+    server::{self, ResumeState},
+};
+use crate::common::{
+    ConflictPolicy, DownloadControl, FileMeta, Files, Offer, OfferStatus, OutgoingOffer, Progress,
+    Transfer, TransferOutcome,
+};
+
+/// How long an offer waits for the user to accept or decline it.
+const DECISION_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// Offers the files the user asks to send, each in its own task.
+/// If nothing fails, the function will never return.
+pub async fn run_outgoing_offers(files: Arc<Files>, identity: Arc<Identity>) -> Result<()> {
+    loop {
+        let offer = files
+            .next_outgoing_offer()
+            .await
+            .ok_or_else(|| eyre!("offer channel sender closed"))?;
+        let files = Arc::clone(&files);
+        let identity = Arc::clone(&identity);
+        tokio::spawn(async move {
+            send_offer(&files, &identity, offer).await;
+        });
+    }
+}
+
+/// Offers a file to a peer, sends it if the peer accepts it, and reports the status of the offer.
+async fn send_offer(files: &Files, identity: &Identity, offer: OutgoingOffer) {
+    let id = files.add_offer(Offer {
+        id: 0,
+        peer: offer.peer.clone(),
+        fingerprint: offer.fingerprint.clone().unwrap_or_default(),
+        file: offer.local_file.name().to_owned(),
+        meta: offer.local_file.meta().clone(),
+        outgoing: true,
+        status: OfferStatus::Pending,
+    });
+    let status = match offer_file(files, identity, id, &offer).await {
+        Ok(()) => OfferStatus::Completed(
+            offer
+                .local_file
+                .path()
+                .and_then(|path| files.get_local_hash(path)),
+        ),
+        Err(report) => match report.downcast_ref::<ErrorResponse>() {
+            Some(ErrorResponse::Declined) => OfferStatus::Declined,
+            _ => {
+                tracing::error!("Offering {} failed: {:?}", offer.local_file.name(), report);
+                OfferStatus::Failed(report.to_string())
+            }
+        },
+    };
+    files.update_offer(id, |o| o.status = status);
+}
+
+async fn offer_file(
+    files: &Files,
+    identity: &Identity,
+    id: u64,
+    offer: &OutgoingOffer,
+) -> Result<()> {
+    let path = offer
+        .local_file
+        .path()
+        .ok_or_else(|| eyre!("only files and folders can be sent"))?;
+    let stream = super::connect(&offer.addrs).await?;
+    let mut stream = secure::connect(stream, identity)
+        .await
+        .wrap_err("failed to establish encrypted session")?;
+    let fingerprint = stream.remote_fingerprint().to_owned();
+    if offer
+        .fingerprint
+        .as_ref()
+        .is_some_and(|f| *f != fingerprint)
+    {
+        return Err(eyre!("peer key does not match the advertised fingerprint"));
+    }
+    files.update_offer(id, |o| o.fingerprint = fingerprint);
+    let request = Request::Offer(OfferRequest {
+        device: files.get_device().borrow().clone(),
+        file: offer.local_file.name().to_owned(),
+        meta: offer.local_file.meta().clone(),
+    });
+    protocol::write_request(&mut stream, request)
+        .await
+        .wrap_err("failed to write offer")?;
+    match protocol::read_response(&mut stream).await? {
+        Response::Offer => {}
+        response => return Err(eyre!("unexpected response: {:?}", response)),
+    }
+    files.update_offer(id, |o| o.status = OfferStatus::Transferring(None));
+    let request = DownloadRequest {
+        file: offer.local_file.name().to_owned(),
+        delivered: HashMap::new(),
+        password: None,
+    };
+    // Every peer that receives offers verifies them with hashes.
+    server::send_file(stream, request, path, true).await
+}
+
+/// Handles a file that a peer offers on an established session.
+/// Once the user accepts it, the offer waits in the download queue for a permit of `downloads` and can be
+/// paused and cancelled like a download.
+pub async fn receive_offer(
+    stream: SecureStream,
+    files: &Files,
+    downloads: &Semaphore,
+    request: OfferRequest,
+) -> Result<()> {
+    let OfferRequest { device, file, meta } = request;
+    let fingerprint = stream.remote_fingerprint().to_owned();
+    let id = match files.add_incoming_offer(Offer {
+        id: 0,
+        peer: device.name.clone(),
+        fingerprint: fingerprint.clone(),
+        file: file.clone(),
+        meta: meta.clone(),
+        outgoing: false,
+        status: OfferStatus::Pending,
+    }) {
+        Some(id) => id,
+        None => {
+            tracing::info!(
+                "Too many offers are pending, refusing {} from {}",
+                file,
+                device.name
+            );
+            return server::respond_error(stream, ErrorResponse::Busy).await;
+        }
+    };
+    if let Some((folder, rule)) = files.auto_accept(&fingerprint, &meta) {
+        tracing::info!(
+            "Accepting {} from {} into {}",
+            file,
+            device.name,
+            folder.display()
+        );
+        if let Err(err) = tokio::fs::create_dir_all(&folder).await {
+            tracing::warn!("Failed to create {}: {}", folder.display(), err);
+        }
+        files.decide_offer(id, Some((folder, rule.conflict)));
+    }
+    let (path, conflict) =
+        match tokio::time::timeout(DECISION_TIMEOUT, wait_for_decision(files, id)).await {
+            Ok(Some(accepted)) => accepted,
+            _ => {
+                files.remove_offer(id);
+                return server::respond_error(stream, ErrorResponse::Declined).await;
+            }
+        };
+    let mut resume_state = ResumeState::new(conflict);
+    // Every offer gets a staging folder of its own, what is left from a failed one is not used.
+    let staging_id = random_string::generate(16, "abcdefghijklmnopqrstuvwxyz0123456789");
+    // None when the user cancelled the offer.
+    let (result, stored, duration) = match resume_state.target(&path, &file, &staging_id).await {
+        Ok(target) => {
+            files.update_offer(id, |o| o.status = OfferStatus::Queued);
+            let (result, duration) = receive_accepted(
+                stream,
+                files,
+                id,
+                downloads,
+                &target,
+                &mut resume_state,
+                &meta,
+            )
+            .await;
+            (result, target.dst.join(&target.root), duration)
+        }
+        Err(err) => {
+            if let Err(err) = server::respond_error(stream, ErrorResponse::BadRequest).await {
+                tracing::warn!("Failed to refuse {}: {:?}", file, err);
+            }
+            (Some(Err(err)), path, Duration::ZERO)
+        }
+    };
+    files.set_offer_control(id, None);
+    if !matches!(result, Some(Ok(_))) {
+        resume_state.discard().await;
+    }
+    let (status, outcome) = match &result {
+        Some(Ok(hash)) => (
+            OfferStatus::Completed(hash.clone()),
+            TransferOutcome::Completed,
+        ),
+        Some(Err(report)) => (
+            OfferStatus::Failed(report.to_string()),
+            TransferOutcome::Failed(report.to_string()),
+        ),
+        None => (OfferStatus::Cancelled, TransferOutcome::Cancelled),
+    };
+    files.add_transfer(Transfer {
+        peer: device.name,
+        fingerprint,
+        file,
+        path: stored,
+        size: Some(meta.size),
+        finished: SystemTime::now(),
+        duration,
+        outcome,
+        hash: match &result {
+            Some(Ok(hash)) => hash.clone(),
+            _ => None,
+        },
+    });
+    files.update_offer(id, |o| o.status = status);
+    match result {
+        Some(result) => result.map(|_| ()),
+        None => Ok(()),
+    }
+}
+
+/// Waits in the download queue, then tells the peer to send the accepted offer and receives it into the
+/// target, stopping to read while the user pauses it. Returns the result, which is [None] if the user
+/// cancelled it, and how long the offer was received for.
+async fn receive_accepted(
+    mut stream: SecureStream,
+    files: &Files,
+    id: u64,
+    downloads: &Semaphore,
+    target: &archive::Target,
+    resume_state: &mut ResumeState,
+    meta: &FileMeta,
+) -> (Option<Result<Option<String>>>, Duration) {
+    // The size the offer announced is what the user or the rule accepted, so the peer may not send more.
+    let limit = archive::Limit {
+        size: meta.size,
+        file_count: meta.file_count,
+    };
+    let mut duration = Duration::ZERO;
+    let mut controls = files.get_offer_controls();
+    let is_cancel = |c| c == Some(DownloadControl::Cancel);
+    let mut permit = tokio::select! {
+        permit = downloads.acquire() => permit.ok(),
+        _ = server::wait_for_control(&mut controls, &id, is_cancel) => {
+            // The peer still waits for the offer to be accepted.
+            if let Err(err) = server::respond_error(stream, ErrorResponse::Declined).await {
+                tracing::warn!("Failed to decline the offer: {:?}", err);
+            }
+            return (None, duration);
+        }
+    };
+    let receive = async move {
+        protocol::write_frame(&mut stream, &Response::Offer)
+            .await
+            .wrap_err("failed to accept offer")?;
+        let header = match protocol::read_response(&mut stream).await? {
+            Response::Download(header) => header,
+            response => return Err(eyre!("unexpected response: {:?}", response)),
+        };
+        let on_progress = |progress| {
+            files.update_offer(id, |o| o.status = OfferStatus::Transferring(Some(progress)))
+        };
+        on_progress(Progress::default());
+        let hashes = server::receive(
+            stream,
+            header,
+            target,
+            resume_state,
+            true,
+            Some(limit),
+            on_progress,
+        )
+        .await?;
+        Ok(hashes.map(|hashes| hashing::content_hash(&hashes, meta.is_dir)))
+    };
+    tokio::pin!(receive);
+    loop {
+        let started = Instant::now();
+        let control = tokio::select! {
+            result = &mut receive => {
+                duration += started.elapsed();
+                return (Some(result), duration);
+            }
+            control = server::wait_for_control(&mut controls, &id, |c| c.is_some()) => control,
+        };
+        duration += started.elapsed();
+        drop(permit.take());
+        if control != Some(DownloadControl::Pause) {
+            return (None, duration);
+        }
+        // The peer waits while nothing is read from the session.
+        let progress = match files.get_offers().borrow().iter().find(|o| o.id == id) {
+            Some(Offer {
+                status: OfferStatus::Transferring(Some(progress)),
+                ..
+            }) => progress.clone(),
+            _ => Progress::default(),
+        };
+        files.update_offer(id, |o| o.status = OfferStatus::Paused(progress.clone()));
+        let control =
+            server::wait_for_control(&mut controls, &id, |c| c != Some(DownloadControl::Pause))
+                .await;
+        if control.is_some() {
+            return (None, duration);
+        }
+        files.update_offer(id, |o| o.status = OfferStatus::Queued);
+        permit = tokio::select! {
+            permit = downloads.acquire() => permit.ok(),
+            _ = server::wait_for_control(&mut controls, &id, is_cancel) => return (None, duration),
+        };
+        files.update_offer(id, |o| o.status = OfferStatus::Transferring(Some(progress)));
+    }
+}
+
+/// Waits until the user accepts or declines the offer, and returns the folder and what to do with existing
+/// files if it was accepted.
+async fn wait_for_decision(files: &Files, id: u64) -> Option<(PathBuf, ConflictPolicy)> {
+    let mut offers = files.get_offers();
+    loop {
+        let status = offers
+            .borrow_and_update()
+            .iter()
+            .find(|o| o.id == id)
+            .map(|o| o.status.clone());
+        match status {
+            Some(OfferStatus::Pending) => {}
+            Some(OfferStatus::Accepted(path, conflict)) => return Some((path, conflict)),
+            _ => return None,
+        }
+        if offers.changed().await.is_err() {
+            return None;
+        }
+    }
+}
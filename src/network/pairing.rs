@@ -0,0 +1,151 @@
+//! This module contains the pairing flow, in which the users of two devices confirm that both show the
+//! same short code before the devices trust each other's keys.
+//!
+//! The code is derived from the hash of the encrypted session's handshake, so a device in the middle
+//! would end up with different codes on the two sides.
+
+use std::{net::SocketAddr, time::Duration};
+
+use super::{
+    protocol::{self, ErrorResponse, Request, Response},
+    secure::{self, Identity, SecureStream},
+};
+use crate::common::{Files, PairedPeer, Pairing, PairingStatus};
+use color_eyre::{
+    eyre::{eyre, WrapErr},
+    Result,
+};
+
+/// How long to wait for the users to confirm the code.
+const CONFIRM_TIMEOUT: Duration = Duration::from_secs(120);
+/// How long the pairing requests of a peer are refused after one of them failed, so that a peer cannot
+/// keep the user from pairing with anyone else by asking again and again.
+const REFUSE_DURATION: Duration = Duration::from_secs(600);
+
+/// Handles the pairings the user asks for.
+/// If nothing fails, the function will never return.
+pub async fn run_pairing_requests(files: &Files, identity: &Identity) -> Result<()> {
+    let mut requests = files.get_pairing_requests();
+    loop {
+        let (addrs, fingerprint) = requests
+            .recv()
+            .await
+            .wrap_err("pairing channel sender closed")?;
+        let pairing = Pairing {
+            fingerprint: fingerprint.clone(),
+            code: None,
+            status: PairingStatus::Connecting,
+        };
+        if !files.start_pairing(pairing) {
+            tracing::warn!("Ignoring pairing request while another pairing is in progress.");
+            continue;
+        }
+        if let Err(err) = request_pairing(files, identity, &addrs, &fingerprint).await {
+            tracing::error!("Pairing with {:?} failed: {:?}", addrs, err);
+            files.update_pairing(|p| p.status = PairingStatus::Failed(err.to_string()));
+        }
+    }
+}
+
+async fn request_pairing(
+    files: &Files,
+    identity: &Identity,
+    addrs: &[SocketAddr],
+    fingerprint: &str,
+) -> Result<()> {
+    let stream = super::connect(addrs).await?;
+    let mut stream = secure::connect(stream, identity)
+        .await
+        .wrap_err("failed to establish encrypted session")?;
+    if stream.remote_fingerprint() != fingerprint {
+        return Err(eyre!("peer key does not match the advertised fingerprint"));
+    }
+    protocol::write_request(&mut stream, Request::Pair)
+        .await
+        .wrap_err("failed to write pairing request")?;
+    match protocol::read_response(&mut stream).await? {
+        Response::Pair => confirm(&mut stream, files).await,
+        response => Err(eyre!("unexpected response: {:?}", response)),
+    }
+}
+
+/// Handles a pairing that a peer asked for on an established session.
+pub async fn accept_pairing(mut stream: SecureStream, files: &Files) -> Result<()> {
+    let fingerprint = stream.remote_fingerprint().to_owned();
+    let pairing = Pairing {
+        fingerprint: fingerprint.clone(),
+        code: None,
+        status: PairingStatus::Connecting,
+    };
+    let refused = files.is_pairing_refused(&fingerprint);
+    let response = if !refused && files.start_pairing(pairing) {
+        Response::Pair
+    } else {
+        Response::Error(ErrorResponse::Busy)
+    };
+    protocol::write_frame(&mut stream, &response)
+        .await
+        .wrap_err("failed to write pairing response")?;
+    if refused {
+        return Err(eyre!("a pairing with this peer failed recently"));
+    }
+    if let Response::Error(_) = response {
+        return Err(eyre!("another pairing is in progress"));
+    }
+    let result = confirm(&mut stream, files).await;
+    if let Err(err) = &result {
+        files.refuse_pairing(fingerprint, REFUSE_DURATION);
+        files.update_pairing(|p| p.status = PairingStatus::Failed(err.to_string()));
+    }
+    result
+}
+
+/// Shows the code, exchanges the decisions of both users and stores the peer if both confirmed.
+async fn confirm(stream: &mut SecureStream, files: &Files) -> Result<()> {
+    let fingerprint = stream.remote_fingerprint().to_owned();
+    let code = pairing_code(stream.handshake_hash());
+    let mut decision = files.get_pairing_decision();
+    files.update_pairing(|p| {
+        p.code = Some(code);
+        p.status = PairingStatus::Confirming;
+    });
+    let accepted = tokio::time::timeout(CONFIRM_TIMEOUT, async {
+        loop {
+            if let Some(accepted) = *decision.borrow_and_update() {
+                return accepted;
+            }
+            if decision.changed().await.is_err() {
+                return false;
+            }
+        }
+    })
+    .await
+    .unwrap_or(false);
+    protocol::write_frame(stream, &accepted)
+        .await
+        .wrap_err("failed to send pairing decision")?;
+    if !accepted {
+        return Err(eyre!("pairing was cancelled"));
+    }
+    files.update_pairing(|p| p.status = PairingStatus::Waiting);
+    let peer_accepted: bool = tokio::time::timeout(CONFIRM_TIMEOUT, protocol::read_frame(stream))
+        .await
+        .map_err(|_| eyre!("the other device did not confirm in time"))?
+        .wrap_err("failed to read pairing decision")?;
+    if !peer_accepted {
+        return Err(eyre!("the other device declined the pairing"));
+    }
+    // Remembered now, since the name a peer sends with its requests could be any name.
+    let name = files.device_name(&fingerprint).unwrap_or_default();
+    files.add_paired_peer(PairedPeer { fingerprint, name });
+    files.update_pairing(|p| p.status = PairingStatus::Paired);
+    Ok(())
+}
+
+/// Returns the six digit code that both sides of a session show, e.g. `123 456`.
+pub fn pairing_code(handshake_hash: &[u8]) -> String {
+    let hash = blake3::hash(handshake_hash);
+    let bytes = hash.as_bytes();
+    let number = u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) % 1_000_000;
+    format!("{:03} {:03}", number / 1000, number % 1000)
+}
@@ -0,0 +1,171 @@
+//! This module contains the polling of peers that were added by address, for peers that discovery does not
+//! reach, like peers on other subnets or behind a VPN.
+//!
+//! Every peer is asked for its files over an encrypted session, and the results are combined with the
+//! discovered files.
+
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+
+use color_eyre::{
+    eyre::{eyre, WrapErr},
+    Result,
+};
+use tokio::{net::lookup_host, time::timeout};
+
+use super::{
+    protocol::{self, Listing, Request, Response, PROTOCOL_VERSION},
+    secure::{self, Identity},
+};
+use crate::common::{Files, RemoteFile};
+
+/// How often the peers are asked for their files.
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+/// How long a peer may take to answer before it is left out until the next poll.
+const POLL_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Polls the given peers and the manual peers of `files`, which use `default_port` if they have no port.
+/// If nothing fails, the function will never return.
+/// If the connected receiver is dropped, this function will return [Ok(())].
+pub async fn run_peer_polling(
+    files: &Files,
+    identity: &Identity,
+    peers: &[String],
+    default_port: u16,
+) -> Result<()> {
+    let mut manual_peers = files.get_manual_peers();
+    let device_id = files.get_device().borrow().id.clone();
+    loop {
+        let mut all_peers = peers.to_vec();
+        for peer in manual_peers.borrow_and_update().iter() {
+            if !all_peers.contains(peer) {
+                all_peers.push(peer.clone());
+            }
+        }
+
+        let mut remote_files = vec![];
+        let mut errors = HashMap::new();
+        for peer in all_peers {
+            let result = match timeout(POLL_TIMEOUT, list(identity, &peer, default_port)).await {
+                Ok(result) => result,
+                Err(_) => Err(eyre!("the peer did not answer in time")),
+            };
+            match result {
+                // A peer that is this device, for example through localhost.
+                Ok(files) if files.iter().any(|f| f.device_id == device_id) => {}
+                Ok(files) => remote_files.extend(files),
+                Err(err) => {
+                    tracing::debug!("Failed to poll {}: {:?}", peer, err);
+                    errors.insert(peer, err.to_string());
+                }
+            }
+        }
+        files.set_peer_errors(errors);
+        if files.polled_files_tx.send(Arc::new(remote_files)).is_err() {
+            return Ok(());
+        }
+
+        tokio::select! {
+            _ = tokio::time::sleep(POLL_INTERVAL) => {}
+            result = manual_peers.changed() => result.wrap_err("manual peers sender closed")?,
+        }
+    }
+}
+
+/// Keeps the remote files up to date with the discovered and the polled files.
+/// If nothing fails, the function will never return.
+pub async fn run_remote_files_merge(files: &Files) -> Result<()> {
+    let mut discovered = files.discovered_files_tx.subscribe();
+    let mut polled = files.polled_files_tx.subscribe();
+    loop {
+        files.merge_remote_files();
+        tokio::select! {
+            result = discovered.changed() => result.wrap_err("discovered files sender closed")?,
+            result = polled.changed() => result.wrap_err("polled files sender closed")?,
+        }
+    }
+}
+
+/// Asks a peer for the files it shares.
+pub async fn list(identity: &Identity, peer: &str, default_port: u16) -> Result<Vec<RemoteFile>> {
+    let addrs = resolve(peer, default_port).await?;
+    let (addr, fingerprint, listing) = fetch_listing(identity, &addrs).await?;
+    let Listing {
+        device,
+        files,
+        capabilities,
+        ..
+    } = listing;
+    Ok(files
+        .into_iter()
+        .map(|file| RemoteFile {
+            addrs: vec![addr],
+            device_id: device.id.clone(),
+            device_name: device.name.clone(),
+            file: file.name,
+            fingerprint: fingerprint.clone(),
+            version: PROTOCOL_VERSION,
+            capabilities: capabilities.clone(),
+            meta: Some(file.meta),
+            interface: None,
+            text: None,
+        })
+        .collect())
+}
+
+/// Asks the peer at the first of the addresses that can be reached for its listing.
+/// Returns the address, the key fingerprint of the peer and the listing.
+pub async fn fetch_listing(
+    identity: &Identity,
+    addrs: &[SocketAddr],
+) -> Result<(SocketAddr, String, Listing)> {
+    let stream = super::connect(addrs).await?;
+    let addr = stream.peer_addr().wrap_err("failed to get peer address")?;
+    let mut stream = secure::connect(stream, identity)
+        .await
+        .wrap_err("failed to establish encrypted session")?;
+    protocol::write_request(&mut stream, Request::List)
+        .await
+        .wrap_err("failed to write list request")?;
+    match protocol::read_response(&mut stream).await? {
+        Response::List(header) => {
+            let listing = protocol::read_listing(&mut stream, header).await?;
+            Ok((addr, stream.remote_fingerprint().to_owned(), listing))
+        }
+        response => Err(eyre!("unexpected response: {:?}", response)),
+    }
+}
+
+/// Resolves `host`, `host:port`, `ip`, `ip:port` or `[ipv6]:port`, using `default_port` if there is no port.
+pub async fn resolve(peer: &str, default_port: u16) -> Result<Vec<SocketAddr>> {
+    if let Ok(addr) = peer.parse::<SocketAddr>() {
+        return Ok(vec![addr]);
+    }
+    if let Ok(ip) = peer
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .parse::<IpAddr>()
+    {
+        return Ok(vec![SocketAddr::new(ip, default_port)]);
+    }
+    let addrs = match peer.rsplit_once(':') {
+        Some((host, port)) => {
+            let port: u16 = port
+                .parse()
+                .wrap_err_with(|| format!("invalid port in {}", peer))?;
+            lookup_host((host, port)).await
+        }
+        None => lookup_host((peer, default_port)).await,
+    };
+    let addrs: Vec<SocketAddr> = addrs
+        .wrap_err_with(|| format!("failed to resolve {}", peer))?
+        .collect();
+    if addrs.is_empty() {
+        return Err(eyre!("{} has no addresses", peer));
+    }
+    Ok(addrs)
+}
@@ -0,0 +1,395 @@
+//! This module contains the messages that are exchanged over an encrypted session.
+//!
+//! Every message is sent as a frame, a big endian `u32` length followed by the message as json.
+//! A client starts by sending a [ClientRequest], and the server answers with a [Response] before anything
+//! else is sent, so that a refused request is reported to the client instead of just closing the
+//! connection.
+//!
+//! A client that offers a file with [Request::Offer] sends it in reverse once the server accepts it: the
+//! client writes the [Response::Download] header, the tar stream and the trailer, as the file server does
+//! for a download.
+//!
+//! A shared text is sent in the [Response::Text] frame itself, short public texts also come with discovery.
+
+use std::{collections::HashMap, fmt};
+
+use color_eyre::{
+    eyre::{eyre, WrapErr},
+    Report, Result,
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::common::{Capability, Device, FileMeta, LocalFile, Refusal};
+
+/// The version of the protocol, which is increased whenever a change breaks older peers.
+pub const PROTOCOL_VERSION: u16 = 2;
+/// The optional features this version supports, advertised in discovery and in requests.
+pub const CAPABILITIES: &[Capability] = &[
+    Capability::Resume,
+    Capability::Pair,
+    Capability::Hash,
+    Capability::Offer,
+];
+/// The largest frame that is accepted, so that a broken peer can not make us allocate without bounds.
+const MAX_FRAME_LEN: u32 = 1 << 20;
+/// How many bytes of files are sent in one page of a listing.
+const MAX_PAGE_LEN: usize = 64 * 1024;
+/// The most files a listing may have, so that a broken peer can not make us read pages without end.
+const MAX_LISTED_FILES: usize = 1_000_000;
+/// How many files of a listing space is reserved for before the pages arrive.
+const MAX_PREALLOCATED_FILES: usize = 1024;
+
+/// The first frame a client sends after the encrypted session is established.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ClientRequest {
+    pub version: u16,
+    #[serde(default)]
+    pub capabilities: Vec<Capability>,
+    pub request: Request,
+}
+
+/// Only the version of a [ClientRequest], which can be read even if the rest changed between versions.
+#[derive(Deserialize)]
+struct Version {
+    version: u16,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum Request {
+    Download(DownloadRequest),
+    Pair,
+    /// Asks for the files the server shares, for peers that are not found by discovery.
+    List,
+    /// Asks the server to receive a file, which it does only if its user accepts it.
+    Offer(OfferRequest),
+    /// Asks for the content of a shared text.
+    Text(TextRequest),
+}
+
+/// Sent by the client to request a file.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DownloadRequest {
+    pub file: String,
+    /// The sizes of the files the client already has, keyed by [super::archive::entry_key].
+    pub delivered: HashMap<String, u64>,
+    /// The password of a share that is protected by one.
+    pub password: Option<String>,
+}
+
+/// Sent by the client to fetch a text.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TextRequest {
+    pub file: String,
+    /// The password of a share that is protected by one.
+    pub password: Option<String>,
+}
+
+/// Sent by the client to offer a file.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct OfferRequest {
+    /// The device that offers the file.
+    pub device: Device,
+    pub file: String,
+    pub meta: FileMeta,
+}
+
+/// The answer of the server to a [ClientRequest].
+#[derive(Debug, Serialize, Deserialize)]
+pub enum Response {
+    /// The file is sent as a tar stream after the header.
+    Download(DownloadHeader),
+    /// The pairing decisions are exchanged next.
+    Pair,
+    /// The files are sent in pages after the header, see [write_listing].
+    List(ListingHeader),
+    /// The user accepted the offered file, which the client sends next.
+    Offer,
+    /// The content of the requested text.
+    Text(String),
+    Error(ErrorResponse),
+}
+
+/// Sent by the file server before the tar stream.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DownloadHeader {
+    /// The size of the tar stream in bytes.
+    pub size: u64,
+    /// The number of bytes left out because the client already has them.
+    pub skipped: u64,
+    /// The offsets that partially delivered files continue from, keyed by [super::archive::entry_key].
+    pub resumed: HashMap<String, u64>,
+}
+
+/// Sent by the file server after the tar stream to clients that support [Capability::Hash].
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DownloadTrailer {
+    /// The BLAKE3 hashes of all files of the download, including the ones that were left out, keyed by
+    /// [super::archive::entry_key].
+    pub hashes: HashMap<String, String>,
+}
+
+/// The files a server shares, which discovery only announces the revision of.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Listing {
+    pub device: Device,
+    pub files: Vec<ListedFile>,
+    pub capabilities: Vec<Capability>,
+    /// The [listing_revision] of the files.
+    pub revision: u64,
+}
+
+impl Listing {
+    pub fn new(device: Device, local_files: &[LocalFile]) -> Listing {
+        let files = listed_files(local_files);
+        Listing {
+            device,
+            revision: listing_revision(&files),
+            files,
+            capabilities: CAPABILITIES.to_vec(),
+        }
+    }
+}
+
+/// Sent by the file server before the pages of a [Listing].
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ListingHeader {
+    pub device: Device,
+    pub capabilities: Vec<Capability>,
+    pub revision: u64,
+    /// The number of files in the pages that follow.
+    pub count: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ListedFile {
+    pub name: String,
+    pub meta: FileMeta,
+    /// The [text_hash] of a shared text, so that a text that comes with discovery can be checked.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hash: Option<String>,
+}
+
+pub fn listed_files(local_files: &[LocalFile]) -> Vec<ListedFile> {
+    local_files
+        .iter()
+        .map(|l| ListedFile {
+            name: l.name().to_owned(),
+            meta: l.meta().clone(),
+            hash: match l {
+                LocalFile::Text { text, .. } => Some(text_hash(text)),
+                LocalFile::Path { .. } => None,
+            },
+        })
+        .collect()
+}
+
+pub fn text_hash(text: &str) -> String {
+    blake3::hash(text.as_bytes()).to_hex().to_string()
+}
+
+/// A number that changes whenever a file is added or removed, or its metadata changes, so that peers
+/// know when to ask for the listing again.
+pub fn listing_revision(files: &[ListedFile]) -> u64 {
+    let json = serde_json::to_vec(files).unwrap_or_default();
+    let hash = blake3::hash(&json);
+    let mut revision = [0; 8];
+    revision.copy_from_slice(&hash.as_bytes()[..8]);
+    u64::from_be_bytes(revision)
+}
+
+/// Writes the listing as a [Response::List] header followed by pages of files, so that a share list of any
+/// length fits in frames.
+pub async fn write_listing(stream: &mut (impl AsyncWrite + Unpin), listing: Listing) -> Result<()> {
+    let header = ListingHeader {
+        device: listing.device,
+        capabilities: listing.capabilities,
+        revision: listing.revision,
+        count: listing.files.len(),
+    };
+    write_frame(stream, &Response::List(header)).await?;
+    for page in pages(listing.files)? {
+        write_frame(stream, &page).await?;
+    }
+    Ok(())
+}
+
+/// Reads the pages of files that follow the header of a listing.
+pub async fn read_listing(
+    stream: &mut (impl AsyncRead + Unpin),
+    header: ListingHeader,
+) -> Result<Listing> {
+    if header.count > MAX_LISTED_FILES {
+        return Err(eyre!("listing of {} files is too large", header.count));
+    }
+    // The count is only trusted as far as the pages that actually arrive.
+    let mut files = Vec::with_capacity(header.count.min(MAX_PREALLOCATED_FILES));
+    while files.len() < header.count {
+        let page: Vec<ListedFile> = read_frame(stream)
+            .await
+            .wrap_err("failed to read listing page")?;
+        if page.is_empty() || files.len() + page.len() > header.count {
+            return Err(eyre!("listing page does not match the header"));
+        }
+        files.extend(page);
+    }
+    Ok(Listing {
+        device: header.device,
+        files,
+        capabilities: header.capabilities,
+        revision: header.revision,
+    })
+}
+
+/// Splits the files into pages of at most [MAX_PAGE_LEN] bytes, or one file if a file alone is larger.
+fn pages(files: Vec<ListedFile>) -> Result<Vec<Vec<ListedFile>>> {
+    let mut pages = vec![];
+    let mut page = vec![];
+    let mut page_len = 0;
+    for file in files {
+        let len = serde_json::to_vec(&file)
+            .wrap_err("failed to serialize listed file")?
+            .len()
+            + 1;
+        if !page.is_empty() && page_len + len > MAX_PAGE_LEN {
+            pages.push(std::mem::take(&mut page));
+            page_len = 0;
+        }
+        page_len += len;
+        page.push(file);
+    }
+    if !page.is_empty() {
+        pages.push(page);
+    }
+    Ok(pages)
+}
+
+/// Why the server did not do what the client asked for.
+#[derive(Eq, PartialEq, Clone, Debug, Serialize, Deserialize)]
+pub enum ErrorResponse {
+    /// No file with the requested name is shared.
+    NotFound,
+    /// The file is shared, but not with the client.
+    Denied(Refusal),
+    /// The server is handling as many connections as it can.
+    Busy,
+    /// The server does not support the version of the client, the server's version is included.
+    UnsupportedVersion(u16),
+    /// The request could not be parsed.
+    BadRequest,
+    /// The user of the server declined the offered file.
+    Declined,
+}
+
+impl ErrorResponse {
+    /// Whether the same request might succeed later.
+    pub fn is_transient(&self) -> bool {
+        matches!(self, ErrorResponse::Busy)
+    }
+
+    /// Turns the error into a report, keeping refusals apart so that they can be shown to the user.
+    pub fn into_report(self) -> Report {
+        match self {
+            ErrorResponse::Denied(refusal) => Report::new(refusal),
+            error => Report::new(error),
+        }
+    }
+}
+
+impl fmt::Display for ErrorResponse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ErrorResponse::NotFound => f.write_str("the file is no longer shared"),
+            ErrorResponse::Denied(refusal) => refusal.fmt(f),
+            ErrorResponse::Busy => f.write_str("the peer is busy, try again later"),
+            ErrorResponse::UnsupportedVersion(version) => write!(
+                f,
+                "the peer uses protocol version {}, but this device uses version {}",
+                version, PROTOCOL_VERSION
+            ),
+            ErrorResponse::BadRequest => f.write_str("the peer did not understand the request"),
+            ErrorResponse::Declined => f.write_str("the peer declined the file"),
+        }
+    }
+}
+
+impl std::error::Error for ErrorResponse {}
+
+/// Sends a request with the current protocol version and capabilities.
+pub async fn write_request(stream: &mut (impl AsyncWrite + Unpin), request: Request) -> Result<()> {
+    let request = ClientRequest {
+        version: PROTOCOL_VERSION,
+        capabilities: CAPABILITIES.to_vec(),
+        request,
+    };
+    write_frame(stream, &request).await
+}
+
+/// Reads the request of a client, or the error to answer it with if it can not be handled.
+pub async fn read_request(
+    stream: &mut (impl AsyncRead + Unpin),
+) -> Result<std::result::Result<ClientRequest, ErrorResponse>> {
+    let frame = read_frame_bytes(stream).await?;
+    match serde_json::from_slice::<Version>(&frame) {
+        Ok(Version { version }) if version != PROTOCOL_VERSION => {
+            return Ok(Err(ErrorResponse::UnsupportedVersion(PROTOCOL_VERSION)))
+        }
+        Ok(_) => {}
+        Err(_) => return Ok(Err(ErrorResponse::BadRequest)),
+    }
+    match serde_json::from_slice::<ClientRequest>(&frame) {
+        Ok(request) => Ok(Ok(request)),
+        Err(_) => Ok(Err(ErrorResponse::BadRequest)),
+    }
+}
+
+/// Reads the response of the server, turning an [ErrorResponse] into an error.
+pub async fn read_response(stream: &mut (impl AsyncRead + Unpin)) -> Result<Response> {
+    match read_frame(stream).await? {
+        Response::Error(error) => Err(error.into_report()),
+        response => Ok(response),
+    }
+}
+
+/// Writes a value as a frame and flushes the stream.
+pub async fn write_frame<T: Serialize>(
+    stream: &mut (impl AsyncWrite + Unpin),
+    value: &T,
+) -> Result<()> {
+    let frame = serde_json::to_vec(value).wrap_err("failed to serialize frame")?;
+    let len = u32::try_from(frame.len())
+        .ok()
+        .filter(|len| *len <= MAX_FRAME_LEN)
+        .ok_or_else(|| eyre!("frame of {} bytes is too large", frame.len()))?;
+    stream
+        .write_u32(len)
+        .await
+        .wrap_err("failed to write frame length")?;
+    stream
+        .write_all(&frame)
+        .await
+        .wrap_err("failed to write frame")?;
+    stream.flush().await.wrap_err("failed to flush frame")
+}
+
+/// Reads a value written with [write_frame].
+pub async fn read_frame<T: DeserializeOwned>(stream: &mut (impl AsyncRead + Unpin)) -> Result<T> {
+    let frame = read_frame_bytes(stream).await?;
+    serde_json::from_slice(&frame).wrap_err("failed to parse frame")
+}
+
+async fn read_frame_bytes(stream: &mut (impl AsyncRead + Unpin)) -> Result<Vec<u8>> {
+    let len = stream
+        .read_u32()
+        .await
+        .wrap_err("failed to read frame length")?;
+    if len > MAX_FRAME_LEN {
+        return Err(eyre!("frame of {} bytes is too large", len));
+    }
+    let mut frame = vec![0; len as usize];
+    stream
+        .read_exact(&mut frame)
+        .await
+        .wrap_err("failed to read frame")?;
+    Ok(frame)
+}
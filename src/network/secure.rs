@@ -0,0 +1,324 @@
+//! This module contains the encrypted session layer that file transfers are made over.
+//!
+//! Sessions use the Noise XX handshake, so both sides learn the static public key of the other side
+//! while every session is encrypted with fresh ephemeral keys.
+//! Every message is sent as a big endian `u16` length followed by the encrypted message.
+//! An empty message marks the end of the stream, so that a dropped connection can not be mistaken for a
+//! completed transfer.
+
+use std::{
+    io,
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+
+use color_eyre::{
+    eyre::{eyre, WrapErr},
+    Result,
+};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf},
+    net::TcpStream,
+};
+
+use crate::storage;
+
+const NOISE_PARAMS: &str = "Noise_XX_25519_ChaChaPoly_BLAKE2s";
+const MAX_MESSAGE_LEN: usize = 65535;
+const TAG_LEN: usize = 16;
+const MAX_PAYLOAD_LEN: usize = MAX_MESSAGE_LEN - TAG_LEN;
+const LEN_PREFIX_LEN: usize = 2;
+const IDENTITY_FILE: &str = "identity.json";
+
+/// The static key pair that identifies this instance to its peers.
+#[derive(Serialize, Deserialize)]
+pub struct Identity {
+    private_key: Vec<u8>,
+    public_key: Vec<u8>,
+}
+
+impl Identity {
+    /// Loads the identity stored by an earlier run, or generates and stores a new one.
+    pub fn load_or_generate() -> Result<Identity> {
+        if let Some(identity) = storage::load(IDENTITY_FILE)? {
+            return Ok(identity);
+        }
+        let identity = Identity::generate()?;
+        storage::save(IDENTITY_FILE, &identity)?;
+        Ok(identity)
+    }
+
+    pub fn generate() -> Result<Identity> {
+        let keypair = builder()?
+            .generate_keypair()
+            .wrap_err("failed to generate key pair")?;
+        Ok(Identity {
+            private_key: keypair.private,
+            public_key: keypair.public,
+        })
+    }
+
+    pub fn fingerprint(&self) -> String {
+        fingerprint(&self.public_key)
+    }
+}
+
+/// Returns the fingerprint that peers advertise for a public key.
+pub fn fingerprint(public_key: &[u8]) -> String {
+    blake3::hash(public_key).to_hex().to_string()
+}
+
+fn builder() -> Result<snow::Builder<'static>> {
+    let params = NOISE_PARAMS
+        .parse()
+        .wrap_err("failed to parse noise params")?;
+    Ok(snow::Builder::new(params))
+}
+
+/// Performs the handshake as the side that opened the connection.
+pub async fn connect(mut stream: TcpStream, identity: &Identity) -> Result<SecureStream> {
+    let mut noise = builder()?
+        .local_private_key(&identity.private_key)
+        .build_initiator()
+        .wrap_err("failed to create handshake")?;
+    let mut buf = vec![0; MAX_MESSAGE_LEN];
+    // -> e
+    let len = noise.write_message(&[], &mut buf)?;
+    write_message(&mut stream, &buf[..len]).await?;
+    // <- e, ee, s, es
+    let message = read_message(&mut stream).await?;
+    noise.read_message(&message, &mut buf)?;
+    // -> s, se
+    let len = noise.write_message(&[], &mut buf)?;
+    write_message(&mut stream, &buf[..len]).await?;
+    SecureStream::new(stream, noise)
+}
+
+/// Performs the handshake as the side that accepted the connection.
+pub async fn accept(mut stream: TcpStream, identity: &Identity) -> Result<SecureStream> {
+    let mut noise = builder()?
+        .local_private_key(&identity.private_key)
+        .build_responder()
+        .wrap_err("failed to create handshake")?;
+    let mut buf = vec![0; MAX_MESSAGE_LEN];
+    // <- e
+    let message = read_message(&mut stream).await?;
+    noise.read_message(&message, &mut buf)?;
+    // -> e, ee, s, es
+    let len = noise.write_message(&[], &mut buf)?;
+    write_message(&mut stream, &buf[..len]).await?;
+    // <- s, se
+    let message = read_message(&mut stream).await?;
+    noise.read_message(&message, &mut buf)?;
+    SecureStream::new(stream, noise)
+}
+
+async fn write_message(stream: &mut TcpStream, message: &[u8]) -> Result<()> {
+    stream
+        .write_u16(message.len() as u16)
+        .await
+        .wrap_err("failed to write handshake message length")?;
+    stream
+        .write_all(message)
+        .await
+        .wrap_err("failed to write handshake message")
+}
+
+async fn read_message(stream: &mut TcpStream) -> Result<Vec<u8>> {
+    let len = stream
+        .read_u16()
+        .await
+        .wrap_err("failed to read handshake message length")?;
+    let mut message = vec![0; len as usize];
+    stream
+        .read_exact(&mut message)
+        .await
+        .wrap_err("failed to read handshake message")?;
+    Ok(message)
+}
+
+/// An encrypted stream to a peer.
+/// Written data is held back until the next write or flush, so the stream must be flushed before waiting
+/// for a reply.
+pub struct SecureStream {
+    stream: TcpStream,
+    transport: snow::TransportState,
+    remote_fingerprint: String,
+    handshake_hash: Vec<u8>,
+    /// Encrypted bytes of the message currently being read, including the length prefix.
+    read_message: Vec<u8>,
+    read_filled: usize,
+    /// Decrypted payload that has not been returned to the reader yet.
+    payload: Vec<u8>,
+    payload_pos: usize,
+    read_closed: bool,
+    /// Encrypted message that is being written, including the length prefix.
+    write_message: Vec<u8>,
+    write_pos: usize,
+    write_closed: bool,
+}
+
+impl SecureStream {
+    fn new(stream: TcpStream, noise: snow::HandshakeState) -> Result<SecureStream> {
+        let remote_key = noise
+            .get_remote_static()
+            .ok_or_else(|| eyre!("peer did not send a static key"))?;
+        let remote_fingerprint = fingerprint(remote_key);
+        let handshake_hash = noise.get_handshake_hash().to_vec();
+        let transport = noise
+            .into_transport_mode()
+            .wrap_err("failed to finish handshake")?;
+        Ok(SecureStream {
+            stream,
+            transport,
+            remote_fingerprint,
+            handshake_hash,
+            read_message: vec![0; LEN_PREFIX_LEN + MAX_MESSAGE_LEN],
+            read_filled: 0,
+            payload: Vec::with_capacity(MAX_MESSAGE_LEN),
+            payload_pos: 0,
+            read_closed: false,
+            write_message: Vec::with_capacity(LEN_PREFIX_LEN + MAX_MESSAGE_LEN),
+            write_pos: 0,
+            write_closed: false,
+        })
+    }
+
+    /// The fingerprint of the static key of the peer.
+    pub fn remote_fingerprint(&self) -> &str {
+        &self.remote_fingerprint
+    }
+
+    /// A hash of the handshake that is the same on both sides of the session.
+    pub fn handshake_hash(&self) -> &[u8] {
+        &self.handshake_hash
+    }
+
+    fn encrypt(&mut self, payload: &[u8]) -> io::Result<()> {
+        self.write_message
+            .resize(LEN_PREFIX_LEN + MAX_MESSAGE_LEN, 0);
+        let len = self
+            .transport
+            .write_message(payload, &mut self.write_message[LEN_PREFIX_LEN..])
+            .map_err(invalid_data)?;
+        self.write_message[..LEN_PREFIX_LEN].copy_from_slice(&(len as u16).to_be_bytes());
+        self.write_message.truncate(LEN_PREFIX_LEN + len);
+        self.write_pos = 0;
+        Ok(())
+    }
+
+    /// Writes the pending encrypted message to the underlying stream.
+    fn poll_write_message(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while self.write_pos < self.write_message.len() {
+            let n =
+                ready!(Pin::new(&mut self.stream)
+                    .poll_write(cx, &self.write_message[self.write_pos..]))?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.write_pos += n;
+        }
+        self.write_message.clear();
+        self.write_pos = 0;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncRead for SecureStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            if this.payload_pos < this.payload.len() {
+                let len = buf.remaining().min(this.payload.len() - this.payload_pos);
+                buf.put_slice(&this.payload[this.payload_pos..this.payload_pos + len]);
+                this.payload_pos += len;
+                return Poll::Ready(Ok(()));
+            }
+            if this.read_closed {
+                return Poll::Ready(Ok(()));
+            }
+            let needed = if this.read_filled < LEN_PREFIX_LEN {
+                LEN_PREFIX_LEN
+            } else {
+                LEN_PREFIX_LEN
+                    + u16::from_be_bytes([this.read_message[0], this.read_message[1]]) as usize
+            };
+            if this.read_filled < needed {
+                let mut read_buf = ReadBuf::new(&mut this.read_message[this.read_filled..needed]);
+                ready!(Pin::new(&mut this.stream).poll_read(cx, &mut read_buf))?;
+                let n = read_buf.filled().len();
+                if n == 0 {
+                    return Poll::Ready(Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "connection closed before the end of the stream",
+                    )));
+                }
+                this.read_filled += n;
+                continue;
+            }
+            if needed == LEN_PREFIX_LEN {
+                return Poll::Ready(Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "received an empty message",
+                )));
+            }
+            this.payload.resize(MAX_MESSAGE_LEN, 0);
+            let len = this
+                .transport
+                .read_message(
+                    &this.read_message[LEN_PREFIX_LEN..needed],
+                    &mut this.payload,
+                )
+                .map_err(invalid_data)?;
+            this.payload.truncate(len);
+            this.payload_pos = 0;
+            this.read_filled = 0;
+            if len == 0 {
+                this.read_closed = true;
+            }
+        }
+    }
+}
+
+impl AsyncWrite for SecureStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_message(cx))?;
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        let len = buf.len().min(MAX_PAYLOAD_LEN);
+        this.encrypt(&buf[..len])?;
+        Poll::Ready(Ok(len))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_message(cx))?;
+        Pin::new(&mut this.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_message(cx))?;
+        if !this.write_closed {
+            this.encrypt(&[])?;
+            this.write_closed = true;
+            ready!(this.poll_write_message(cx))?;
+        }
+        Pin::new(&mut this.stream).poll_shutdown(cx)
+    }
+}
+
+fn invalid_data(err: snow::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, err.to_string())
+}
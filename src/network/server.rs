@@ -1,112 +1,775 @@
-use std::{
-    net::{Ipv4Addr, SocketAddrV4},
-    path::PathBuf,
-};
-
-use color_eyre::{eyre::WrapErr, Result};
-use tokio::{
-    io::{AsyncBufReadExt, AsyncWriteExt, BufWriter},
-    sync::watch,
-};
-use tracing::error;
-
-use crate::common::{Files, LocalFile, RemoteFile, DownloadStatus};
-
-pub async fn run_file_download(files: &Files) -> Result<()> {
-    let mut downloads = files.get_downloads();
-    loop {
-        let (remote_file, path) = downloads
-            .recv()
-            .await
-            .wrap_err("download channel sender closed")?;
-        files.set_download_status(remote_file.clone(), Some(DownloadStatus::Running));
-        let result = download(remote_file.clone(), path).await;
-        let status = match result {
-            Ok(_) => DownloadStatus::Completed,
-            Err(report) => DownloadStatus::Failed(report.to_string()),
-        };
-        files.set_download_status(remote_file, Some(status));
-    }
-}
-
-async fn download(remote_file: RemoteFile, path: PathBuf) -> Result<()> {
-    let mut stream = tokio::net::TcpStream::connect(remote_file.addr)
-        .await
-        .wrap_err("failed to connect")?;
-    let mut filename =
-        serde_json::to_vec(&remote_file.file).wrap_err("failed to serialize filename")?;
-    filename.push(b'\n');
-    stream
-        .write_all(&filename)
-        .await
-        .wrap_err("failed to write filename to stream")?;
-    let reader = tokio::io::BufReader::new(stream);
-    let mut archive = tokio_tar::Archive::new(reader);
-    archive
-        .unpack(path)
-        .await
-        .wrap_err("failed to unpack tar")
-}
-
-pub async fn run_file_server(
-    port: u16,
-    local_files: watch::Receiver<Vec<LocalFile>>,
-) -> Result<()> {
-    let addr = SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, port);
-    let socket = tokio::net::TcpListener::bind(addr).await?;
-    loop {
-        let (stream, addr) = match socket.accept().await {
-            Ok(result) => result,
-            Err(err) => {
-                error!("File server failed to accept: {}", err);
-                continue;
-            }
-        };
-        tracing::debug!("Client connected: {}", addr);
-        let local_files = local_files.borrow().clone();
-        tokio::spawn(async move {
-            match run_connection(stream, local_files).await {
-                Ok(_) => tracing::info!("Client completed: {}", addr),
-                Err(err) => tracing::error!("Client failed: {} {}", addr, err),
-            }
-        });
-    }
-}
-
-async fn run_connection(stream: tokio::net::TcpStream, local_files: Vec<LocalFile>) -> Result<()> {
-    let mut buf_stream = tokio::io::BufReader::new(stream);
-    let mut filename = String::new();
-    buf_stream
-        .read_line(&mut filename)
-        .await
-        .wrap_err("failed to read filename from connection")?;
-    let filename: String =
-        serde_json::from_str(&filename).wrap_err("failed to parse filename as json")?;
-    tracing::debug!("Received filename request: {}", filename);
-    let file = local_files.iter().find(|f| f.name == filename);
-    let file = match file {
-        Some(file) => file,
-        None => return Err(color_eyre::eyre::eyre!("filename not found: {}", filename)),
-    };
-    tracing::debug!("Found file at: {:?}", file.path.to_str());
-    let stream = buf_stream.into_inner();
-    let buf_writer = BufWriter::new(stream);
-    let mut builder = tokio_tar::Builder::new(buf_writer);
-    if file.path.is_dir() {
-        builder
-            .append_dir_all(filename, file.path.as_path())
-            .await
-            .wrap_err("failed to write dir to tar builder")?;
-    } else {
-        let mut file = tokio::fs::File::open(file.path.as_path())
-            .await
-            .wrap_err("failed to open file that was to be written to tar builder")?;
-        builder
-            .append_file(filename, &mut file)
-            .await
-            .wrap_err("failed to write file to tar builder")?;
-    }
-    tracing::debug!("Finishing tar builder.");
-    let mut buf_writer = builder.into_inner().await.wrap_err("failed to finish the tar builder")?;
-    buf_writer.flush().await.wrap_err("failed to flush the buf writer")
-}
+use std::{
+    collections::{HashMap, HashSet},
+    hash::Hash,
+    io,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddrV4, SocketAddrV6},
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::{Duration, Instant, SystemTime},
+};
+
+use color_eyre::{
+    eyre::{eyre, WrapErr},
+    Report, Result,
+};
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWriteExt, BufWriter, ReadBuf},
+    sync::{watch, OwnedSemaphorePermit, Semaphore},
+};
+use tracing::error;
+
+use super::{
+    archive, hashing, offers, pairing,
+    protocol::{
+        self, ClientRequest, DownloadHeader, DownloadRequest, DownloadTrailer, ErrorResponse,
+        Listing, Request, Response,
+    },
+    secure::{self, Identity, SecureStream},
+    texts,
+};
+use crate::common::{
+    Capability, ConflictPolicy, Download, DownloadControl, DownloadStatus, Files, LocalFile,
+    Progress, Refusal, RemoteFile, Transfer, TransferOutcome,
+};
+
+/// How often a download reports its progress.
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(200);
+/// How many times a download is attempted before it fails.
+const DOWNLOAD_ATTEMPTS: u32 = 3;
+/// How long to wait before resuming a download that failed.
+const RESUME_DELAY: Duration = Duration::from_secs(2);
+/// How many connections the file server handles at once, further clients are told that it is busy.
+const MAX_CONNECTIONS: usize = 16;
+/// How long a client may take to establish the session and send its request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Keeps track of the files a download has written, so that it can be resumed after a failure.
+#[derive(Debug, Default)]
+pub(super) struct ResumeState {
+    conflict: ConflictPolicy,
+    /// Where the download is unpacked, chosen by the first attempt.
+    target: Option<archive::Target>,
+    written: HashSet<PathBuf>,
+}
+
+impl ResumeState {
+    pub(super) fn new(conflict: ConflictPolicy) -> ResumeState {
+        ResumeState {
+            conflict,
+            ..ResumeState::default()
+        }
+    }
+
+    /// Returns where the download is unpacked, choosing it if this is the first attempt.
+    /// The files an earlier run with the same `id` left in the staging folder count as written, so that
+    /// they are not sent again.
+    pub(super) async fn target(
+        &mut self,
+        dst: &Path,
+        name: &str,
+        id: &str,
+    ) -> Result<archive::Target> {
+        if let Some(target) = &self.target {
+            return Ok(target.clone());
+        }
+        let target = archive::Target::new(dst, name, id, self.conflict)
+            .await
+            .wrap_err("failed to choose where to download to")?;
+        self.written = target
+            .staged_files()
+            .await
+            .wrap_err("failed to read the files of an earlier download")?
+            .into_iter()
+            .collect();
+        self.target = Some(target.clone());
+        Ok(target)
+    }
+
+    /// Removes the files the download has written, when it will not be resumed.
+    pub(super) async fn discard(&self) {
+        if let Some(target) = &self.target {
+            if let Err(err) = target.discard().await {
+                tracing::warn!("Failed to remove {}: {}", target.staging().display(), err);
+            }
+        }
+    }
+
+    /// Returns the sizes of the files that have been written so far.
+    async fn delivered(&self) -> HashMap<String, u64> {
+        let mut delivered = HashMap::new();
+        let target = match &self.target {
+            Some(target) => target,
+            None => return delivered,
+        };
+        for path in self.written.iter() {
+            let local = match target.staging_path(path) {
+                Ok(local) => local,
+                Err(_) => continue,
+            };
+            if let Ok(metadata) = tokio::fs::metadata(local).await {
+                delivered.insert(archive::entry_key(path), metadata.len());
+            }
+        }
+        delivered
+    }
+}
+
+/// Runs the downloads that are requested, at most as many at once as `downloads` has permits. The others
+/// wait in the queue until a running download finishes or is paused.
+/// If nothing fails, the function will never return.
+pub async fn run_file_download(
+    files: Arc<Files>,
+    identity: Arc<Identity>,
+    downloads: Arc<Semaphore>,
+) -> Result<()> {
+    loop {
+        let download = files
+            .next_download()
+            .await
+            .ok_or_else(|| eyre!("download channel sender closed"))?;
+        let remote_file = download.remote_file.clone();
+        if let Some(msg) = remote_file.update_required() {
+            add_transfer(
+                &files,
+                &remote_file,
+                download.path,
+                Duration::ZERO,
+                TransferOutcome::Failed(msg.clone()),
+                None,
+            );
+            files.set_download_status(remote_file, Some(DownloadStatus::Failed(msg)));
+            continue;
+        }
+        if files
+            .get_download_status(&remote_file)
+            .is_some_and(|status| status.is_active())
+        {
+            tracing::info!("Already downloading {}", remote_file.file);
+            continue;
+        }
+        files.set_download_control(remote_file.clone(), None);
+        files.set_download_status(remote_file, Some(DownloadStatus::Queued));
+        let files = Arc::clone(&files);
+        let identity = Arc::clone(&identity);
+        let downloads = Arc::clone(&downloads);
+        tokio::spawn(async move {
+            run_download(&files, &identity, &downloads, download).await;
+        });
+    }
+}
+
+/// Downloads a file, resuming it if an attempt fails or the user resumes it after pausing, and reports
+/// its status.
+async fn run_download(
+    files: &Files,
+    identity: &Identity,
+    downloads: &Semaphore,
+    Download {
+        remote_file,
+        path,
+        password,
+        conflict,
+    }: Download,
+) {
+    let mut controls = files.get_download_controls();
+    let mut resume_state = ResumeState::new(conflict);
+    let mut duration = Duration::ZERO;
+    // None when the user cancelled the download.
+    let result = loop {
+        let permit = tokio::select! {
+            permit = downloads.acquire() => permit,
+            _ = wait_for_control(&mut controls, &remote_file, |c| c == Some(DownloadControl::Cancel)) => {
+                break None;
+            }
+        };
+        let started = Instant::now();
+        let attempts = run_attempts(
+            files,
+            identity,
+            &remote_file,
+            &path,
+            password.as_deref(),
+            &mut resume_state,
+        );
+        let control = tokio::select! {
+            result = attempts => {
+                duration += started.elapsed();
+                break Some(result);
+            }
+            control = wait_for_control(&mut controls, &remote_file, |c| c.is_some()) => control,
+        };
+        duration += started.elapsed();
+        drop(permit);
+        if control == Some(DownloadControl::Pause) {
+            let progress = match files.get_download_status(&remote_file) {
+                Some(DownloadStatus::Running(progress)) => progress,
+                _ => Progress::default(),
+            };
+            files.set_download_status(remote_file.clone(), Some(DownloadStatus::Paused(progress)));
+            let control = wait_for_control(&mut controls, &remote_file, |c| {
+                c != Some(DownloadControl::Pause)
+            })
+            .await;
+            if control.is_none() {
+                files.set_download_status(remote_file.clone(), Some(DownloadStatus::Queued));
+                continue;
+            }
+        }
+        break None;
+    };
+    files.set_download_control(remote_file.clone(), None);
+    // Only a cancelled download removes its staging folder, after a failure downloading it again
+    // continues from there.
+    if result.is_none() {
+        resume_state.discard().await;
+    }
+    let status = match result {
+        Some(Ok(hash)) => DownloadStatus::Completed(hash),
+        Some(Err(report)) => match report.downcast::<Refusal>() {
+            Ok(refusal) => DownloadStatus::Refused(refusal),
+            Err(report) => DownloadStatus::Failed(report.to_string()),
+        },
+        None => DownloadStatus::Cancelled,
+    };
+    let (outcome, hash) = match &status {
+        DownloadStatus::Completed(hash) => (TransferOutcome::Completed, hash.clone()),
+        DownloadStatus::Refused(refusal) => (TransferOutcome::Refused(refusal.clone()), None),
+        DownloadStatus::Failed(msg) => (TransferOutcome::Failed(msg.clone()), None),
+        _ => (TransferOutcome::Cancelled, None),
+    };
+    let path = match &resume_state.target {
+        Some(target) => target.dst.join(&target.root),
+        None => path,
+    };
+    add_transfer(files, &remote_file, path, duration, outcome, hash);
+    files.set_download_status(remote_file, Some(status));
+}
+
+/// Adds a download that has ended to the history.
+fn add_transfer(
+    files: &Files,
+    remote_file: &RemoteFile,
+    path: PathBuf,
+    duration: Duration,
+    outcome: TransferOutcome,
+    hash: Option<String>,
+) {
+    files.add_transfer(Transfer {
+        peer: remote_file.device_name.clone(),
+        fingerprint: remote_file.fingerprint.clone(),
+        file: remote_file.file.clone(),
+        path,
+        size: remote_file.meta.as_ref().map(|meta| meta.size),
+        finished: SystemTime::now(),
+        duration,
+        outcome,
+        hash,
+    });
+}
+
+/// Attempts a download until it succeeds or fails with an error that attempting it again will not fix.
+async fn run_attempts(
+    files: &Files,
+    identity: &Identity,
+    remote_file: &RemoteFile,
+    path: &Path,
+    password: Option<&str>,
+    resume_state: &mut ResumeState,
+) -> Result<Option<String>> {
+    files.set_download_status(
+        remote_file.clone(),
+        Some(DownloadStatus::Running(Progress::default())),
+    );
+    let mut attempt = 1;
+    loop {
+        let result = download(
+            remote_file.clone(),
+            path,
+            password,
+            resume_state,
+            identity,
+            |progress| {
+                files.set_download_status(
+                    remote_file.clone(),
+                    Some(DownloadStatus::Running(progress)),
+                )
+            },
+        )
+        .await;
+        match result {
+            Err(err) if attempt < DOWNLOAD_ATTEMPTS && is_transient(&err) => {
+                tracing::warn!("Download attempt {} failed, resuming: {:?}", attempt, err);
+                attempt += 1;
+                tokio::time::sleep(RESUME_DELAY).await;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Waits until the user's control of the download with the key is what `f` is waiting for, and returns
+/// it.
+pub(super) async fn wait_for_control<K: Eq + Hash>(
+    controls: &mut watch::Receiver<HashMap<K, DownloadControl>>,
+    key: &K,
+    f: impl Fn(Option<DownloadControl>) -> bool,
+) -> Option<DownloadControl> {
+    loop {
+        let control = controls.borrow_and_update().get(key).copied();
+        if f(control) {
+            return control;
+        }
+        if controls.changed().await.is_err() {
+            // Without the sender, the control can not change anymore.
+            std::future::pending::<()>().await;
+        }
+    }
+}
+
+/// Whether a failed download might succeed when it is attempted again, which is the case if the peer was
+/// busy or the connection failed. Anything else, like a refusal, a key that does not match or a protocol
+/// error, would fail the same way again.
+pub(super) fn is_transient(err: &Report) -> bool {
+    if let Some(error) = err.downcast_ref::<ErrorResponse>() {
+        return error.is_transient();
+    }
+    err.chain()
+        .filter_map(|cause| cause.downcast_ref::<io::Error>())
+        .any(|cause| {
+            matches!(
+                cause.kind(),
+                io::ErrorKind::TimedOut
+                    | io::ErrorKind::ConnectionRefused
+                    | io::ErrorKind::ConnectionReset
+                    | io::ErrorKind::ConnectionAborted
+                    | io::ErrorKind::NotConnected
+                    | io::ErrorKind::BrokenPipe
+                    | io::ErrorKind::UnexpectedEof
+            )
+        })
+}
+
+pub(super) async fn download(
+    remote_file: RemoteFile,
+    path: &Path,
+    password: Option<&str>,
+    resume_state: &mut ResumeState,
+    identity: &Identity,
+    on_progress: impl FnMut(Progress) + Unpin + Send + Sync,
+) -> Result<Option<String>> {
+    let stream = super::connect(&remote_file.addrs).await?;
+    let mut stream = secure::connect(stream, identity)
+        .await
+        .wrap_err("failed to establish encrypted session")?;
+    if stream.remote_fingerprint() != remote_file.fingerprint {
+        return Err(eyre!("peer key does not match the advertised fingerprint"));
+    }
+    let target = resume_state
+        .target(path, &remote_file.file, &staging_id(&remote_file))
+        .await?;
+    let delivered = resume_state.delivered().await;
+    let request = Request::Download(DownloadRequest {
+        file: remote_file.file.clone(),
+        delivered: delivered.clone(),
+        password: password.map(str::to_owned),
+    });
+    protocol::write_request(&mut stream, request)
+        .await
+        .wrap_err("failed to write request")?;
+    let mut header = match protocol::read_response(&mut stream).await? {
+        Response::Download(header) => header,
+        response => return Err(eyre!("unexpected response: {:?}", response)),
+    };
+    // Only continue files from the offsets that were actually asked for.
+    header
+        .resumed
+        .retain(|key, offset| delivered.get(key) == Some(offset));
+    let hashed = remote_file.supports(Capability::Hash);
+    let is_dir = remote_file.meta.as_ref().is_some_and(|meta| meta.is_dir);
+    let hashes = receive(
+        stream,
+        header,
+        &target,
+        resume_state,
+        hashed,
+        None,
+        on_progress,
+    )
+    .await?;
+    Ok(hashes.map(|hashes| hashing::content_hash(&hashes, is_dir)))
+}
+
+/// The id of the staging folder of a download, which is the same every time the file is downloaded from
+/// the same peer, so that a later run continues where an interrupted one stopped.
+pub(super) fn staging_id(remote_file: &RemoteFile) -> String {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(remote_file.fingerprint.as_bytes());
+    hasher.update(&[0]);
+    hasher.update(remote_file.file.as_bytes());
+    hasher.finalize().to_hex()[..16].to_owned()
+}
+
+/// Unpacks the tar stream that follows a download header into the target and moves it into place.
+/// If `hashed`, the trailer with the hashes to verify the files with is read after the tar stream, and the
+/// hashes of the files are returned if they all match. A tar stream that holds more than the `limit` is refused.
+pub(super) async fn receive(
+    stream: SecureStream,
+    header: DownloadHeader,
+    target: &archive::Target,
+    resume_state: &mut ResumeState,
+    hashed: bool,
+    limit: Option<archive::Limit>,
+    mut on_progress: impl FnMut(Progress) + Unpin + Send + Sync,
+) -> Result<Option<HashMap<String, String>>> {
+    let max_size = limit.map_or(u64::MAX, |limit| limit.archive_size());
+    if header.size > max_size {
+        return Err(eyre!(
+            "the peer sends {} bytes, more than the {} bytes that are accepted",
+            header.size,
+            max_size
+        ));
+    }
+    let started = Instant::now();
+    let reader = ProgressReader::new(stream, move |received| {
+        let mut progress = Progress::new(received, header.size, started.elapsed());
+        progress.received += header.skipped;
+        progress.total += header.skipped;
+        on_progress(progress)
+    });
+    // Limited to the tar stream, so that the trailer can be read after it.
+    let mut reader = reader.take(header.size);
+    archive::unpack(
+        &mut reader,
+        target,
+        &header.resumed,
+        &mut resume_state.written,
+        limit,
+    )
+    .await
+    .wrap_err("failed to unpack tar")?;
+    let hashes = if hashed {
+        tokio::io::copy(&mut reader, &mut tokio::io::sink())
+            .await
+            .wrap_err("failed to read the end of the tar")?;
+        let mut stream = reader.into_inner().inner;
+        let trailer: DownloadTrailer = protocol::read_frame(&mut stream)
+            .await
+            .wrap_err("failed to read download trailer")?;
+        verify(target, &trailer.hashes, resume_state).await?
+    } else {
+        None
+    };
+    target
+        .finish()
+        .await
+        .wrap_err("failed to move the download into place")?;
+    Ok(hashes)
+}
+
+/// Checks the files the download wrote against the hashes the peer sent, and returns the hashes of the
+/// files as they are on this device. A written file without a hash counts as not matching.
+/// Files that do not match are removed, so that they are sent again when the download is resumed.
+/// Files that were kept instead of received are compared as well, and if one of them differs, [None] is
+/// returned, since the share on this device is not the one the peer sent.
+async fn verify(
+    target: &archive::Target,
+    hashes: &HashMap<String, String>,
+    resume_state: &mut ResumeState,
+) -> Result<Option<HashMap<String, String>>> {
+    let mut local_hashes = HashMap::new();
+    let mut corrupted = vec![];
+    for path in resume_state.written.iter() {
+        let key = archive::entry_key(path);
+        let file = target.staging_path(path)?;
+        let actual = hashing::hash_file(&file)
+            .await
+            .wrap_err_with(|| format!("failed to hash {}", key))?;
+        match hashes.get(&key) {
+            Some(hash) if *hash == actual => {
+                local_hashes.insert(key, actual);
+            }
+            hash => {
+                tracing::warn!(
+                    "{} has the hash {}, but the peer sent {:?}",
+                    key,
+                    actual,
+                    hash
+                );
+                tokio::fs::remove_file(&file)
+                    .await
+                    .wrap_err_with(|| format!("failed to remove corrupted {}", key))?;
+                corrupted.push(path.clone());
+            }
+        }
+    }
+    if !corrupted.is_empty() {
+        for path in corrupted.iter() {
+            resume_state.written.remove(path);
+        }
+        let mut keys: Vec<String> = corrupted.iter().map(|p| archive::entry_key(p)).collect();
+        keys.sort_unstable();
+        return Err(eyre!("the hash of {} does not match", keys.join(", ")));
+    }
+    // The files that were not written already exist where the share belongs and were kept.
+    let mut verified = true;
+    for (key, hash) in hashes.iter() {
+        if local_hashes.contains_key(key) {
+            continue;
+        }
+        let file = target.final_path(Path::new(key))?;
+        let actual = hashing::hash_file(&file)
+            .await
+            .wrap_err_with(|| format!("{} was not received", key))?;
+        if actual != *hash {
+            tracing::info!("Kept {}, which differs from the file the peer sent", key);
+            verified = false;
+        }
+        local_hashes.insert(key.clone(), actual);
+    }
+    Ok(verified.then_some(local_hashes))
+}
+
+/// Received offers share `downloads` with the downloads, so that they count towards the same limit.
+pub async fn run_file_server(
+    port: u16,
+    files: Arc<Files>,
+    identity: Arc<Identity>,
+    downloads: Arc<Semaphore>,
+) -> Result<()> {
+    let socket = match bind_dual_stack(port) {
+        Ok(socket) => socket,
+        Err(err) => {
+            tracing::warn!("Failed to listen on IPv6, using only IPv4: {:?}", err);
+            tokio::net::TcpListener::bind(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, port)).await?
+        }
+    };
+    let connections = Arc::new(Semaphore::new(MAX_CONNECTIONS));
+    loop {
+        let (stream, addr) = match socket.accept().await {
+            Ok(result) => result,
+            Err(err) => {
+                error!("File server failed to accept: {}", err);
+                continue;
+            }
+        };
+        tracing::debug!("Client connected: {}", addr);
+        let files = Arc::clone(&files);
+        let identity = Arc::clone(&identity);
+        let downloads = Arc::clone(&downloads);
+        let permit = Arc::clone(&connections).try_acquire_owned().ok();
+        tokio::spawn(async move {
+            match run_connection(stream, &files, &identity, &downloads, permit).await {
+                Ok(_) => tracing::info!("Client completed: {}", addr),
+                Err(err) => tracing::error!("Client failed: {} {}", addr, err),
+            }
+        });
+    }
+}
+
+/// Listens on IPv6 and, through IPv4 mapped addresses, on IPv4.
+fn bind_dual_stack(port: u16) -> Result<tokio::net::TcpListener> {
+    let socket = Socket::new(Domain::IPV6, Type::STREAM, Some(Protocol::TCP))
+        .wrap_err("failed to create socket")?;
+    socket
+        .set_only_v6(false)
+        .wrap_err("failed to set socket to dual-stack")?;
+    #[cfg(unix)]
+    socket
+        .set_reuse_address(true)
+        .wrap_err("failed to set address reuse")?;
+    socket
+        .set_nonblocking(true)
+        .wrap_err("failed to set socket to non-blocking")?;
+    let addr = SocketAddrV6::new(Ipv6Addr::UNSPECIFIED, port, 0, 0);
+    socket
+        .bind(&addr.into())
+        .wrap_err("failed to bind socket")?;
+    socket.listen(1024).wrap_err("failed to listen")?;
+    tokio::net::TcpListener::from_std(socket.into()).wrap_err("failed to register socket")
+}
+
+/// Handles the request of a client. The connection counts towards [MAX_CONNECTIONS] while it holds the
+/// permit, which is [None] if the server is busy.
+async fn run_connection(
+    stream: tokio::net::TcpStream,
+    files: &Files,
+    identity: &Identity,
+    downloads: &Semaphore,
+    permit: Option<OwnedSemaphorePermit>,
+) -> Result<()> {
+    let request = async {
+        let mut stream = secure::accept(stream, identity)
+            .await
+            .wrap_err("failed to establish encrypted session")?;
+        let request = protocol::read_request(&mut stream)
+            .await
+            .wrap_err("failed to read request from connection")?;
+        Ok::<_, Report>((stream, request))
+    };
+    let (stream, request) = tokio::time::timeout(REQUEST_TIMEOUT, request)
+        .await
+        .map_err(|_| eyre!("the client did not send a request in time"))??;
+    let ClientRequest {
+        capabilities,
+        request,
+        ..
+    } = match request {
+        Ok(_) if permit.is_none() => return respond_error(stream, ErrorResponse::Busy).await,
+        Ok(request) => request,
+        Err(error) => return respond_error(stream, error).await,
+    };
+    match request {
+        Request::Download(mut request) => {
+            if !capabilities.contains(&Capability::Resume) {
+                request.delivered.clear();
+            }
+            let fingerprint = stream.remote_fingerprint().to_owned();
+            let authorized =
+                files.authorize_download(&request.file, &fingerprint, request.password.as_deref());
+            match authorized {
+                Some(Ok(LocalFile::Path { path, .. })) => {
+                    let send_hashes = capabilities.contains(&Capability::Hash);
+                    send_file(stream, request, &path, send_hashes).await
+                }
+                // Texts are fetched with their own request.
+                Some(Ok(LocalFile::Text { .. })) => {
+                    respond_error(stream, ErrorResponse::BadRequest).await
+                }
+                Some(Err(refusal)) => respond_error(stream, ErrorResponse::Denied(refusal)).await,
+                None => respond_error(stream, ErrorResponse::NotFound).await,
+            }
+        }
+        Request::Pair => {
+            // Waiting for the user to decide should not keep other clients out.
+            drop(permit);
+            pairing::accept_pairing(stream, files).await
+        }
+        Request::List => send_listing(stream, files).await,
+        Request::Offer(request) => {
+            // Offers are limited by how many may be pending and are received in the download queue.
+            drop(permit);
+            offers::receive_offer(stream, files, downloads, request).await
+        }
+        Request::Text(request) => texts::send_text(stream, files, request).await,
+    }
+}
+
+async fn send_listing(mut stream: SecureStream, files: &Files) -> Result<()> {
+    let device = files.get_device().borrow().clone();
+    let listing = Listing::new(device, &files.get_local_files().borrow());
+    protocol::write_listing(&mut stream, listing)
+        .await
+        .wrap_err("failed to write listing")?;
+    stream
+        .shutdown()
+        .await
+        .wrap_err("failed to shut down the stream")
+}
+
+/// Tells the client why its request is not handled and closes the connection.
+pub(super) async fn respond_error(mut stream: SecureStream, error: ErrorResponse) -> Result<()> {
+    tracing::info!("Responding with error: {}", error);
+    protocol::write_frame(&mut stream, &Response::Error(error))
+        .await
+        .wrap_err("failed to write error response")?;
+    stream
+        .shutdown()
+        .await
+        .wrap_err("failed to shut down the stream")
+}
+
+pub(super) async fn send_file(
+    stream: SecureStream,
+    request: DownloadRequest,
+    path: &Path,
+    send_hashes: bool,
+) -> Result<()> {
+    let filename = request.file;
+    tracing::debug!("Found file at: {:?}", path.to_str());
+    let mut entries = archive::entries(&filename, path)
+        .await
+        .wrap_err("failed to list files to write to tar")?;
+    let full_size = archive::archive_size(&entries);
+    // The files that are left out are hashed too, the client verifies the whole download.
+    let all_entries = entries.clone();
+    let resumed = archive::resume(&mut entries, &request.delivered);
+    let size = archive::archive_size(&entries);
+    if !request.delivered.is_empty() {
+        tracing::debug!("Resuming {} files, {} bytes left.", resumed.len(), size);
+    }
+    let response = Response::Download(DownloadHeader {
+        size,
+        skipped: full_size.saturating_sub(size),
+        resumed,
+    });
+    let mut buf_writer = BufWriter::new(stream);
+    protocol::write_frame(&mut buf_writer, &response)
+        .await
+        .wrap_err("failed to write download header")?;
+    tracing::debug!("Writing tar with {} entries.", entries.len());
+    let (mut buf_writer, mut hashes) = archive::write(buf_writer, &entries)
+        .await
+        .wrap_err("failed to write tar")?;
+    if send_hashes {
+        // The files that are sent are hashed while they are written, only the files the receiver already
+        // has are read again.
+        let delivered: Vec<archive::Entry> = all_entries
+            .into_iter()
+            .filter(|entry| !entry.is_dir && !hashes.contains_key(&archive::entry_key(&entry.path)))
+            .collect();
+        hashes.extend(
+            hashing::hash_entries(&delivered)
+                .await
+                .wrap_err("failed to hash files")?,
+        );
+        protocol::write_frame(&mut buf_writer, &DownloadTrailer { hashes })
+            .await
+            .wrap_err("failed to write download trailer")?;
+    }
+    buf_writer
+        .shutdown()
+        .await
+        .wrap_err("failed to shut down the buf writer")
+}
+
+/// Wraps a reader and reports the total number of bytes read at most every [PROGRESS_INTERVAL].
+struct ProgressReader<R, F> {
+    inner: R,
+    received: u64,
+    last_report: Instant,
+    report: F,
+}
+
+impl<R, F> ProgressReader<R, F> {
+    fn new(inner: R, report: F) -> Self {
+        ProgressReader {
+            inner,
+            received: 0,
+            last_report: Instant::now(),
+            report,
+        }
+    }
+}
+
+impl<R: AsyncRead + Unpin, F: FnMut(u64) + Unpin> AsyncRead for ProgressReader<R, F> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let filled = buf.filled().len();
+        let this = &mut *self;
+        let result = Pin::new(&mut this.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(())) = result {
+            this.received += (buf.filled().len() - filled) as u64;
+            if this.last_report.elapsed() >= PROGRESS_INTERVAL {
+                this.last_report = Instant::now();
+                (this.report)(this.received);
+            }
+        }
+        result
+    }
+}
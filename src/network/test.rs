@@ -1,83 +1,168 @@
-use crate::network::IPV4_MULTICAST_ADDR;
+use crate::network::{IPV4_MULTICAST_ADDR, IPV6_MULTICAST_ADDR};
 use crate::{
-    common::{LocalFile},
-    network::discovery::{run_discovery_receiver, run_discovery_sender},
+    common::{
+        AcceptRule, Capability, ConflictPolicy, Device, Download, DownloadStatus, FileMeta, Files,
+        LocalFile, Offer, OfferStatus, OutgoingOffer, PairedPeer, PairingStatus, Refusal,
+        RemoteFile, SharePolicy, TransferOutcome,
+    },
+    network::{
+        archive,
+        discovery::{self, run_discovery_receiver, run_discovery_sender},
+        hashing,
+        interfaces::{self, Interface},
+        mdns,
+        offers::run_outgoing_offers,
+        pairing::run_pairing_requests,
+        peers,
+        protocol::{
+            self, listing_revision, ClientRequest, ErrorResponse, Request, CAPABILITIES,
+            PROTOCOL_VERSION,
+        },
+        secure::Identity,
+        server::{self, download, run_file_download, run_file_server, ResumeState},
+        texts::fetch_text,
+    },
+};
+use std::{
+    collections::{HashMap, HashSet},
+    path::{Path, PathBuf},
+    sync::Arc,
 };
-use std::{net::{Ipv4Addr, SocketAddrV4}, time::Duration};
-use std::{path::PathBuf, sync::Arc};
-use tokio::{
-    sync::watch,
+use std::{
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::Duration,
 };
+use tokio::sync::{watch, Semaphore};
+use tokio_tar::EntryType;
 
 #[tokio::test]
 async fn discovery() {
     let port = 17891;
-    let (local_files_tx, local_files_rx) = watch::channel(vec![
-        LocalFile {
-            path: PathBuf::new(),
-            name: String::from("test1"),
-        },
-        LocalFile {
-            path: PathBuf::new(),
-            name: String::from("test2"),
+    let files = Arc::new(Files::default());
+    files.add_local_file(LocalFile::Path {
+        path: PathBuf::from("test1"),
+        name: String::from("test1"),
+        meta: FileMeta::default(),
+        policy: SharePolicy::Public,
+    });
+    files.add_local_file(LocalFile::Path {
+        path: PathBuf::from("test2"),
+        name: String::from("test2"),
+        meta: FileMeta {
+            size: 5,
+            is_dir: false,
+            file_count: 1,
+            modified: Some(1_600_000_000),
+            is_text: false,
         },
-    ]);
+        policy: SharePolicy::Public,
+    });
+    let device = files.get_device().borrow().clone();
+    let fingerprint = serve(port, files.clone());
 
+    let (local_files_rx, device_rx) = (files.get_local_files(), files.get_device());
     tokio::spawn(async move {
-        run_discovery_sender(local_files_rx, SocketAddrV4::new(Ipv4Addr::LOCALHOST, port)).await.unwrap();
+        run_discovery_sender(
+            local_files_rx,
+            device_rx,
+            SocketAddr::from((Ipv4Addr::LOCALHOST, port)),
+            &[],
+            fingerprint,
+        )
+        .await
+        .unwrap();
     });
 
     let (remote_files_tx, mut remote_files_rx) = watch::channel(Arc::new(vec![]));
+    let (devices_tx, devices_rx) = watch::channel(Arc::new(vec![]));
 
     tokio::spawn(async move {
-        run_discovery_receiver(&remote_files_tx, port, IPV4_MULTICAST_ADDR).await.unwrap();
+        let identity = Arc::new(Identity::generate().unwrap());
+        run_discovery_receiver(
+            &remote_files_tx,
+            &devices_tx,
+            port,
+            &[IPV4_MULTICAST_ADDR.into()],
+            &[],
+            identity,
+        )
+        .await
+        .unwrap();
     });
 
-    remote_files_rx.changed().await.unwrap();
-
-    let remote_files = (*remote_files_rx.borrow_and_update()).clone();
+    let remote_files = wait_for_remote_files(&mut remote_files_rx, |r| !r.is_empty()).await;
 
     assert_eq!(2, remote_files.len());
-    assert_eq!(Ipv4Addr::LOCALHOST, remote_files[0].addr.ip());
+    assert_eq!(Ipv4Addr::LOCALHOST, remote_files[0].addrs[0].ip());
     assert_eq!(String::from("test1"), remote_files[0].file);
-    assert_eq!(Ipv4Addr::LOCALHOST, remote_files[1].addr.ip());
+    assert_eq!(Ipv4Addr::LOCALHOST, remote_files[1].addrs[0].ip());
     assert_eq!(String::from("test2"), remote_files[1].file);
+    assert!(remote_files[0].is_compatible());
+    assert_eq!(device.id, remote_files[0].device_id);
+    assert_eq!(device.name, remote_files[0].device_name);
+    assert_eq!(Some(5), remote_files[1].meta.as_ref().map(|m| m.size));
 
-    local_files_tx.send(vec![]).unwrap();
+    let local_files = files.get_local_files().borrow().clone();
+    for local_file in local_files {
+        files.remove_local_file(&local_file);
+    }
 
-    remote_files_rx.changed().await.unwrap();
-
-    let remote_files = (*remote_files_rx.borrow_and_update()).clone();
+    wait_for_remote_files(&mut remote_files_rx, |r| r.is_empty()).await;
 
-    assert!(remote_files.is_empty());
+    // A device that shares nothing is still known, so that files can be sent to it.
+    let devices = devices_rx.borrow().clone();
+    assert_eq!(1, devices.len());
+    assert_eq!(device.id, devices[0].device_id);
+    assert_eq!(device.name, devices[0].device_name);
 }
 
 #[tokio::test]
 async fn discovery_timeout() {
     let port = 17892;
-    let (local_files_tx, local_files_rx) = watch::channel(vec![
-        LocalFile {
-            path: PathBuf::new(),
-            name: String::from("test1"),
-        },
-    ]);
+    let files = Arc::new(Files::default());
+    files.add_local_file(LocalFile::Path {
+        path: PathBuf::from("test1"),
+        name: String::from("test1"),
+        meta: FileMeta::default(),
+        policy: SharePolicy::Public,
+    });
+    let fingerprint = serve(port, files.clone());
 
+    // The sender stops when its channel closes.
+    let (local_files_tx, local_files_rx) = watch::channel(files.get_local_files().borrow().clone());
+    let device_rx = files.get_device();
     tokio::spawn(async move {
-        run_discovery_sender(local_files_rx, SocketAddrV4::new(Ipv4Addr::LOCALHOST, port)).await.unwrap();
+        run_discovery_sender(
+            local_files_rx,
+            device_rx,
+            SocketAddr::from((Ipv4Addr::LOCALHOST, port)),
+            &[],
+            fingerprint,
+        )
+        .await
+        .unwrap();
     });
 
     let (remote_files_tx, mut remote_files_rx) = watch::channel(Arc::new(vec![]));
 
     tokio::spawn(async move {
-        run_discovery_receiver(&remote_files_tx, port, IPV4_MULTICAST_ADDR).await.unwrap();
+        let identity = Arc::new(Identity::generate().unwrap());
+        run_discovery_receiver(
+            &remote_files_tx,
+            &watch::channel(Arc::new(vec![])).0,
+            port,
+            &[IPV4_MULTICAST_ADDR.into()],
+            &[],
+            identity,
+        )
+        .await
+        .unwrap();
     });
 
-    remote_files_rx.changed().await.unwrap();
-
-    let remote_files = (*remote_files_rx.borrow_and_update()).clone();
+    let remote_files = wait_for_remote_files(&mut remote_files_rx, |r| !r.is_empty()).await;
 
     assert_eq!(1, remote_files.len());
-    assert_eq!(Ipv4Addr::LOCALHOST, remote_files[0].addr.ip());
+    assert_eq!(Ipv4Addr::LOCALHOST, remote_files[0].addrs[0].ip());
     assert_eq!(String::from("test1"), remote_files[0].file);
 
     std::mem::drop(local_files_tx);
@@ -89,4 +174,2054 @@ async fn discovery_timeout() {
     let remote_files = (*remote_files_rx.borrow_and_update()).clone();
 
     assert!(remote_files.is_empty());
-}
\ No newline at end of file
+}
+
+/// Serves the files on `port`, so that the files of a discovered device can be fetched, and returns the
+/// key fingerprint of the server.
+fn serve(port: u16, files: Arc<Files>) -> String {
+    let identity = Arc::new(Identity::generate().unwrap());
+    let fingerprint = identity.fingerprint();
+    tokio::spawn(async move {
+        run_file_server(port, files, identity, Arc::new(Semaphore::new(4)))
+            .await
+            .unwrap();
+    });
+    fingerprint
+}
+
+/// Waits until the remote files are what `f` is waiting for.
+async fn wait_for_remote_files(
+    remote_files_rx: &mut watch::Receiver<Arc<Vec<RemoteFile>>>,
+    f: impl Fn(&[RemoteFile]) -> bool,
+) -> Vec<RemoteFile> {
+    let wait = async {
+        loop {
+            let remote_files = remote_files_rx.borrow_and_update().clone();
+            if f(&remote_files) {
+                return remote_files.to_vec();
+            }
+            remote_files_rx.changed().await.unwrap();
+        }
+    };
+    tokio::time::timeout(Duration::from_secs(10), wait)
+        .await
+        .unwrap()
+}
+
+fn remote_file(port: u16, file: &str, fingerprint: String) -> RemoteFile {
+    let addr = SocketAddr::from((Ipv4Addr::LOCALHOST, port));
+    RemoteFile {
+        addrs: vec![addr],
+        device_id: String::from("device"),
+        device_name: String::from("Device"),
+        file: String::from(file),
+        fingerprint,
+        version: PROTOCOL_VERSION,
+        capabilities: CAPABILITIES.to_vec(),
+        meta: None,
+        interface: None,
+        text: None,
+    }
+}
+
+fn temp_dir() -> PathBuf {
+    let name = random_string::generate(12, "abcdefghijklmnopqrstuvwxyz");
+    let dir = std::env::temp_dir().join(format!("shary-test-{}", name));
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// The staging folder a download of `remote_file` into `output` unpacks to.
+fn staging(output: &Path, remote_file: &RemoteFile) -> PathBuf {
+    output.join(format!(
+        ".{}.{}.shary-partial",
+        remote_file.file,
+        server::staging_id(remote_file)
+    ))
+}
+
+fn create_test_folder() -> PathBuf {
+    let dir = temp_dir().join("folder");
+    let nested = dir.join("n".repeat(60)).join("e".repeat(60));
+    std::fs::create_dir_all(&nested).unwrap();
+    std::fs::write(dir.join("empty"), b"").unwrap();
+    std::fs::write(dir.join("small"), b"hello").unwrap();
+    std::fs::write(nested.join("large"), vec![7u8; 100_000]).unwrap();
+    dir
+}
+
+#[test]
+fn file_meta() {
+    let dir = create_test_folder();
+    let meta = LocalFile::new(dir).unwrap().meta().clone();
+    assert!(meta.is_dir);
+    assert_eq!(3, meta.file_count);
+    assert_eq!(100_005, meta.size);
+    assert!(meta.modified.is_some());
+}
+
+#[tokio::test]
+async fn content_hash() {
+    let dir = temp_dir().join("folder");
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("small"), b"hello").unwrap();
+    let entries = archive::entries("folder", &dir).await.unwrap();
+    let hashes = hashing::hash_entries(&entries).await.unwrap();
+    let file_hash = blake3::hash(b"hello").to_hex().to_string();
+    assert_eq!(file_hash, hashing::content_hash(&hashes, false));
+    // A folder with one file is hashed like any folder, not like its file.
+    let lines = format!("{}  folder/small\n", file_hash);
+    assert_eq!(
+        blake3::hash(lines.as_bytes()).to_hex().to_string(),
+        hashing::content_hash(&hashes, true)
+    );
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn links_and_unreadable_entries() {
+    let dir = create_test_folder();
+    // A link back to the folder would be walked forever, and a broken link can not be read.
+    std::os::unix::fs::symlink(&dir, dir.join("loop")).unwrap();
+    std::os::unix::fs::symlink(dir.join("missing"), dir.join("broken")).unwrap();
+    std::os::unix::fs::symlink(dir.join("small"), dir.join("linked")).unwrap();
+
+    let meta = LocalFile::new(dir.clone()).unwrap().meta().clone();
+    assert_eq!(4, meta.file_count);
+    assert_eq!(100_010, meta.size);
+    let entries = archive::entries("folder", &dir).await.unwrap();
+    let files: Vec<_> = entries.iter().filter(|e| !e.is_dir).collect();
+    assert_eq!(4, files.len());
+    assert_eq!(100_010, files.iter().map(|e| e.size).sum::<u64>());
+}
+
+#[tokio::test]
+async fn archive_size() {
+    let dir = create_test_folder();
+    let entries = archive::entries("folder", &dir).await.unwrap();
+    let (tar, hashes) = archive::write(Vec::new(), &entries).await.unwrap();
+    assert_eq!(hashing::hash_entries(&entries).await.unwrap(), hashes);
+    assert_eq!(tar.len() as u64, archive::archive_size(&entries));
+}
+
+#[tokio::test]
+async fn download_folder() {
+    let port = 17893;
+    let dir = create_test_folder();
+    let files = Arc::new(Files::default());
+    files.add_local_file(LocalFile::new(dir.clone()).unwrap());
+
+    let server_identity = Arc::new(Identity::generate().unwrap());
+    let remote_file = remote_file(port, "folder", server_identity.fingerprint());
+    let identity = Identity::generate().unwrap();
+
+    tokio::spawn(async move {
+        run_file_server(port, files, server_identity, Arc::new(Semaphore::new(4)))
+            .await
+            .unwrap();
+    });
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let output = temp_dir();
+    download(
+        remote_file,
+        &output,
+        None,
+        &mut ResumeState::default(),
+        &identity,
+        |_| {},
+    )
+    .await
+    .unwrap();
+
+    let nested = output
+        .join("folder")
+        .join("n".repeat(60))
+        .join("e".repeat(60));
+    assert_eq!(
+        b"hello".to_vec(),
+        std::fs::read(output.join("folder").join("small")).unwrap()
+    );
+    assert_eq!(
+        vec![7u8; 100_000],
+        std::fs::read(nested.join("large")).unwrap()
+    );
+}
+
+#[tokio::test]
+async fn resume_download() {
+    let port = 17894;
+    let dir = create_test_folder();
+    let files = Arc::new(Files::default());
+    files.add_local_file(LocalFile::new(dir.clone()).unwrap());
+
+    let server_identity = Arc::new(Identity::generate().unwrap());
+    let mut remote_file = remote_file(port, "folder", server_identity.fingerprint());
+    // Without hashes, so that the marked bytes below are not reported as corrupted.
+    remote_file.capabilities.retain(|c| *c != Capability::Hash);
+    let identity = Identity::generate().unwrap();
+
+    tokio::spawn(async move {
+        run_file_server(port, files, server_identity, Arc::new(Semaphore::new(4)))
+            .await
+            .unwrap();
+    });
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let output = temp_dir();
+    let mut resume_state = ResumeState::default();
+    download(
+        remote_file.clone(),
+        &output,
+        None,
+        &mut resume_state,
+        &identity,
+        |_| {},
+    )
+    .await
+    .unwrap();
+
+    // Pretend the connection dropped while the large file was being written to the staging folder.
+    // The kept bytes are marked so that it is visible they were not sent again.
+    let nested = Path::new("n".repeat(60).as_str()).join("e".repeat(60));
+    let staged = staging(&output, &remote_file).join(&nested);
+    std::fs::create_dir_all(&staged).unwrap();
+    std::fs::write(staged.join("large"), vec![1u8; 30_000]).unwrap();
+    std::fs::remove_file(output.join("folder").join("small")).unwrap();
+
+    download(
+        remote_file.clone(),
+        &output,
+        None,
+        &mut resume_state,
+        &identity,
+        |_| {},
+    )
+    .await
+    .unwrap();
+    let large = output.join("folder").join(&nested).join("large");
+
+    assert_eq!(
+        b"hello".to_vec(),
+        std::fs::read(output.join("folder").join("small")).unwrap()
+    );
+    let mut expected = vec![1u8; 30_000];
+    expected.extend(vec![7u8; 70_000]);
+    assert_eq!(expected, std::fs::read(&large).unwrap());
+
+    // A download that starts over, like after a restart, continues with what the staging folder holds.
+    let output = temp_dir();
+    let staged = staging(&output, &remote_file).join(&nested);
+    std::fs::create_dir_all(&staged).unwrap();
+    std::fs::write(staged.join("large"), vec![2u8; 40_000]).unwrap();
+    download(
+        remote_file.clone(),
+        &output,
+        None,
+        &mut ResumeState::default(),
+        &identity,
+        |_| {},
+    )
+    .await
+    .unwrap();
+    let mut expected = vec![2u8; 40_000];
+    expected.extend(vec![7u8; 60_000]);
+    assert_eq!(
+        expected,
+        std::fs::read(output.join("folder").join(nested).join("large")).unwrap()
+    );
+    assert!(!staging(&output, &remote_file).exists());
+}
+
+#[tokio::test]
+async fn download_verifies_hashes() {
+    let port = 17906;
+    let dir = create_test_folder();
+    let files = Arc::new(Files::default());
+    files.add_local_file(LocalFile::new(dir.clone()).unwrap());
+
+    let server_identity = Arc::new(Identity::generate().unwrap());
+    let remote_file = RemoteFile {
+        meta: Some(FileMeta::read(&dir).unwrap()),
+        ..remote_file(port, "folder", server_identity.fingerprint())
+    };
+    let identity = Identity::generate().unwrap();
+
+    tokio::spawn(async move {
+        run_file_server(port, files, server_identity, Arc::new(Semaphore::new(4)))
+            .await
+            .unwrap();
+    });
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let output = temp_dir();
+    let mut resume_state = ResumeState::default();
+    let hash = download(
+        remote_file.clone(),
+        &output,
+        None,
+        &mut resume_state,
+        &identity,
+        |_| {},
+    )
+    .await
+    .unwrap();
+    let entries = archive::entries("folder", &dir).await.unwrap();
+    let expected = hashing::content_hash(&hashing::hash_entries(&entries).await.unwrap(), true);
+    assert_eq!(Some(expected.clone()), hash);
+
+    assert!(!staging(&output, &remote_file).exists());
+
+    // Bytes that were corrupted before the connection dropped are detected after resuming, and the
+    // downloaded folder is not touched.
+    let nested = Path::new("n".repeat(60).as_str()).join("e".repeat(60));
+    let staged = staging(&output, &remote_file).join(&nested);
+    std::fs::create_dir_all(&staged).unwrap();
+    std::fs::write(staged.join("large"), vec![1u8; 30_000]).unwrap();
+    let result = download(
+        remote_file.clone(),
+        &output,
+        None,
+        &mut resume_state,
+        &identity,
+        |_| {},
+    )
+    .await;
+    assert!(result.unwrap_err().to_string().contains("does not match"));
+    assert!(!staged.join("large").exists());
+    let large = output.join("folder").join(&nested).join("large");
+    assert_eq!(vec![7u8; 100_000], std::fs::read(&large).unwrap());
+
+    // The corrupted file is sent again when the download is retried.
+    let hash = download(
+        remote_file.clone(),
+        &output,
+        None,
+        &mut resume_state,
+        &identity,
+        |_| {},
+    )
+    .await
+    .unwrap();
+    assert_eq!(vec![7u8; 100_000], std::fs::read(&large).unwrap());
+    assert!(!staging(&output, &remote_file).exists());
+    assert_eq!(Some(expected.clone()), hash);
+
+    // A file the peer did not send a hash for is not trusted either.
+    let output = temp_dir();
+    let staged = staging(&output, &remote_file);
+    std::fs::create_dir_all(&staged).unwrap();
+    std::fs::write(staged.join("stale"), b"stale").unwrap();
+    let result = download(
+        remote_file.clone(),
+        &output,
+        None,
+        &mut ResumeState::default(),
+        &identity,
+        |_| {},
+    )
+    .await;
+    assert!(result
+        .unwrap_err()
+        .to_string()
+        .contains("folder/stale does not match"));
+    assert!(!staged.join("stale").exists());
+    assert!(!output.join("folder").join("stale").exists());
+
+    // A download that resumes a file reports the hash of the whole file.
+    let output = temp_dir();
+    let staged = staging(&output, &remote_file).join(&nested);
+    std::fs::create_dir_all(&staged).unwrap();
+    std::fs::write(staged.join("large"), vec![7u8; 30_000]).unwrap();
+    let hash = download(
+        remote_file.clone(),
+        &output,
+        None,
+        &mut ResumeState::default(),
+        &identity,
+        |_| {},
+    )
+    .await
+    .unwrap();
+    assert_eq!(Some(expected.clone()), hash);
+
+    // Files that are kept instead of received are compared too, the share is only verified if they match.
+    for (kept, verified) in [(b"hello", Some(expected)), (b"other", None)] {
+        let output = temp_dir();
+        std::fs::create_dir_all(output.join("folder")).unwrap();
+        std::fs::write(output.join("folder").join("small"), kept).unwrap();
+        let hash = download(
+            remote_file.clone(),
+            &output,
+            None,
+            &mut ResumeState::new(ConflictPolicy::Skip),
+            &identity,
+            |_| {},
+        )
+        .await
+        .unwrap();
+        assert_eq!(verified, hash);
+        assert_eq!(
+            kept.to_vec(),
+            std::fs::read(output.join("folder").join("small")).unwrap()
+        );
+    }
+}
+
+#[tokio::test]
+async fn download_rejects_wrong_fingerprint() {
+    let port = 17895;
+    let dir = create_test_folder();
+    let files = Arc::new(Files::default());
+    files.add_local_file(LocalFile::new(dir.clone()).unwrap());
+
+    let server_identity = Arc::new(Identity::generate().unwrap());
+    let identity = Identity::generate().unwrap();
+    let remote_file = remote_file(port, "folder", identity.fingerprint());
+
+    tokio::spawn(async move {
+        run_file_server(port, files, server_identity, Arc::new(Semaphore::new(4)))
+            .await
+            .unwrap();
+    });
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let output = temp_dir();
+    let result = download(
+        remote_file,
+        &output,
+        None,
+        &mut ResumeState::default(),
+        &identity,
+        |_| {},
+    )
+    .await;
+    assert!(result.is_err());
+    assert!(!output.join("folder").exists());
+}
+
+async fn wait_for_pairing_status(files: &Files, status: PairingStatus) {
+    let mut pairing = files.get_pairing();
+    while pairing.borrow_and_update().as_ref().map(|p| &p.status) != Some(&status) {
+        pairing.changed().await.unwrap();
+    }
+}
+
+#[tokio::test]
+async fn pairing() {
+    let port = 17896;
+    let server_files = Arc::new(Files::default());
+    let server_identity = Arc::new(Identity::generate().unwrap());
+    let server_fingerprint = server_identity.fingerprint();
+    let client_files = Arc::new(Files::default());
+    let client_identity = Identity::generate().unwrap();
+    let client_fingerprint = client_identity.fingerprint();
+
+    {
+        let server_files = Arc::clone(&server_files);
+        tokio::spawn(async move {
+            run_file_server(
+                port,
+                server_files,
+                server_identity,
+                Arc::new(Semaphore::new(4)),
+            )
+            .await
+            .unwrap();
+        });
+    }
+    {
+        let client_files = Arc::clone(&client_files);
+        tokio::spawn(async move {
+            run_pairing_requests(&client_files, &client_identity)
+                .await
+                .unwrap();
+        });
+    }
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    client_files.pair(
+        vec![SocketAddr::from((Ipv4Addr::LOCALHOST, port))],
+        server_fingerprint.clone(),
+    );
+
+    wait_for_pairing_status(&client_files, PairingStatus::Confirming).await;
+    wait_for_pairing_status(&server_files, PairingStatus::Confirming).await;
+    let client_code = client_files
+        .get_pairing()
+        .borrow()
+        .as_ref()
+        .unwrap()
+        .code
+        .clone();
+    let server_code = server_files
+        .get_pairing()
+        .borrow()
+        .as_ref()
+        .unwrap()
+        .code
+        .clone();
+    assert!(client_code.is_some());
+    assert_eq!(client_code, server_code);
+
+    client_files.decide_pairing(true);
+    server_files.decide_pairing(true);
+
+    wait_for_pairing_status(&client_files, PairingStatus::Paired).await;
+    wait_for_pairing_status(&server_files, PairingStatus::Paired).await;
+    assert!(client_files.is_paired(&server_fingerprint));
+    assert!(server_files.is_paired(&client_fingerprint));
+}
+
+#[tokio::test]
+async fn pairing_refused_after_rejection() {
+    let port = 17914;
+    let server_files = Arc::new(Files::default());
+    let server_identity = Arc::new(Identity::generate().unwrap());
+    let server_fingerprint = server_identity.fingerprint();
+    let client_files = Arc::new(Files::default());
+    let client_identity = Identity::generate().unwrap();
+
+    {
+        let server_files = Arc::clone(&server_files);
+        tokio::spawn(async move {
+            run_file_server(
+                port,
+                server_files,
+                server_identity,
+                Arc::new(Semaphore::new(4)),
+            )
+            .await
+            .unwrap();
+        });
+    }
+    {
+        let client_files = Arc::clone(&client_files);
+        tokio::spawn(async move {
+            run_pairing_requests(&client_files, &client_identity)
+                .await
+                .unwrap();
+        });
+    }
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    let addrs = vec![SocketAddr::from((Ipv4Addr::LOCALHOST, port))];
+
+    client_files.pair(addrs.clone(), server_fingerprint.clone());
+    wait_for_pairing_status(&server_files, PairingStatus::Confirming).await;
+    server_files.decide_pairing(false);
+    client_files.decide_pairing(true);
+    for files in [&server_files, &client_files] {
+        let mut pairing = files.get_pairing();
+        while !pairing
+            .borrow_and_update()
+            .as_ref()
+            .unwrap()
+            .status
+            .is_finished()
+        {
+            pairing.changed().await.unwrap();
+        }
+    }
+    server_files.clear_pairing();
+
+    // Asking again right away does not show the pairing to the user.
+    let mut pairing = client_files.get_pairing();
+    client_files.pair(addrs, server_fingerprint);
+    loop {
+        let status = pairing.borrow_and_update().as_ref().unwrap().status.clone();
+        if let PairingStatus::Failed(msg) = status {
+            if msg.contains("busy") {
+                break;
+            }
+        }
+        pairing.changed().await.unwrap();
+    }
+    assert!(server_files.get_pairing().borrow().is_none());
+}
+
+#[tokio::test]
+async fn share_policy() {
+    let port = 17897;
+    let dir = create_test_folder();
+    let files = Arc::new(Files::default());
+    let local_file = LocalFile::new(dir.clone()).unwrap();
+    files.add_local_file(local_file.clone());
+
+    let server_identity = Arc::new(Identity::generate().unwrap());
+    let remote_file = remote_file(port, "folder", server_identity.fingerprint());
+    let identity = Identity::generate().unwrap();
+    let other_identity = Identity::generate().unwrap();
+
+    {
+        let files = Arc::clone(&files);
+        tokio::spawn(async move {
+            run_file_server(port, files, server_identity, Arc::new(Semaphore::new(4)))
+                .await
+                .unwrap();
+        });
+    }
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let refusal = |result: color_eyre::Result<Option<String>>| {
+        result.unwrap_err().downcast::<Refusal>().unwrap()
+    };
+    let output = temp_dir();
+
+    files.set_share_policy(
+        &local_file,
+        SharePolicy::Peers(vec![identity.fingerprint()]),
+    );
+    let result = download(
+        remote_file.clone(),
+        &output,
+        None,
+        &mut ResumeState::default(),
+        &identity,
+        |_| {},
+    )
+    .await;
+    assert_eq!(Refusal::NotAllowed, refusal(result));
+    files.add_paired_peer(PairedPeer {
+        fingerprint: identity.fingerprint(),
+        name: String::from("Sender"),
+    });
+    download(
+        remote_file.clone(),
+        &output,
+        None,
+        &mut ResumeState::default(),
+        &identity,
+        |_| {},
+    )
+    .await
+    .unwrap();
+
+    files.set_share_policy(&local_file, SharePolicy::password());
+    let password = match files.get_local_files().borrow()[0].policy() {
+        SharePolicy::Password { password, .. } => password.clone(),
+        _ => unreachable!(),
+    };
+    let result = download(
+        remote_file.clone(),
+        &output,
+        None,
+        &mut ResumeState::default(),
+        &identity,
+        |_| {},
+    )
+    .await;
+    assert_eq!(Refusal::PasswordRequired, refusal(result));
+    let result = download(
+        remote_file.clone(),
+        &output,
+        Some("wrong"),
+        &mut ResumeState::default(),
+        &identity,
+        |_| {},
+    )
+    .await;
+    assert_eq!(Refusal::WrongPassword, refusal(result));
+    download(
+        remote_file.clone(),
+        &output,
+        Some(&password),
+        &mut ResumeState::default(),
+        &identity,
+        |_| {},
+    )
+    .await
+    .unwrap();
+    // The peer that used the password can download again, but the password is used up.
+    download(
+        remote_file.clone(),
+        &output,
+        None,
+        &mut ResumeState::default(),
+        &identity,
+        |_| {},
+    )
+    .await
+    .unwrap();
+    let result = download(
+        remote_file,
+        &output,
+        Some(&password),
+        &mut ResumeState::default(),
+        &other_identity,
+        |_| {},
+    )
+    .await;
+    assert_eq!(Refusal::WrongPassword, refusal(result));
+
+    // A peer that guesses wrong too often is refused, and so many guesses replace the password.
+    let password = |files: &Files| match files.get_local_files().borrow()[0].policy() {
+        SharePolicy::Password { password, .. } => password.clone(),
+        _ => unreachable!(),
+    };
+    let first = password(&files);
+    for _ in 0..3 {
+        let result = files.authorize_download("folder", "guesser", Some("wrong"));
+        assert_eq!(
+            Some(Err(Refusal::WrongPassword)),
+            result.map(|r| r.map(|_| ()))
+        );
+    }
+    let result = files.authorize_download("folder", "guesser", Some(&first));
+    assert_eq!(
+        Some(Err(Refusal::TooManyAttempts)),
+        result.map(|r| r.map(|_| ()))
+    );
+    for i in 3..10 {
+        files.authorize_download("folder", &format!("guesser {}", i), Some("wrong"));
+    }
+    assert_ne!(first, password(&files));
+    let result = files.authorize_download("folder", "other", Some(&first));
+    assert_eq!(
+        Some(Err(Refusal::WrongPassword)),
+        result.map(|r| r.map(|_| ()))
+    );
+    let second = password(&files);
+    assert!(files
+        .authorize_download("folder", "other", Some(&second))
+        .unwrap()
+        .is_ok());
+}
+
+#[tokio::test]
+async fn download_not_found() {
+    let port = 17898;
+    let files = Arc::new(Files::default());
+    let server_identity = Arc::new(Identity::generate().unwrap());
+    let remote_file = remote_file(port, "missing", server_identity.fingerprint());
+    let identity = Identity::generate().unwrap();
+
+    tokio::spawn(async move {
+        run_file_server(port, files, server_identity, Arc::new(Semaphore::new(4)))
+            .await
+            .unwrap();
+    });
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let output = temp_dir();
+    let result = download(
+        remote_file,
+        &output,
+        None,
+        &mut ResumeState::default(),
+        &identity,
+        |_| {},
+    )
+    .await;
+    let error = result.unwrap_err().downcast::<ErrorResponse>().unwrap();
+    assert_eq!(ErrorResponse::NotFound, error);
+}
+
+#[tokio::test]
+async fn protocol_version() {
+    let (mut client, mut server) = tokio::io::duplex(1024);
+    let request = ClientRequest {
+        version: PROTOCOL_VERSION + 1,
+        capabilities: vec![],
+        request: Request::Pair,
+    };
+    protocol::write_frame(&mut client, &request).await.unwrap();
+    let result = protocol::read_request(&mut server).await.unwrap();
+    assert_eq!(
+        Some(ErrorResponse::UnsupportedVersion(PROTOCOL_VERSION)),
+        result.err()
+    );
+
+    protocol::write_request(&mut client, Request::Pair)
+        .await
+        .unwrap();
+    let result = protocol::read_request(&mut server).await.unwrap();
+    let request = result.unwrap();
+    assert!(matches!(request.request, Request::Pair));
+    assert_eq!(CAPABILITIES, request.capabilities.as_slice());
+}
+
+#[tokio::test]
+async fn discovery_old_version() {
+    let port = 17899;
+    let (remote_files_tx, mut remote_files_rx) = watch::channel(Arc::new(vec![]));
+    tokio::spawn(async move {
+        let identity = Arc::new(Identity::generate().unwrap());
+        run_discovery_receiver(
+            &remote_files_tx,
+            &watch::channel(Arc::new(vec![])).0,
+            port,
+            &[IPV4_MULTICAST_ADDR.into()],
+            &[],
+            identity,
+        )
+        .await
+        .unwrap();
+    });
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    // A packet of a peer from before versions were advertised.
+    let socket = tokio::net::UdpSocket::bind((Ipv4Addr::LOCALHOST, 0))
+        .await
+        .unwrap();
+    socket
+        .send_to(br#"{"files":["old"]}"#, (Ipv4Addr::LOCALHOST, port))
+        .await
+        .unwrap();
+
+    remote_files_rx.changed().await.unwrap();
+    let remote_files = (*remote_files_rx.borrow_and_update()).clone();
+    assert_eq!(1, remote_files.len());
+    assert_eq!(0, remote_files[0].version);
+    assert_eq!(None, remote_files[0].meta);
+    assert_eq!(
+        remote_files[0].addrs[0].to_string(),
+        remote_files[0].device_id
+    );
+    assert!(remote_files[0].update_required().is_some());
+}
+
+#[tokio::test]
+async fn discovery_ignores_spoofed_device_id() {
+    let port = 17913;
+    let (remote_files_tx, mut remote_files_rx) = watch::channel(Arc::new(vec![]));
+    tokio::spawn(async move {
+        let identity = Arc::new(Identity::generate().unwrap());
+        run_discovery_receiver(
+            &remote_files_tx,
+            &watch::channel(Arc::new(vec![])).0,
+            port,
+            &[IPV4_MULTICAST_ADDR.into()],
+            &[],
+            identity,
+        )
+        .await
+        .unwrap();
+    });
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let socket = tokio::net::UdpSocket::bind((Ipv4Addr::LOCALHOST, 0))
+        .await
+        .unwrap();
+    let packet =
+        br#"{"device_id":"device","device_name":"Real","files":["real"],"fingerprint":"real"}"#;
+    socket
+        .send_to(packet, (Ipv4Addr::LOCALHOST, port))
+        .await
+        .unwrap();
+    wait_for_remote_files(&mut remote_files_rx, |r| !r.is_empty()).await;
+
+    // Another sender that claims the id with its own key does not replace the files of the device.
+    let spoofer = tokio::net::UdpSocket::bind((Ipv4Addr::LOCALHOST, 0))
+        .await
+        .unwrap();
+    let packet =
+        br#"{"device_id":"device","device_name":"Fake","files":["fake"],"fingerprint":"fake"}"#;
+    spoofer
+        .send_to(packet, (Ipv4Addr::LOCALHOST, port))
+        .await
+        .unwrap();
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    let remote_files = remote_files_rx.borrow().clone();
+    assert_eq!(1, remote_files.len());
+    assert_eq!("real", remote_files[0].file);
+    assert_eq!("real", remote_files[0].fingerprint);
+    assert_eq!(1, remote_files[0].addrs.len());
+}
+
+#[tokio::test]
+async fn discovery_ipv6() {
+    let port = 17900;
+    let files = Arc::new(Files::default());
+    files.add_local_file(LocalFile::new(create_test_folder()).unwrap());
+    let fingerprint = serve(port, files.clone());
+
+    let (remote_files_tx, mut remote_files_rx) = watch::channel(Arc::new(vec![]));
+    tokio::spawn(async move {
+        let identity = Arc::new(Identity::generate().unwrap());
+        run_discovery_receiver(
+            &remote_files_tx,
+            &watch::channel(Arc::new(vec![])).0,
+            port,
+            &[IPV4_MULTICAST_ADDR.into(), IPV6_MULTICAST_ADDR.into()],
+            &[],
+            identity,
+        )
+        .await
+        .unwrap();
+    });
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let (local_files_rx, device_rx) = (files.get_local_files(), files.get_device());
+    tokio::spawn(async move {
+        run_discovery_sender(
+            local_files_rx,
+            device_rx,
+            SocketAddr::from((Ipv6Addr::LOCALHOST, port)),
+            &[],
+            fingerprint,
+        )
+        .await
+        .unwrap();
+    });
+
+    let remote_files = wait_for_remote_files(&mut remote_files_rx, |r| !r.is_empty()).await;
+    assert_eq!(1, remote_files.len());
+    assert_eq!(
+        SocketAddr::from((Ipv6Addr::LOCALHOST, port)),
+        remote_files[0].addrs[0]
+    );
+}
+
+#[tokio::test]
+async fn download_ipv6() {
+    let port = 17901;
+    let dir = create_test_folder();
+    let files = Arc::new(Files::default());
+    files.add_local_file(LocalFile::new(dir.clone()).unwrap());
+
+    let server_identity = Arc::new(Identity::generate().unwrap());
+    let mut remote_file = remote_file(port, "folder", server_identity.fingerprint());
+    // The first address does not reach the peer, so the download has to fall back to the second.
+    remote_file.addrs = vec![
+        SocketAddr::from((Ipv4Addr::LOCALHOST, 1)),
+        SocketAddr::from((Ipv6Addr::LOCALHOST, port)),
+    ];
+    let identity = Identity::generate().unwrap();
+
+    tokio::spawn(async move {
+        run_file_server(port, files, server_identity, Arc::new(Semaphore::new(4)))
+            .await
+            .unwrap();
+    });
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let output = temp_dir();
+    download(
+        remote_file,
+        &output,
+        None,
+        &mut ResumeState::default(),
+        &identity,
+        |_| {},
+    )
+    .await
+    .unwrap();
+    assert_eq!(
+        b"hello".to_vec(),
+        std::fs::read(output.join("folder").join("small")).unwrap()
+    );
+}
+
+#[test]
+fn transient_errors() {
+    use color_eyre::eyre::{eyre, Report, WrapErr};
+    let io_error = |kind| {
+        Err::<(), _>(std::io::Error::from(kind))
+            .wrap_err("failed to unpack tar")
+            .unwrap_err()
+    };
+    // Only a busy peer and a failed connection are worth another attempt.
+    assert!(server::is_transient(&io_error(
+        std::io::ErrorKind::ConnectionReset
+    )));
+    assert!(server::is_transient(&io_error(
+        std::io::ErrorKind::TimedOut
+    )));
+    assert!(server::is_transient(&ErrorResponse::Busy.into_report()));
+    assert!(!server::is_transient(&io_error(
+        std::io::ErrorKind::InvalidData
+    )));
+    assert!(!server::is_transient(
+        &ErrorResponse::BadRequest.into_report()
+    ));
+    assert!(!server::is_transient(&Report::new(Refusal::NotAllowed)));
+    assert!(!server::is_transient(&eyre!(
+        "peer key does not match the advertised fingerprint"
+    )));
+}
+
+#[tokio::test]
+async fn connect_prefers_reached_address() {
+    let closed = tokio::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0))
+        .await
+        .unwrap();
+    let first = closed.local_addr().unwrap();
+    drop(closed);
+    let listener = tokio::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0))
+        .await
+        .unwrap();
+    let second = listener.local_addr().unwrap();
+    let addrs = [first, second];
+
+    let stream = super::connect(&addrs).await.unwrap();
+    assert_eq!(second, stream.peer_addr().unwrap());
+    // Once the address that did not answer is back, the address that answered is still tried first.
+    let _listener = tokio::net::TcpListener::bind(first).await.unwrap();
+    let stream = super::connect(&addrs).await.unwrap();
+    assert_eq!(second, stream.peer_addr().unwrap());
+}
+
+#[test]
+fn mdns_txt_record() {
+    let device = Device::generate();
+    let local_files = vec![LocalFile::new(create_test_folder()).unwrap()];
+    let properties = mdns::txt_properties(&device, &local_files, "fingerprint");
+    let info = mdns_sd::ServiceInfo::new(
+        mdns::SERVICE_TYPE,
+        &device.id,
+        "host.local.",
+        "192.168.0.2",
+        17902,
+        properties,
+    )
+    .unwrap();
+
+    let remote_files = mdns::remote_files(&info, &[]);
+    assert_eq!(1, remote_files.len());
+    assert_eq!("folder", remote_files[0].file);
+    assert_eq!(
+        SocketAddr::from(([192, 168, 0, 2], 17902)),
+        remote_files[0].addrs[0]
+    );
+    assert_eq!(device.id, remote_files[0].device_id);
+    assert_eq!(device.name, remote_files[0].device_name);
+    assert_eq!("fingerprint", remote_files[0].fingerprint);
+    assert_eq!(Some(local_files[0].meta().clone()), remote_files[0].meta);
+    assert!(remote_files[0].is_compatible());
+    assert_eq!(CAPABILITIES.to_vec(), remote_files[0].capabilities);
+}
+
+#[test]
+fn mdns_txt_record_limit() {
+    let device = Device::generate();
+    let local_files: Vec<LocalFile> = (0..100)
+        .map(|i| LocalFile::Path {
+            path: PathBuf::new(),
+            name: format!("file{}", i),
+            meta: FileMeta::default(),
+            policy: SharePolicy::Public,
+        })
+        .collect();
+    let properties = mdns::txt_properties(&device, &local_files, "fingerprint");
+    let info = mdns_sd::ServiceInfo::new(
+        mdns::SERVICE_TYPE,
+        &device.id,
+        "host.local.",
+        "192.168.0.2",
+        17902,
+        properties,
+    )
+    .unwrap();
+
+    let remote_files = mdns::remote_files(&info, &[]);
+    assert!(!remote_files.is_empty());
+    assert!(remote_files.len() < local_files.len());
+    assert_eq!("file0", remote_files[0].file);
+    // Browsers fetch all of the files by the revision.
+    let listing = protocol::Listing::new(device, &local_files);
+    assert_eq!(Some(listing.revision), mdns::revision(&info));
+}
+
+#[tokio::test]
+async fn discovery_interface() {
+    let port = 17902;
+    let files = Arc::new(Files::default());
+    files.add_local_file(LocalFile::new(create_test_folder()).unwrap());
+    let fingerprint = serve(port, files.clone());
+    let interfaces = vec![Interface {
+        name: String::from("test0"),
+        index: 1,
+        addrs: vec![(
+            Ipv4Addr::LOCALHOST.into(),
+            Some(Ipv4Addr::new(255, 0, 0, 0).into()),
+        )],
+    }];
+
+    let (remote_files_tx, mut remote_files_rx) = watch::channel(Arc::new(vec![]));
+    tokio::spawn(async move {
+        let identity = Arc::new(Identity::generate().unwrap());
+        run_discovery_receiver(
+            &remote_files_tx,
+            &watch::channel(Arc::new(vec![])).0,
+            port,
+            &[IPV4_MULTICAST_ADDR.into()],
+            &interfaces,
+            identity,
+        )
+        .await
+        .unwrap();
+    });
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let (local_files_rx, device_rx) = (files.get_local_files(), files.get_device());
+    tokio::spawn(async move {
+        run_discovery_sender(
+            local_files_rx,
+            device_rx,
+            SocketAddr::from((Ipv4Addr::LOCALHOST, port)),
+            &[],
+            fingerprint,
+        )
+        .await
+        .unwrap();
+    });
+
+    let remote_files = wait_for_remote_files(&mut remote_files_rx, |r| !r.is_empty()).await;
+    assert_eq!(1, remote_files.len());
+    assert_eq!(Some(String::from("test0")), remote_files[0].interface);
+}
+
+#[test]
+fn find_interface() {
+    let interfaces = vec![
+        Interface {
+            name: String::from("eth0"),
+            index: 2,
+            addrs: vec![
+                (
+                    Ipv4Addr::new(192, 168, 1, 10).into(),
+                    Some(Ipv4Addr::new(255, 255, 255, 0).into()),
+                ),
+                (
+                    "fe80::1".parse().unwrap(),
+                    Some("ffff:ffff:ffff:ffff::".parse().unwrap()),
+                ),
+            ],
+        },
+        Interface {
+            name: String::from("tun0"),
+            index: 3,
+            addrs: vec![
+                (
+                    Ipv4Addr::new(10, 8, 0, 2).into(),
+                    Some(Ipv4Addr::new(255, 255, 0, 0).into()),
+                ),
+                (
+                    "fe80::2".parse().unwrap(),
+                    Some("ffff:ffff:ffff:ffff::".parse().unwrap()),
+                ),
+            ],
+        },
+    ];
+    let find =
+        |addr: &str| interfaces::find(&interfaces, &addr.parse().unwrap()).map(|i| i.name.as_str());
+    assert_eq!(Some("eth0"), find("192.168.1.20:17671"));
+    assert_eq!(Some("tun0"), find("10.8.3.4:17671"));
+    assert_eq!(None, find("172.16.0.1:17671"));
+    // Both interfaces have the link-local subnet, so the scope decides.
+    assert_eq!(Some("tun0"), find("[fe80::9%3]:17671"));
+}
+
+#[tokio::test]
+async fn manual_peer() {
+    let port = 17903;
+    let files = Arc::new(Files::default());
+    files.add_local_file(LocalFile::new(create_test_folder()).unwrap());
+    let device = files.get_device().borrow().clone();
+    let server_identity = Arc::new(Identity::generate().unwrap());
+    let fingerprint = server_identity.fingerprint();
+    tokio::spawn(async move {
+        run_file_server(port, files, server_identity, Arc::new(Semaphore::new(4)))
+            .await
+            .unwrap();
+    });
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let identity = Identity::generate().unwrap();
+    // Without a port the default port is used.
+    let polled = peers::list(&identity, "127.0.0.1", port).await.unwrap();
+    assert_eq!(1, polled.len());
+    assert_eq!("folder", polled[0].file);
+    assert_eq!(device.id, polled[0].device_id);
+    assert_eq!(device.name, polled[0].device_name);
+    assert_eq!(fingerprint, polled[0].fingerprint);
+    assert_eq!(
+        SocketAddr::from((Ipv4Addr::LOCALHOST, port)),
+        polled[0].addrs[0]
+    );
+    assert!(polled[0].meta.is_some());
+
+    // A peer that is both discovered and polled is listed once.
+    let files = Files::default();
+    let discovered = remote_file(port, "other", String::from("fingerprint"));
+    files
+        .discovered_files_tx
+        .send_replace(Arc::new(vec![discovered.clone()]));
+    files
+        .polled_files_tx
+        .send_replace(Arc::new(vec![polled[0].clone(), discovered]));
+    files.merge_remote_files();
+    assert_eq!(2, files.get_remote_files().borrow().len());
+
+    assert!(peers::list(&identity, "127.0.0.1:1", port).await.is_err());
+}
+
+#[tokio::test]
+async fn listing() {
+    let port = 17904;
+    let files = Arc::new(Files::default());
+    files.add_local_file(LocalFile::new(create_test_folder()).unwrap());
+    let fingerprint = serve(port, files.clone());
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let identity = Identity::generate().unwrap();
+    let addrs = [SocketAddr::from((Ipv4Addr::LOCALHOST, port))];
+    let (_, remote_fingerprint, listing) = peers::fetch_listing(&identity, &addrs).await.unwrap();
+    assert_eq!(fingerprint, remote_fingerprint);
+    assert_eq!(files.get_device().borrow().id, listing.device.id);
+    assert_eq!(1, listing.files.len());
+    assert_eq!(listing_revision(&listing.files), listing.revision);
+
+    let local_files = files.get_local_files().borrow().clone();
+    files.remove_local_file(&local_files[0]);
+    let (_, _, changed) = peers::fetch_listing(&identity, &addrs).await.unwrap();
+    assert!(changed.files.is_empty());
+    assert_ne!(listing.revision, changed.revision);
+}
+
+#[tokio::test]
+async fn idle_connections_time_out() {
+    let port = 17912;
+    let files = Arc::new(Files::default());
+    serve(port, files);
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    // Clients that never send a request take up every connection until they time out.
+    let addr = SocketAddr::from((Ipv4Addr::LOCALHOST, port));
+    let mut idle = vec![];
+    for _ in 0..16 {
+        idle.push(tokio::net::TcpStream::connect(addr).await.unwrap());
+    }
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    let identity = Identity::generate().unwrap();
+    let result = peers::fetch_listing(&identity, &[addr]).await;
+    assert_eq!(
+        ErrorResponse::Busy,
+        result.unwrap_err().downcast::<ErrorResponse>().unwrap()
+    );
+
+    tokio::time::sleep(Duration::from_secs(11)).await;
+    peers::fetch_listing(&identity, &[addr]).await.unwrap();
+}
+
+/// Files with long names, more than fit in a datagram.
+fn many_files(count: usize) -> Vec<LocalFile> {
+    (0..count)
+        .map(|i| {
+            let name = format!("{:0>150}", i);
+            LocalFile::Path {
+                path: PathBuf::from(&name),
+                name,
+                meta: FileMeta {
+                    size: i as u64,
+                    is_dir: false,
+                    file_count: 1,
+                    modified: Some(1_600_000_000),
+                    is_text: false,
+                },
+                policy: SharePolicy::Public,
+            }
+        })
+        .collect()
+}
+
+#[tokio::test]
+async fn discovery_large_share_list() {
+    let port = 17905;
+    let files = Arc::new(Files::default());
+    // More than fit in one frame of the listing.
+    for local_file in many_files(5000) {
+        files.add_local_file(local_file);
+    }
+    let fingerprint = serve(port, files.clone());
+
+    let (local_files_rx, device_rx) = (files.get_local_files(), files.get_device());
+    tokio::spawn(async move {
+        run_discovery_sender(
+            local_files_rx,
+            device_rx,
+            SocketAddr::from((Ipv4Addr::LOCALHOST, port)),
+            &[],
+            fingerprint,
+        )
+        .await
+        .unwrap();
+    });
+
+    let (remote_files_tx, mut remote_files_rx) = watch::channel(Arc::new(vec![]));
+    tokio::spawn(async move {
+        let identity = Arc::new(Identity::generate().unwrap());
+        run_discovery_receiver(
+            &remote_files_tx,
+            &watch::channel(Arc::new(vec![])).0,
+            port,
+            &[IPV4_MULTICAST_ADDR.into()],
+            &[],
+            identity,
+        )
+        .await
+        .unwrap();
+    });
+
+    let remote_files = wait_for_remote_files(&mut remote_files_rx, |r| !r.is_empty()).await;
+    assert_eq!(5000, remote_files.len());
+    let local_files = files.get_local_files().borrow().clone();
+    for (remote_file, local_file) in remote_files.iter().zip(&local_files) {
+        assert_eq!(local_file.name(), remote_file.file);
+        assert_eq!(Some(local_file.meta()), remote_file.meta.as_ref());
+    }
+}
+
+#[test]
+fn beacon_size() {
+    let mut device = Files::default().get_device().borrow().clone();
+    device.name = "ä".repeat(2000);
+
+    let beacon = discovery::beacon(&device, &many_files(8000), "fingerprint").unwrap();
+    assert!(beacon.len() <= discovery::MAX_PACKET_LEN);
+    let packet: serde_json::Value = serde_json::from_slice(&beacon).unwrap();
+    assert!(packet["device_name"].as_str().unwrap().starts_with("ää"));
+}
+
+/// Builds a tar archive with the given paths, kinds and link targets, written as they are since
+/// builders refuse to write unsafe paths.
+async fn raw_archive(entries: &[(&str, EntryType, &str)]) -> Vec<u8> {
+    let mut builder = tokio_tar::Builder::new(vec![]);
+    for (path, kind, link) in entries {
+        let data: &[u8] = if kind.is_file() { b"hello" } else { b"" };
+        let mut header = tokio_tar::Header::new_old();
+        header.as_old_mut().name[..path.len()].copy_from_slice(path.as_bytes());
+        header.as_old_mut().linkname[..link.len()].copy_from_slice(link.as_bytes());
+        header.set_entry_type(*kind);
+        header.set_size(data.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+        builder.append(&header, data).await.unwrap();
+    }
+    builder.into_inner().await.unwrap()
+}
+
+async fn unpack_raw(
+    dst: &Path,
+    entries: &[(&str, EntryType, &str)],
+    conflict: ConflictPolicy,
+) -> std::io::Result<()> {
+    let archive = raw_archive(entries).await;
+    let target = archive::Target::new(dst, "folder", "test", conflict)
+        .await
+        .unwrap();
+    let result = archive::unpack(
+        archive.as_slice(),
+        &target,
+        &HashMap::new(),
+        &mut HashSet::new(),
+        None,
+    )
+    .await;
+    match result {
+        Ok(()) => target.finish().await,
+        Err(err) => {
+            target.discard().await.unwrap();
+            Err(err)
+        }
+    }
+}
+
+#[tokio::test]
+async fn unpack_rejects_escaping_entries() {
+    let dir = ("folder", EntryType::Directory, "");
+    let escaping = [
+        vec![("folder/../evil", EntryType::Regular, "")],
+        vec![("/tmp/evil", EntryType::Regular, "")],
+        vec![("other/evil", EntryType::Regular, "")],
+        vec![dir, ("folder/link", EntryType::Symlink, "../evil")],
+        vec![dir, ("folder/link", EntryType::Symlink, "/tmp/evil")],
+        vec![
+            dir,
+            ("folder/a/link", EntryType::Symlink, "b/../../../evil"),
+        ],
+        // Links to the share itself make the archive path deeper than where the last link is created.
+        vec![
+            dir,
+            ("folder/a", EntryType::Symlink, "."),
+            ("folder/a/b", EntryType::Symlink, "."),
+            ("folder/a/b/link", EntryType::Symlink, "../../evil"),
+        ],
+        vec![dir, ("folder/link", EntryType::Link, "other/evil")],
+    ];
+    for entries in escaping {
+        let dst = temp_dir();
+        assert!(
+            unpack_raw(&dst, &entries, ConflictPolicy::Rename)
+                .await
+                .is_err(),
+            "{:?}",
+            entries
+        );
+        assert!(!dst.join("evil").exists());
+        assert_eq!(0, std::fs::read_dir(&dst).unwrap().count());
+    }
+
+    // Links inside the share are unpacked.
+    let dst = temp_dir();
+    let entries = [
+        dir,
+        ("folder/a", EntryType::Regular, ""),
+        ("folder/b/link", EntryType::Symlink, "../a"),
+    ];
+    unpack_raw(&dst, &entries, ConflictPolicy::Rename)
+        .await
+        .unwrap();
+    assert_eq!(
+        b"hello".to_vec(),
+        std::fs::read(dst.join("folder").join("b").join("link")).unwrap()
+    );
+
+    // A link that already exists is kept or replaced, but never written through.
+    for conflict in [ConflictPolicy::Skip, ConflictPolicy::Overwrite] {
+        let dst = temp_dir();
+        let outside = temp_dir();
+        std::os::unix::fs::symlink(&outside, dst.join("folder")).unwrap();
+        let entries = [("folder/evil", EntryType::Regular, "")];
+        unpack_raw(&dst, &entries, conflict).await.unwrap();
+        assert!(!outside.join("evil").exists());
+        let is_link = std::fs::symlink_metadata(dst.join("folder"))
+            .unwrap()
+            .is_symlink();
+        assert_eq!(conflict == ConflictPolicy::Skip, is_link);
+    }
+
+    // A link in the staging folder that leads outside is refused before folders are created through it.
+    let dst = temp_dir();
+    let outside = temp_dir();
+    std::fs::create_dir(dst.join(".folder.test.shary-partial")).unwrap();
+    std::os::unix::fs::symlink(&outside, dst.join(".folder.test.shary-partial").join("out"))
+        .unwrap();
+    let entries = [("folder/out/new/evil", EntryType::Regular, "")];
+    assert!(unpack_raw(&dst, &entries, ConflictPolicy::Rename)
+        .await
+        .is_err());
+    assert!(!outside.join("new").exists());
+}
+
+#[tokio::test]
+async fn concurrent_targets() {
+    let dst = temp_dir();
+    let first = archive::Target::new(&dst, "folder", "first", ConflictPolicy::Rename)
+        .await
+        .unwrap();
+    let second = archive::Target::new(&dst, "folder", "second", ConflictPolicy::Rename)
+        .await
+        .unwrap();
+    assert_eq!("folder", first.root);
+    assert_eq!("folder (1)", second.root);
+    assert_ne!(first.staging(), second.staging());
+
+    // The name is free again once the first download is over.
+    drop(first);
+    let third = archive::Target::new(&dst, "folder", "third", ConflictPolicy::Rename)
+        .await
+        .unwrap();
+    assert_eq!("folder", third.root);
+    assert!(
+        archive::Target::new(&dst, "folder", "../x", ConflictPolicy::Rename)
+            .await
+            .is_err()
+    );
+}
+
+#[tokio::test]
+async fn unpack_limit() {
+    let entries = [
+        ("folder", EntryType::Directory, ""),
+        ("folder/a", EntryType::Regular, ""),
+        ("folder/b", EntryType::Regular, ""),
+    ];
+    let archive = raw_archive(&entries).await;
+    let unpack = |size, file_count| {
+        let archive = archive.clone();
+        async move {
+            let target =
+                archive::Target::new(&temp_dir(), "folder", "test", ConflictPolicy::Rename)
+                    .await
+                    .unwrap();
+            let limit = archive::Limit { size, file_count };
+            archive::unpack(
+                archive.as_slice(),
+                &target,
+                &HashMap::new(),
+                &mut HashSet::new(),
+                Some(limit),
+            )
+            .await
+        }
+    };
+    unpack(10, 2).await.unwrap();
+    // More bytes or more files than were announced are refused.
+    assert!(unpack(9, 2).await.is_err());
+    assert!(unpack(10, 1).await.is_err());
+
+    // The overhead does not grow without bound with the file count the peer announces.
+    let limit = |file_count| archive::Limit {
+        size: 0,
+        file_count,
+    };
+    assert!(limit(u64::MAX).archive_size() < 2_000_000_000);
+    assert!(limit(1).archive_size() < limit(2).archive_size());
+}
+
+#[tokio::test]
+async fn unpack_conflict_policy() {
+    let entries = [
+        ("folder", EntryType::Directory, ""),
+        ("folder/a", EntryType::Regular, ""),
+        ("folder/b", EntryType::Regular, ""),
+    ];
+    let existing = || {
+        let dst = temp_dir();
+        std::fs::create_dir(dst.join("folder")).unwrap();
+        std::fs::write(dst.join("folder").join("a"), b"mine").unwrap();
+        dst
+    };
+
+    let dst = existing();
+    unpack_raw(&dst, &entries, ConflictPolicy::Rename)
+        .await
+        .unwrap();
+    assert_eq!(
+        b"mine".to_vec(),
+        std::fs::read(dst.join("folder").join("a")).unwrap()
+    );
+    assert_eq!(
+        b"hello".to_vec(),
+        std::fs::read(dst.join("folder (1)").join("a")).unwrap()
+    );
+
+    let dst = existing();
+    unpack_raw(&dst, &entries, ConflictPolicy::Skip)
+        .await
+        .unwrap();
+    assert_eq!(
+        b"mine".to_vec(),
+        std::fs::read(dst.join("folder").join("a")).unwrap()
+    );
+    assert_eq!(
+        b"hello".to_vec(),
+        std::fs::read(dst.join("folder").join("b")).unwrap()
+    );
+
+    let dst = existing();
+    unpack_raw(&dst, &entries, ConflictPolicy::Overwrite)
+        .await
+        .unwrap();
+    assert_eq!(
+        b"hello".to_vec(),
+        std::fs::read(dst.join("folder").join("a")).unwrap()
+    );
+}
+
+#[tokio::test]
+async fn download_queue() {
+    let port = 17907;
+    let dir = temp_dir();
+    let server_files = Arc::new(Files::default());
+    let names = ["a.txt", "b.txt", "c.txt"];
+    for name in names {
+        std::fs::write(dir.join(name), name).unwrap();
+        server_files.add_local_file(LocalFile::new(dir.join(name)).unwrap());
+    }
+    let fingerprint = serve(port, server_files);
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    // Only one download runs at a time, the others wait in the queue instead of being dropped.
+    let files = Arc::new(Files::default());
+    let identity = Arc::new(Identity::generate().unwrap());
+    tokio::spawn(run_file_download(
+        Arc::clone(&files),
+        identity,
+        Arc::new(Semaphore::new(1)),
+    ));
+    let output = temp_dir();
+    let remote_files: Vec<_> = names
+        .iter()
+        .map(|name| remote_file(port, name, fingerprint.clone()))
+        .collect();
+    let mut statuses = files.get_download_statuses();
+    for remote_file in remote_files.iter() {
+        files.add_download(Download {
+            remote_file: remote_file.clone(),
+            path: output.clone(),
+            password: None,
+            conflict: ConflictPolicy::default(),
+        });
+    }
+    let wait = async {
+        loop {
+            let completed = remote_files.iter().all(|remote_file| {
+                matches!(
+                    statuses.borrow_and_update().get(remote_file),
+                    Some(DownloadStatus::Completed(_))
+                )
+            });
+            if completed {
+                break;
+            }
+            statuses.changed().await.unwrap();
+        }
+    };
+    tokio::time::timeout(Duration::from_secs(10), wait)
+        .await
+        .unwrap();
+    for name in names {
+        assert_eq!(name.as_bytes(), std::fs::read(output.join(name)).unwrap());
+    }
+}
+
+/// Waits until the status of the download is what `f` is waiting for.
+async fn wait_for_download_status(
+    files: &Files,
+    remote_file: &RemoteFile,
+    f: impl Fn(&DownloadStatus) -> bool,
+) {
+    let mut statuses = files.get_download_statuses();
+    let wait = async {
+        while !statuses
+            .borrow_and_update()
+            .get(remote_file)
+            .is_some_and(&f)
+        {
+            statuses.changed().await.unwrap();
+        }
+    };
+    tokio::time::timeout(Duration::from_secs(10), wait)
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn download_control() {
+    let port = 17908;
+    let dir = temp_dir();
+    let content: Vec<u8> = (0..4_000_000u32).map(|i| (i % 251) as u8).collect();
+    std::fs::write(dir.join("large"), &content).unwrap();
+    let server_files = Arc::new(Files::default());
+    server_files.add_local_file(LocalFile::new(dir.join("large")).unwrap());
+    let fingerprint = serve(port, server_files);
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let files = Arc::new(Files::default());
+    let identity = Arc::new(Identity::generate().unwrap());
+    let downloads = Arc::new(Semaphore::new(0));
+    tokio::spawn(run_file_download(
+        Arc::clone(&files),
+        identity,
+        Arc::clone(&downloads),
+    ));
+    let output = temp_dir();
+    let remote_file = remote_file(port, "large", fingerprint);
+    let add_download = || {
+        files.add_download(Download {
+            remote_file: remote_file.clone(),
+            path: output.clone(),
+            password: None,
+            conflict: ConflictPolicy::default(),
+        })
+    };
+
+    // A queued download can be cancelled before it starts.
+    add_download();
+    wait_for_download_status(&files, &remote_file, |s| *s == DownloadStatus::Queued).await;
+    files.cancel_download(&remote_file);
+    wait_for_download_status(&files, &remote_file, |s| *s == DownloadStatus::Cancelled).await;
+    assert_eq!(
+        TransferOutcome::Cancelled,
+        files.get_history().borrow()[0].outcome
+    );
+
+    // A paused download keeps what it has received and completes when it is resumed.
+    downloads.add_permits(1);
+    add_download();
+    wait_for_download_status(
+        &files,
+        &remote_file,
+        |s| matches!(s, DownloadStatus::Running(p) if p.received > 0),
+    )
+    .await;
+    files.pause_download(&remote_file);
+    wait_for_download_status(&files, &remote_file, |s| {
+        matches!(s, DownloadStatus::Paused(_))
+    })
+    .await;
+    assert!(staging(&output, &remote_file).exists());
+    files.resume_download(&remote_file);
+    wait_for_download_status(&files, &remote_file, |s| {
+        matches!(s, DownloadStatus::Completed(_))
+    })
+    .await;
+    assert_eq!(content, std::fs::read(output.join("large")).unwrap());
+
+    // Both downloads are kept in the history.
+    let history = files.get_history().borrow().clone();
+    assert_eq!(2, history.len());
+    assert_eq!(TransferOutcome::Completed, history[1].outcome);
+    assert_eq!(output.join("large"), history[1].path);
+    assert_eq!(
+        Some(hashing::hash_file(&dir.join("large")).await.unwrap()),
+        history[1].hash
+    );
+
+    // A download that fails before it starts is kept as well.
+    let mut incompatible = remote_file.clone();
+    incompatible.version = PROTOCOL_VERSION + 1;
+    files.add_download(Download {
+        remote_file: incompatible.clone(),
+        path: output.clone(),
+        password: None,
+        conflict: ConflictPolicy::default(),
+    });
+    wait_for_download_status(&files, &incompatible, |s| {
+        matches!(s, DownloadStatus::Failed(_))
+    })
+    .await;
+    let history = files.get_history().borrow().clone();
+    assert_eq!(3, history.len());
+    assert!(matches!(history[2].outcome, TransferOutcome::Failed(_)));
+}
+
+/// Waits until the offers are what `f` is waiting for.
+async fn wait_for_offer(files: &Files, f: impl Fn(&Offer) -> bool) -> Offer {
+    let mut offers = files.get_offers();
+    let wait = async {
+        loop {
+            if let Some(offer) = offers.borrow_and_update().iter().find(|o| f(o)) {
+                return offer.clone();
+            }
+            offers.changed().await.unwrap();
+        }
+    };
+    tokio::time::timeout(Duration::from_secs(10), wait)
+        .await
+        .unwrap()
+}
+
+#[tokio::test]
+async fn offer() {
+    let port = 17909;
+    let receiver_files = Arc::new(Files::default());
+    let receiver_identity = Arc::new(Identity::generate().unwrap());
+    let fingerprint = receiver_identity.fingerprint();
+    let downloads = Arc::new(Semaphore::new(0));
+    tokio::spawn(run_file_server(
+        port,
+        Arc::clone(&receiver_files),
+        receiver_identity,
+        Arc::clone(&downloads),
+    ));
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    let files = Arc::new(Files::default());
+    tokio::spawn(run_outgoing_offers(
+        Arc::clone(&files),
+        Arc::new(Identity::generate().unwrap()),
+    ));
+    let dir = create_test_folder();
+    let outgoing = OutgoingOffer {
+        peer: String::from("Receiver"),
+        addrs: vec![SocketAddr::from((Ipv4Addr::LOCALHOST, port))],
+        fingerprint: Some(fingerprint),
+        local_file: LocalFile::new(dir.clone()).unwrap(),
+    };
+
+    // A declined offer is not sent.
+    files.offer_file(outgoing.clone());
+    let offer = wait_for_offer(&receiver_files, |o| o.status == OfferStatus::Pending).await;
+    assert_eq!("folder", offer.file);
+    receiver_files.decide_offer(offer.id, None);
+    let sent = wait_for_offer(&files, |o| o.status.is_finished()).await;
+    assert_eq!(OfferStatus::Declined, sent.status);
+    files.remove_offer(sent.id);
+
+    // An accepted offer waits in the download queue, where it can be cancelled.
+    let output = temp_dir();
+    files.offer_file(outgoing.clone());
+    let offer = wait_for_offer(&receiver_files, |o| o.status == OfferStatus::Pending).await;
+    receiver_files.decide_offer(offer.id, Some((output.clone(), ConflictPolicy::default())));
+    wait_for_offer(&receiver_files, |o| o.status == OfferStatus::Queued).await;
+    receiver_files.cancel_offer(offer.id);
+    let received = wait_for_offer(&receiver_files, |o| o.status.is_finished()).await;
+    assert_eq!(OfferStatus::Cancelled, received.status);
+    receiver_files.remove_offer(received.id);
+    let sent = wait_for_offer(&files, |o| o.status.is_finished()).await;
+    assert_eq!(OfferStatus::Declined, sent.status);
+    files.remove_offer(sent.id);
+
+    // Only two offers of a peer may wait for a decision.
+    for _ in 0..3 {
+        files.offer_file(outgoing.clone());
+    }
+    let sent = wait_for_offer(&files, |o| o.status.is_finished()).await;
+    assert!(
+        matches!(sent.status, OfferStatus::Failed(ref msg) if msg.contains("busy")),
+        "{:?}",
+        sent.status
+    );
+    files.remove_offer(sent.id);
+    for _ in 0..2 {
+        let offer = wait_for_offer(&receiver_files, |o| o.status == OfferStatus::Pending).await;
+        receiver_files.decide_offer(offer.id, None);
+        let sent = wait_for_offer(&files, |o| o.status.is_finished()).await;
+        files.remove_offer(sent.id);
+    }
+    assert!(receiver_files
+        .get_offers()
+        .borrow()
+        .iter()
+        .all(|o| o.status.is_finished()));
+    for offer in receiver_files.get_offers().borrow().clone() {
+        receiver_files.remove_offer(offer.id);
+    }
+
+    // An accepted offer is sent like a download and verified once a download permit is free.
+    files.offer_file(outgoing.clone());
+    let offer = wait_for_offer(&receiver_files, |o| o.status == OfferStatus::Pending).await;
+    receiver_files.decide_offer(offer.id, Some((output.clone(), ConflictPolicy::default())));
+    wait_for_offer(&receiver_files, |o| o.status == OfferStatus::Queued).await;
+    downloads.add_permits(1);
+    let received = wait_for_offer(&receiver_files, |o| o.status.is_finished()).await;
+    assert!(matches!(received.status, OfferStatus::Completed(Some(_))));
+    let sent = wait_for_offer(&files, |o| o.status.is_finished()).await;
+    assert!(matches!(sent.status, OfferStatus::Completed(_)));
+    let nested = output
+        .join("folder")
+        .join("n".repeat(60))
+        .join("e".repeat(60));
+    assert_eq!(
+        vec![7u8; 100_000],
+        std::fs::read(nested.join("large")).unwrap()
+    );
+    let history = receiver_files.get_history().borrow().clone();
+    assert_eq!(
+        vec![TransferOutcome::Cancelled, TransferOutcome::Completed],
+        history
+            .iter()
+            .map(|t| t.outcome.clone())
+            .collect::<Vec<_>>()
+    );
+    files.remove_offer(sent.id);
+    receiver_files.remove_offer(received.id);
+
+    // The peer may not send more than the offer announced, like a file that grew after it was offered.
+    let grown = temp_dir().join("grown");
+    std::fs::write(&grown, b"small").unwrap();
+    files.offer_file(OutgoingOffer {
+        local_file: LocalFile::new(grown.clone()).unwrap(),
+        ..outgoing
+    });
+    let offer = wait_for_offer(&receiver_files, |o| o.status == OfferStatus::Pending).await;
+    std::fs::write(&grown, b"larger than it was offered").unwrap();
+    receiver_files.decide_offer(offer.id, Some((output.clone(), ConflictPolicy::default())));
+    let received = wait_for_offer(&receiver_files, |o| o.status.is_finished()).await;
+    assert!(
+        matches!(received.status, OfferStatus::Failed(_)),
+        "{:?}",
+        received.status
+    );
+    assert!(!output.join("grown").exists());
+}
+
+#[tokio::test]
+async fn offer_auto_accept() {
+    let port = 17910;
+    let receiver_files = Arc::new(Files::default());
+    let fingerprint = serve(port, Arc::clone(&receiver_files));
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    let files = Arc::new(Files::default());
+    // The folder is named after the name the peer had when it was paired, not the one it sends later.
+    files.set_device_name("Other");
+    let identity = Identity::generate().unwrap();
+    receiver_files.add_paired_peer(PairedPeer {
+        fingerprint: identity.fingerprint(),
+        name: String::from("../Colleague"),
+    });
+    let output = temp_dir();
+    receiver_files.set_accept_rule(AcceptRule {
+        fingerprint: identity.fingerprint(),
+        folder: output.clone(),
+        max_size: Some(1000),
+        conflict: ConflictPolicy::Skip,
+    });
+    tokio::spawn(run_outgoing_offers(Arc::clone(&files), Arc::new(identity)));
+    let offer_file = |path: PathBuf| {
+        files.offer_file(OutgoingOffer {
+            peer: String::from("Receiver"),
+            addrs: vec![SocketAddr::from((Ipv4Addr::LOCALHOST, port))],
+            fingerprint: Some(fingerprint.clone()),
+            local_file: LocalFile::new(path).unwrap(),
+        })
+    };
+
+    // A small file is stored in the folder of the peer, named without the characters that could leave it.
+    let dir = temp_dir();
+    std::fs::write(dir.join("small"), b"hello").unwrap();
+    offer_file(dir.join("small"));
+    let received = wait_for_offer(&receiver_files, |o| o.status.is_finished()).await;
+    assert!(matches!(received.status, OfferStatus::Completed(_)));
+    assert_eq!(
+        b"hello".to_vec(),
+        std::fs::read(output.join("Colleague").join("small")).unwrap()
+    );
+    receiver_files.remove_offer(received.id);
+
+    // The conflict policy of the rule keeps the existing file.
+    std::fs::write(dir.join("small"), b"world").unwrap();
+    offer_file(dir.join("small"));
+    let received = wait_for_offer(&receiver_files, |o| o.status.is_finished()).await;
+    assert!(matches!(received.status, OfferStatus::Completed(_)));
+    assert_eq!(
+        b"hello".to_vec(),
+        std::fs::read(output.join("Colleague").join("small")).unwrap()
+    );
+    receiver_files.remove_offer(received.id);
+
+    // The user is asked about a file that is larger than the limit.
+    offer_file(create_test_folder());
+    let offer = wait_for_offer(&receiver_files, |o| o.file == "folder").await;
+    assert_eq!(OfferStatus::Pending, offer.status);
+}
+
+#[tokio::test]
+async fn texts() {
+    let port = 17911;
+    let files = Arc::new(Files::default());
+    let short = String::from("https://example.com/a");
+    let long = format!("token\n{}", "x".repeat(1000));
+    files.add_local_file(LocalFile::text(short.clone()).unwrap());
+    let mut local_file = LocalFile::text(long.clone()).unwrap();
+    *local_file.policy_mut() = SharePolicy::password();
+    files.add_local_file(local_file);
+    // The same text is only shared once, texts are numbered instead of named after what they say.
+    assert!(!files.add_local_file(LocalFile::text(short.clone()).unwrap()));
+    assert!(files.add_local_file(LocalFile::text(format!("{}\nother", short)).unwrap()));
+    let names: Vec<_> = files
+        .get_local_files()
+        .borrow()
+        .iter()
+        .map(|f| f.name().to_owned())
+        .collect();
+    assert_eq!(vec!["Text 1", "Text 2", "Text 3"], names);
+    assert!(LocalFile::text(String::from(" \n ")).is_err());
+    let fingerprint = serve(port, files.clone());
+    let packet_fingerprint = fingerprint.clone();
+
+    let (local_files_rx, device_rx) = (files.get_local_files(), files.get_device());
+    tokio::spawn(async move {
+        run_discovery_sender(
+            local_files_rx,
+            device_rx,
+            SocketAddr::from((Ipv4Addr::LOCALHOST, port)),
+            &[],
+            fingerprint,
+        )
+        .await
+        .unwrap();
+    });
+    let (remote_files_tx, mut remote_files_rx) = watch::channel(Arc::new(vec![]));
+    tokio::spawn(async move {
+        let identity = Arc::new(Identity::generate().unwrap());
+        run_discovery_receiver(
+            &remote_files_tx,
+            &watch::channel(Arc::new(vec![])).0,
+            port,
+            &[IPV4_MULTICAST_ADDR.into()],
+            &[],
+            identity,
+        )
+        .await
+        .unwrap();
+    });
+    let remote_files = wait_for_remote_files(&mut remote_files_rx, |r| r.len() == 3).await;
+
+    // The short public text comes with discovery, the long one that is protected by a password does not.
+    let remote_text = |name: &str| {
+        remote_files
+            .iter()
+            .find(|r| r.file == name)
+            .unwrap()
+            .clone()
+    };
+    let short_file = remote_text("Text 1");
+    assert!(short_file.meta.as_ref().unwrap().is_text);
+    assert_eq!(Some(short.clone()), short_file.text);
+    let long_file = remote_text("Text 2");
+    assert!(long_file.meta.as_ref().unwrap().is_text);
+    assert_eq!(None, long_file.text);
+
+    let identity = Identity::generate().unwrap();
+    assert_eq!(
+        short,
+        fetch_text(&short_file, None, &identity).await.unwrap()
+    );
+    let result = fetch_text(&long_file, None, &identity).await;
+    assert_eq!(
+        Refusal::PasswordRequired,
+        result.unwrap_err().downcast::<Refusal>().unwrap()
+    );
+    let password = match files.get_local_files().borrow()[1].policy() {
+        SharePolicy::Password { password, .. } => password.clone(),
+        _ => unreachable!(),
+    };
+    assert_eq!(
+        long,
+        fetch_text(&long_file, Some(&password), &identity)
+            .await
+            .unwrap()
+    );
+
+    // Texts are not downloaded like files, the peer that used the password keeps access.
+    let output = temp_dir();
+    let result = download(
+        long_file,
+        &output,
+        None,
+        &mut ResumeState::default(),
+        &identity,
+        |_| {},
+    )
+    .await;
+    assert_eq!(
+        ErrorResponse::BadRequest,
+        result.unwrap_err().downcast::<ErrorResponse>().unwrap()
+    );
+
+    // A packet that anyone can send with another text for the name is not believed, the listing has its hash.
+    let packet = serde_json::json!({
+        "device_id": files.get_device().borrow().id,
+        "device_name": "",
+        "files": [],
+        "fingerprint": packet_fingerprint,
+        "version": PROTOCOL_VERSION,
+        "capabilities": CAPABILITIES,
+        "revision": listing_revision(&protocol::listed_files(&files.get_local_files().borrow())),
+        "texts": [{ "name": "Text 1", "text": "https://example.com/forged" }],
+    });
+    let spoofer = tokio::net::UdpSocket::bind((Ipv4Addr::LOCALHOST, 0))
+        .await
+        .unwrap();
+    spoofer
+        .send_to(
+            &serde_json::to_vec(&packet).unwrap(),
+            (Ipv4Addr::LOCALHOST, port),
+        )
+        .await
+        .unwrap();
+    let is_short = |r: &RemoteFile| r.file == "Text 1" && r.text.as_ref() == Some(&short);
+    let remote_files =
+        wait_for_remote_files(&mut remote_files_rx, |r| !r.iter().any(is_short)).await;
+    assert_eq!(
+        None,
+        remote_files
+            .iter()
+            .find(|r| r.file == "Text 1")
+            .unwrap()
+            .text
+    );
+}
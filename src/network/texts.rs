@@ -0,0 +1,99 @@
+//! This module contains fetching shared texts, which are sent in one frame instead of as a tar stream.
+//!
+//! Short public texts come with discovery and are not fetched at all, see [super::discovery::beacon].
+
+use std::sync::Arc;
+
+use color_eyre::{
+    eyre::{eyre, WrapErr},
+    Result,
+};
+use tokio::io::AsyncWriteExt;
+
+use super::{
+    protocol::{self, ErrorResponse, Request, Response, TextRequest},
+    secure::{self, Identity, SecureStream},
+    server,
+};
+use crate::common::{Files, LocalFile, Refusal, RemoteFile, TextStatus};
+
+/// Fetches the texts the user asks for, each in its own task.
+/// If nothing fails, the function will never return.
+pub async fn run_text_requests(files: Arc<Files>, identity: Arc<Identity>) -> Result<()> {
+    loop {
+        let (remote_file, password) = files
+            .next_text_request()
+            .await
+            .ok_or_else(|| eyre!("text request channel sender closed"))?;
+        let files = Arc::clone(&files);
+        let identity = Arc::clone(&identity);
+        tokio::spawn(async move {
+            let status = match fetch_text(&remote_file, password.as_deref(), &identity).await {
+                Ok(text) => TextStatus::Fetched(text),
+                Err(report) => match report.downcast::<Refusal>() {
+                    Ok(refusal) => TextStatus::Refused(refusal),
+                    Err(report) => {
+                        tracing::error!("Fetching {} failed: {:?}", remote_file.file, report);
+                        TextStatus::Failed(report.to_string())
+                    }
+                },
+            };
+            files.set_text_status(remote_file, Some(status));
+        });
+    }
+}
+
+pub(super) async fn fetch_text(
+    remote_file: &RemoteFile,
+    password: Option<&str>,
+    identity: &Identity,
+) -> Result<String> {
+    if let Some(msg) = remote_file.update_required() {
+        return Err(eyre!(msg));
+    }
+    let stream = super::connect(&remote_file.addrs).await?;
+    let mut stream = secure::connect(stream, identity)
+        .await
+        .wrap_err("failed to establish encrypted session")?;
+    if stream.remote_fingerprint() != remote_file.fingerprint {
+        return Err(eyre!("peer key does not match the advertised fingerprint"));
+    }
+    let request = Request::Text(TextRequest {
+        file: remote_file.file.clone(),
+        password: password.map(str::to_owned),
+    });
+    protocol::write_request(&mut stream, request)
+        .await
+        .wrap_err("failed to write request")?;
+    match protocol::read_response(&mut stream).await? {
+        Response::Text(text) => Ok(text),
+        response => Err(eyre!("unexpected response: {:?}", response)),
+    }
+}
+
+/// Sends a shared text on an established session, if the client may have it.
+pub async fn send_text(
+    mut stream: SecureStream,
+    files: &Files,
+    request: TextRequest,
+) -> Result<()> {
+    let fingerprint = stream.remote_fingerprint().to_owned();
+    let text =
+        match files.authorize_download(&request.file, &fingerprint, request.password.as_deref()) {
+            Some(Ok(LocalFile::Text { text, .. })) => text,
+            Some(Ok(LocalFile::Path { .. })) => {
+                return server::respond_error(stream, ErrorResponse::BadRequest).await
+            }
+            Some(Err(refusal)) => {
+                return server::respond_error(stream, ErrorResponse::Denied(refusal)).await
+            }
+            None => return server::respond_error(stream, ErrorResponse::NotFound).await,
+        };
+    protocol::write_frame(&mut stream, &Response::Text(text))
+        .await
+        .wrap_err("failed to write text")?;
+    stream
+        .shutdown()
+        .await
+        .wrap_err("failed to shut down the stream")
+}
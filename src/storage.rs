@@ -0,0 +1,61 @@
+//! This module contains functions to keep state in files between runs.
+
+use std::{io, path::PathBuf};
+
+use color_eyre::{
+    eyre::{eyre, WrapErr},
+    Result,
+};
+use directories_next::ProjectDirs;
+use serde::{de::DeserializeOwned, Serialize};
+
+/// Matches the name the user interface stores its own state under.
+const APP_NAME: &str = "Shary";
+
+fn path(name: &str) -> Result<PathBuf> {
+    let dirs =
+        ProjectDirs::from("", "", APP_NAME).ok_or_else(|| eyre!("no home directory found"))?;
+    Ok(dirs.data_dir().join(name))
+}
+
+/// Loads a value stored with [save], or returns [None] if nothing has been stored yet.
+pub fn load<T: DeserializeOwned>(name: &str) -> Result<Option<T>> {
+    let path = path(name)?;
+    let data = match std::fs::read(&path) {
+        Ok(data) => data,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err).wrap_err_with(|| format!("failed to read {}", path.display())),
+    };
+    let value = serde_json::from_slice(&data)
+        .wrap_err_with(|| format!("failed to parse {}", path.display()))?;
+    Ok(Some(value))
+}
+
+/// Stores a value so that only the current user can read it.
+/// The value is written to a temporary file that then replaces the stored one, so that a process that
+/// stores at the same time or stops while writing never leaves a truncated file behind.
+pub fn save<T: Serialize>(name: &str, value: &T) -> Result<()> {
+    let path = path(name)?;
+    let parent = path
+        .parent()
+        .ok_or_else(|| eyre!("{} has no parent", path.display()))?;
+    std::fs::create_dir_all(parent).wrap_err("failed to create data directory")?;
+    let data = serde_json::to_vec_pretty(value).wrap_err("failed to serialize value")?;
+    let suffix = random_string::generate(8, "abcdefghijklmnopqrstuvwxyz0123456789");
+    let temp = parent.join(format!(".{}.{}.tmp", name, suffix));
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let written = options
+        .open(&temp)
+        .and_then(|mut file| {
+            io::Write::write_all(&mut file, &data)?;
+            file.sync_all()
+        })
+        .and_then(|()| std::fs::rename(&temp, &path));
+    if written.is_err() {
+        let _ = std::fs::remove_file(&temp);
+    }
+    written.wrap_err_with(|| format!("failed to write {}", path.display()))
+}
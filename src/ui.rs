@@ -1,11 +1,16 @@
 use crate::{
-    common::{Files, LocalFile, RemoteFile},
-    ok_or_continue, some_or_continue,
+    common::{
+        format_bytes, format_duration, format_fingerprint, format_hash, format_meta, AcceptRule,
+        Capability, ConflictPolicy, Download, Files, LocalFile, Offer, OfferStatus, OutgoingOffer,
+        PairedPeer, Pairing, PairingStatus, Refusal, RemoteDevice, RemoteFile, SharePolicy,
+        TextStatus, Transfer, TransferOutcome,
+    },
+    some_or_continue,
 };
 use eframe::epaint::text::TextWrapping;
 use egui::{text::LayoutJob, InnerResponse, TextFormat, Ui};
 use rfd::FileDialog;
-use std::{path::PathBuf, sync::Arc};
+use std::{collections::HashMap, net::SocketAddr, path::PathBuf, sync::Arc, time::SystemTime};
 use tokio::{runtime::Runtime, sync::watch};
 
 const SIZE: egui::Vec2 = egui::Vec2 {
@@ -32,19 +37,71 @@ pub fn run(files: Arc<Files>) {
                 .build()
                 .expect("failed to create tokio runtime");
             let mut remote_files = files.get_remote_files();
+            let mut discovered_devices = files.get_discovered_devices();
+            let mut download_statuses = files.get_download_statuses();
+            let mut pairing = files.get_pairing();
+            let mut paired_peers = files.get_paired_peers();
+            let mut local_files = files.get_local_files();
+            let mut local_hashes = files.get_local_hashes();
+            let mut manual_peers = files.get_manual_peers();
+            let mut peer_errors = files.get_peer_errors();
+            let mut history = files.get_history();
+            let mut offers = files.get_offers();
+            let mut accept_rules = files.get_accept_rules();
+            let mut text_statuses = files.get_text_statuses();
             runtime.spawn(async move {
                 loop {
-                    let _ = remote_files.changed().await;
+                    tokio::select! {
+                        _ = local_files.changed() => {}
+                        _ = local_hashes.changed() => {}
+                        _ = manual_peers.changed() => {}
+                        _ = peer_errors.changed() => {}
+                        _ = remote_files.changed() => {}
+                        _ = discovered_devices.changed() => {}
+                        _ = download_statuses.changed() => {}
+                        _ = pairing.changed() => {}
+                        _ = paired_peers.changed() => {}
+                        _ = history.changed() => {}
+                        _ = offers.changed() => {}
+                        _ = accept_rules.changed() => {}
+                        _ = text_statuses.changed() => {}
+                    }
                     ctx.request_repaint();
                 }
             });
             let local_files = files.get_local_files();
+            let local_hashes = files.get_local_hashes();
             let remote_files = files.get_remote_files();
+            let discovered_devices = files.get_discovered_devices();
+            let pairing = files.get_pairing();
+            let paired_peers = files.get_paired_peers();
+            let manual_peers = files.get_manual_peers();
+            let peer_errors = files.get_peer_errors();
+            let history = files.get_history();
+            let offers = files.get_offers();
+            let accept_rules = files.get_accept_rules();
+            let device_name = files.get_device().borrow().name.clone();
             let app = App {
                 files,
                 local_files,
+                local_hashes,
                 remote_files,
-                _runtime: runtime,
+                discovered_devices,
+                pairing,
+                paired_peers,
+                manual_peers,
+                peer_errors,
+                history,
+                show_history: false,
+                offers,
+                accept_rules,
+                passwords: HashMap::new(),
+                copying: None,
+                new_text: String::new(),
+                device_name,
+                new_peer: String::new(),
+                conflict: ConflictPolicy::default(),
+                runtime,
             };
             Box::new(app)
         }),
@@ -53,31 +110,129 @@ pub fn run(files: Arc<Files>) {
 
 enum Action {
     AddSend(PathBuf),
+    AddText(String),
     RemoveSend(LocalFile),
-    Download(RemoteFile, PathBuf),
+    SetPolicy(LocalFile, SharePolicy),
+    SetDeviceName(String),
+    AddPeer(String),
+    RemovePeer(String),
+    Download(RemoteFile, PathBuf, Option<String>),
+    FetchText(RemoteFile, Option<String>),
+    Pair(Vec<SocketAddr>, String),
+    DecidePairing(bool),
+    ClearPairing,
+    ClearHistory,
+    Offer(OutgoingOffer),
+    DecideOffer(u64, Option<PathBuf>),
+    PauseOffer(u64),
+    ResumeOffer(u64),
+    CancelOffer(u64),
+    RemoveOffer(u64),
+    SetAcceptRule(AcceptRule),
+    RemoveAcceptRule(String),
 }
 
 struct App {
     files: Arc<Files>,
     local_files: watch::Receiver<Vec<LocalFile>>,
+    local_hashes: watch::Receiver<HashMap<PathBuf, String>>,
     remote_files: watch::Receiver<Arc<Vec<RemoteFile>>>,
-    _runtime: Runtime,
+    discovered_devices: watch::Receiver<Arc<Vec<RemoteDevice>>>,
+    pairing: watch::Receiver<Option<Pairing>>,
+    paired_peers: watch::Receiver<Vec<PairedPeer>>,
+    manual_peers: watch::Receiver<Vec<String>>,
+    peer_errors: watch::Receiver<HashMap<String, String>>,
+    history: watch::Receiver<Vec<Transfer>>,
+    /// Whether the history window is open.
+    show_history: bool,
+    offers: watch::Receiver<Vec<Offer>>,
+    accept_rules: watch::Receiver<Vec<AcceptRule>>,
+    /// The passwords typed for remote files that are protected by one.
+    passwords: HashMap<RemoteFile, String>,
+    /// The remote text to copy to the clipboard once it is fetched.
+    copying: Option<RemoteFile>,
+    /// The text to share as it is being typed.
+    new_text: String,
+    /// The device name as it is being edited.
+    device_name: String,
+    /// The address of a peer to add as it is being typed.
+    new_peer: String,
+    /// What downloads do with files that already exist.
+    conflict: ConflictPolicy,
+    runtime: Runtime,
 }
 
 impl eframe::App for App {
     fn update(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
         for dropped_file in ctx.input().raw.dropped_files.iter() {
             let path = some_or_continue!(dropped_file.path.clone());
-            let local_file = ok_or_continue!(LocalFile::new(path));
-            self.files.add_local_file(local_file);
+            self.add_local_path(path);
+        }
+        let pairing = self.pairing.borrow().clone();
+        if let Some(pairing) = pairing {
+            for action in draw_pairing(ctx, &pairing) {
+                self.handle_action(action);
+            }
+        }
+        let offers = self.offers.borrow().clone();
+        if !offers.is_empty() {
+            for action in draw_offers(ctx, &offers) {
+                self.handle_action(action);
+            }
+        }
+        if self.show_history {
+            let history = self.history.borrow().clone();
+            for action in draw_history(ctx, &history, &mut self.show_history) {
+                self.handle_action(action);
+            }
         }
+        egui::TopBottomPanel::top("device").show(ctx, |ui| {
+            ui.horizontal(|ui| {
+                ui.label("Device name");
+                let response = ui.text_edit_singleline(&mut self.device_name);
+                if response.lost_focus() {
+                    let name = self.device_name.clone();
+                    self.handle_action(Action::SetDeviceName(name));
+                }
+                let actions = self.draw_peers(ui);
+                for action in actions {
+                    self.handle_action(action);
+                }
+                for action in self.draw_accept_rules(ui) {
+                    self.handle_action(action);
+                }
+                ui.menu_button(format!("Existing files: {}", self.conflict), |ui| {
+                    for conflict in [
+                        ConflictPolicy::Rename,
+                        ConflictPolicy::Skip,
+                        ConflictPolicy::Overwrite,
+                    ] {
+                        if ui
+                            .radio_value(&mut self.conflict, conflict, conflict.to_string())
+                            .clicked()
+                        {
+                            ui.close_menu();
+                        }
+                    }
+                });
+                if ui.selectable_label(self.show_history, "History").clicked() {
+                    self.show_history = !self.show_history;
+                }
+            });
+        });
         egui::CentralPanel::default().show(ctx, |ui| {
-            let remote_files = self.remote_files.borrow().clone();
+            let mut remote_files = self.remote_files.borrow().to_vec();
+            remote_files.sort_by(|a, b| {
+                (&a.device_name, &a.device_id, &a.file).cmp(&(
+                    &b.device_name,
+                    &b.device_id,
+                    &b.file,
+                ))
+            });
             let actions = self.draw(ui, &remote_files);
-            let _updated = actions
-                .into_iter()
-                .map(|a| self.handle_action(a))
-                .any(|a| a);
+            for action in actions {
+                self.handle_action(action);
+            }
         });
     }
 }
@@ -85,6 +240,7 @@ impl eframe::App for App {
 impl App {
     fn draw(&mut self, ui: &mut egui::Ui, remote_files: &[RemoteFile]) -> Vec<Action> {
         let mut actions = vec![];
+        let devices = self.offer_devices(remote_files);
 
         let columns = GRID_COLUMNS as f32;
         let spacing = ui.spacing();
@@ -96,7 +252,24 @@ impl App {
             .max_col_width(width)
             .show(ui, |ui| {
                 let mut count = 0;
+                let mut device_id = None;
                 for remote_file in remote_files.iter() {
+                    if device_id != Some(&remote_file.device_id) {
+                        device_id = Some(&remote_file.device_id);
+                        if count % GRID_COLUMNS != 0 {
+                            ui.end_row();
+                        }
+                        let addrs: Vec<String> =
+                            remote_file.addrs.iter().map(ToString::to_string).collect();
+                        let addr = match &remote_file.interface {
+                            Some(interface) => format!("{} on {}", addrs.join(", "), interface),
+                            None => addrs.join(", "),
+                        };
+                        ui.label(egui::RichText::new(&remote_file.device_name).strong())
+                            .on_hover_text(addr);
+                        ui.end_row();
+                        count = 0;
+                    }
                     cell(ui, |ui| {
                         let mut job = LayoutJob::single_section(
                             remote_file.file.clone(),
@@ -109,15 +282,126 @@ impl App {
                             max_width: ui.available_width(),
                         };
                         ui.label(job);
-                        ui.add_space(16f32);
+                        if let Some(meta) = &remote_file.meta {
+                            let icon = if meta.is_text {
+                                "🗒"
+                            } else if meta.is_dir {
+                                "🗀"
+                            } else {
+                                "🗋"
+                            };
+                            let label = ui.label(
+                                egui::RichText::new(format!("{} {}", icon, format_meta(meta)))
+                                    .small(),
+                            );
+                            if let Some(age) = meta.age() {
+                                label.on_hover_text(format!(
+                                    "Modified {} ago",
+                                    format_duration(age)
+                                ));
+                            }
+                        }
+                        let paired = self.files.is_paired(&remote_file.fingerprint);
+                        let fingerprint = format_fingerprint(&remote_file.fingerprint);
+                        let fingerprint = if paired {
+                            egui::RichText::new(format!("✔ {}", fingerprint))
+                                .small()
+                                .color(egui::Color32::GREEN)
+                        } else {
+                            egui::RichText::new(fingerprint).small().weak()
+                        };
+                        ui.label(fingerprint)
+                            .on_hover_text(&remote_file.fingerprint);
+                        if let Some(msg) = remote_file.update_required() {
+                            ui.label(
+                                egui::RichText::new("Update required")
+                                    .small()
+                                    .color(egui::Color32::YELLOW),
+                            )
+                            .on_hover_text(msg);
+                            return;
+                        }
+                        if !paired
+                            && remote_file.supports(Capability::Pair)
+                            && ui.small_button("Pair").clicked()
+                        {
+                            actions.push(Action::Pair(
+                                remote_file.addrs.clone(),
+                                remote_file.fingerprint.clone(),
+                            ));
+                        }
+                        ui.add_space(8f32);
+                        if remote_file.meta.as_ref().is_some_and(|meta| meta.is_text) {
+                            self.draw_remote_text(ui, remote_file, &mut actions);
+                            return;
+                        }
                         match self.files.get_download_status(remote_file) {
                             Some(status) => {
                                 match status {
-                                    crate::common::DownloadStatus::Running => {
-                                        ui.spinner();
+                                    crate::common::DownloadStatus::Queued => {
+                                        ui.label("Queued");
+                                        if ui.button("Cancel").clicked() {
+                                            self.files.cancel_download(remote_file);
+                                        }
                                     }
-                                    crate::common::DownloadStatus::Completed => {
+                                    crate::common::DownloadStatus::Running(progress) => {
+                                        ui.add(
+                                            egui::ProgressBar::new(progress.fraction())
+                                                .show_percentage(),
+                                        );
+                                        ui.label(format!(
+                                            "{} of {}",
+                                            format_bytes(progress.received),
+                                            format_bytes(progress.total)
+                                        ));
+                                        if let Some(eta) = progress.eta {
+                                            ui.label(format!(
+                                                "{}/s, {} left",
+                                                format_bytes(progress.rate),
+                                                format_duration(eta)
+                                            ));
+                                        }
+                                        if ui.button("Pause").clicked() {
+                                            self.files.pause_download(remote_file);
+                                        }
+                                        if ui.button("Cancel").clicked() {
+                                            self.files.cancel_download(remote_file);
+                                        }
+                                    }
+                                    crate::common::DownloadStatus::Paused(progress) => {
+                                        ui.add(egui::ProgressBar::new(progress.fraction()));
+                                        ui.label(format!(
+                                            "Paused at {} of {}",
+                                            format_bytes(progress.received),
+                                            format_bytes(progress.total)
+                                        ));
+                                        if ui.button("Resume").clicked() {
+                                            self.files.resume_download(remote_file);
+                                        }
+                                        if ui.button("Cancel").clicked() {
+                                            self.files.cancel_download(remote_file);
+                                        }
+                                    }
+                                    crate::common::DownloadStatus::Cancelled => {
+                                        ui.label("Download cancelled");
+                                        if ui.button("OK").clicked() {
+                                            self.files
+                                                .set_download_status(remote_file.clone(), None);
+                                        }
+                                    }
+                                    crate::common::DownloadStatus::Completed(hash) => {
                                         ui.label("Download successful");
+                                        if let Some(hash) = hash {
+                                            ui.label(
+                                                egui::RichText::new(format!(
+                                                    "✔ {}",
+                                                    format_hash(&hash)
+                                                ))
+                                                .small()
+                                                .color(egui::Color32::GREEN),
+                                            )
+                                            .on_hover_text(format!("Verified, BLAKE3 {}", hash));
+                                        }
                                         if ui.button("OK").clicked() {
                                             self.files
                                                 .set_download_status(remote_file.clone(), None);
@@ -131,13 +415,48 @@ impl App {
                                                 .set_download_status(remote_file.clone(), None);
                                         }
                                     }
+                                    crate::common::DownloadStatus::Refused(
+                                        refusal @ (Refusal::PasswordRequired
+                                        | Refusal::WrongPassword),
+                                    ) => {
+                                        ui.label(refusal.to_string());
+                                        let password =
+                                            self.passwords.entry(remote_file.clone()).or_default();
+                                        ui.add(
+                                            egui::TextEdit::singleline(password)
+                                                .password(true)
+                                                .hint_text("Password"),
+                                        );
+                                        if ui.button("Download").clicked() {
+                                            let path = FileDialog::new().pick_folder();
+                                            if let Some(path) = path {
+                                                actions.push(Action::Download(
+                                                    remote_file.clone(),
+                                                    path,
+                                                    Some(password.clone()),
+                                                ))
+                                            }
+                                        }
+                                    }
+                                    crate::common::DownloadStatus::Refused(refusal) => {
+                                        ui.label("Download refused:");
+                                        ui.label(refusal.to_string());
+                                        if ui.button("OK").clicked() {
+                                            self.files
+                                                .set_download_status(remote_file.clone(), None);
+                                        }
+                                    }
                                 };
                             }
                             None => {
                                 if ui.button("Download").clicked() {
                                     let path = FileDialog::new().pick_folder();
                                     if let Some(path) = path {
-                                        actions.push(Action::Download(remote_file.clone(), path))
+                                        actions.push(Action::Download(
+                                            remote_file.clone(),
+                                            path,
+                                            None,
+                                        ))
                                     }
                                 }
                             }
@@ -148,11 +467,19 @@ impl App {
                         ui.end_row();
                     }
                 }
+                if count % GRID_COLUMNS != 0 {
+                    ui.end_row();
+                }
+                ui.label(egui::RichText::new("This device").strong());
+                ui.end_row();
+                count = 0;
                 let local_files = self.local_files.borrow();
+                let local_hashes = self.local_hashes.borrow();
+                let paired_peers = self.paired_peers.borrow();
                 for local_file in local_files.iter() {
                     cell(ui, |ui| {
                         let mut job = LayoutJob::single_section(
-                            local_file.name.clone(),
+                            local_file.name().to_owned(),
                             TextFormat::default(),
                         );
                         job.wrap = TextWrapping {
@@ -162,6 +489,16 @@ impl App {
                             max_width: ui.available_width(),
                         };
                         ui.label(job);
+                        if let Some(hash) =
+                            local_file.path().and_then(|path| local_hashes.get(path))
+                        {
+                            ui.label(egui::RichText::new(format_hash(hash)).small().weak())
+                                .on_hover_text(format!("BLAKE3 {}", hash));
+                        }
+                        draw_policy(ui, local_file, &paired_peers, &mut actions);
+                        if local_file.path().is_some() {
+                            draw_send_to(ui, local_file, &devices, &mut actions);
+                        }
                         ui.add_space(8f32);
                         if ui.button("Stop sharing").clicked() {
                             actions.push(Action::RemoveSend(local_file.clone()));
@@ -188,26 +525,611 @@ impl App {
                         }
                     }
                     ui.label("or drag and drop");
+                    ui.label("or");
+                    ui.menu_button("text", |ui| {
+                        ui.add(
+                            egui::TextEdit::multiline(&mut self.new_text)
+                                .hint_text("A URL, a token or a log snippet"),
+                        );
+                        if ui.button("Share").clicked() && !self.new_text.trim().is_empty() {
+                            actions.push(Action::AddText(std::mem::take(&mut self.new_text)));
+                            ui.close_menu();
+                        }
+                    });
                 });
             });
         actions
     }
 
+    /// The devices that files can be sent to, which are the discovered devices and the devices of the
+    /// remote files, so that peers that were added by address are included.
+    fn offer_devices(&self, remote_files: &[RemoteFile]) -> Vec<RemoteDevice> {
+        let mut devices = self.discovered_devices.borrow().to_vec();
+        for remote_file in remote_files {
+            if !devices.iter().any(|d| d.device_id == remote_file.device_id) {
+                devices.push(remote_file.device());
+            }
+        }
+        devices.retain(|d| d.capabilities.contains(&Capability::Offer));
+        devices.sort_by(|a, b| (&a.device_name, &a.device_id).cmp(&(&b.device_name, &b.device_id)));
+        devices
+    }
+
+    /// Draws a text a peer shares with a button to copy it, which fetches the text first if it did not come
+    /// with discovery.
+    fn draw_remote_text(
+        &mut self,
+        ui: &mut Ui,
+        remote_file: &RemoteFile,
+        actions: &mut Vec<Action>,
+    ) {
+        let status = match &remote_file.text {
+            Some(text) => Some(TextStatus::Fetched(text.clone())),
+            None => self.files.get_text_status(remote_file),
+        };
+        match status {
+            None => {
+                if ui.button("Copy").clicked() {
+                    self.copying = Some(remote_file.clone());
+                    actions.push(Action::FetchText(remote_file.clone(), None));
+                }
+            }
+            Some(TextStatus::Fetching) => {
+                ui.spinner();
+            }
+            Some(TextStatus::Fetched(text)) => {
+                let mut job = LayoutJob::single_section(text.clone(), TextFormat::default());
+                job.wrap = TextWrapping {
+                    max_rows: 3,
+                    break_anywhere: true,
+                    overflow_character: Some('…'),
+                    max_width: ui.available_width(),
+                };
+                ui.label(job);
+                if ui.button("Copy").clicked() || self.copying.as_ref() == Some(remote_file) {
+                    self.copying = None;
+                    ui.output().copied_text = text;
+                }
+            }
+            Some(TextStatus::Refused(
+                refusal @ (Refusal::PasswordRequired | Refusal::WrongPassword),
+            )) => {
+                ui.label(refusal.to_string());
+                let password = self.passwords.entry(remote_file.clone()).or_default();
+                ui.add(
+                    egui::TextEdit::singleline(password)
+                        .password(true)
+                        .hint_text("Password"),
+                );
+                if ui.button("Copy").clicked() {
+                    self.copying = Some(remote_file.clone());
+                    actions.push(Action::FetchText(
+                        remote_file.clone(),
+                        Some(password.clone()),
+                    ));
+                }
+            }
+            Some(TextStatus::Refused(refusal)) => {
+                ui.label("Copy refused:");
+                ui.label(refusal.to_string());
+                if ui.button("OK").clicked() {
+                    self.files.set_text_status(remote_file.clone(), None);
+                }
+            }
+            Some(TextStatus::Failed(msg)) => {
+                ui.label("Copy failed:");
+                ui.label(msg);
+                if ui.button("OK").clicked() {
+                    self.files.set_text_status(remote_file.clone(), None);
+                }
+            }
+        }
+    }
+
+    /// Draws a menu with the peers that were added by address and a field to add another.
+    fn draw_peers(&mut self, ui: &mut Ui) -> Vec<Action> {
+        let mut actions = vec![];
+        let manual_peers = self.manual_peers.borrow().clone();
+        let peer_errors = self.peer_errors.borrow().clone();
+        let title = if peer_errors.is_empty() {
+            format!("Peers ({})", manual_peers.len())
+        } else {
+            format!("Peers ({}, ⚠ {})", manual_peers.len(), peer_errors.len())
+        };
+        ui.menu_button(title, |ui| {
+            ui.label("Peers that are not found automatically, like peers in other networks.");
+            for peer in manual_peers.iter() {
+                ui.horizontal(|ui| {
+                    if ui.button("✖").clicked() {
+                        actions.push(Action::RemovePeer(peer.clone()));
+                    }
+                    match peer_errors.get(peer) {
+                        Some(error) => {
+                            ui.colored_label(egui::Color32::YELLOW, peer)
+                                .on_hover_text(error);
+                        }
+                        None => {
+                            ui.label(peer);
+                        }
+                    }
+                });
+            }
+            ui.horizontal(|ui| {
+                let response = ui.add(
+                    egui::TextEdit::singleline(&mut self.new_peer).hint_text("host or host:port"),
+                );
+                let entered = response.lost_focus() && ui.input().key_pressed(egui::Key::Enter);
+                if (ui.button("Add").clicked() || entered) && !self.new_peer.trim().is_empty() {
+                    actions.push(Action::AddPeer(std::mem::take(&mut self.new_peer)));
+                }
+            });
+        });
+        actions
+    }
+
+    /// Draws a menu with the paired peers whose offered files are accepted without asking.
+    fn draw_accept_rules(&self, ui: &mut Ui) -> Vec<Action> {
+        let mut actions = vec![];
+        let accept_rules = self.accept_rules.borrow().clone();
+        let paired_peers = self.paired_peers.borrow().clone();
+        ui.menu_button(format!("Auto-accept ({})", accept_rules.len()), |ui| {
+            ui.label("Files that these peers offer are stored without asking.");
+            if paired_peers.is_empty() {
+                ui.label("Pair with a device to accept its files.");
+            }
+            for peer in paired_peers.iter() {
+                let rule = accept_rules
+                    .iter()
+                    .find(|r| r.fingerprint == peer.fingerprint);
+                ui.horizontal(|ui| {
+                    let mut checked = rule.is_some();
+                    let label = if peer.name.is_empty() {
+                        format_fingerprint(&peer.fingerprint)
+                    } else {
+                        format!("{} ({})", peer.name, format_fingerprint(&peer.fingerprint))
+                    };
+                    if ui.checkbox(&mut checked, label).changed() {
+                        actions.push(if checked {
+                            Action::SetAcceptRule(AcceptRule {
+                                conflict: self.conflict,
+                                ..AcceptRule::new(peer.fingerprint.clone())
+                            })
+                        } else {
+                            Action::RemoveAcceptRule(peer.fingerprint.clone())
+                        });
+                    }
+                    let rule = match rule {
+                        Some(rule) => rule,
+                        None => return,
+                    };
+                    let folder = ui
+                        .button("📁")
+                        .on_hover_text(rule.folder.display().to_string());
+                    if folder.clicked() {
+                        if let Some(folder) =
+                            FileDialog::new().set_directory(&rule.folder).pick_folder()
+                        {
+                            actions.push(Action::SetAcceptRule(AcceptRule {
+                                folder,
+                                ..rule.clone()
+                            }));
+                        }
+                    }
+                    let mut megabytes = rule.max_size.map_or(0, |max_size| max_size / 1_000_000);
+                    let max_size = ui
+                        .add(egui::DragValue::new(&mut megabytes).suffix(" MB"))
+                        .on_hover_text("The largest file that is accepted, 0 accepts any size.");
+                    if max_size.changed() {
+                        actions.push(Action::SetAcceptRule(AcceptRule {
+                            max_size: (megabytes > 0).then_some(megabytes * 1_000_000),
+                            ..rule.clone()
+                        }));
+                    }
+                    ui.menu_button(rule.conflict.to_string(), |ui| {
+                        for conflict in [
+                            ConflictPolicy::Rename,
+                            ConflictPolicy::Skip,
+                            ConflictPolicy::Overwrite,
+                        ] {
+                            if ui
+                                .radio(rule.conflict == conflict, conflict.to_string())
+                                .clicked()
+                            {
+                                actions.push(Action::SetAcceptRule(AcceptRule {
+                                    conflict,
+                                    ..rule.clone()
+                                }));
+                                ui.close_menu();
+                            }
+                        }
+                    })
+                    .response
+                    .on_hover_text("What to do if a file already exists in the folder.");
+                });
+            }
+        });
+        actions
+    }
+
+    /// Shares a file or folder once its metadata is read, which takes a while for large folders.
+    fn add_local_path(&self, path: PathBuf) {
+        let files = Arc::clone(&self.files);
+        self.runtime
+            .spawn_blocking(move || match LocalFile::new(path.clone()) {
+                Ok(local_file) => {
+                    files.add_local_file(local_file);
+                }
+                Err(err) => tracing::warn!("Failed to share {}: {:?}", path.display(), err),
+            });
+    }
+
     fn handle_action(&mut self, action: Action) -> bool {
         match action {
-            Action::AddSend(path) => match LocalFile::new(path) {
+            Action::AddSend(path) => {
+                self.add_local_path(path);
+                false
+            }
+            Action::AddText(text) => match LocalFile::text(text) {
                 Ok(local_file) => self.files.add_local_file(local_file),
                 Err(_) => false,
             },
             Action::RemoveSend(local_file) => self.files.remove_local_file(&local_file),
-            Action::Download(file, path) => {
-                self.files.add_download(file, path);
+            Action::SetPolicy(local_file, policy) => {
+                self.files.set_share_policy(&local_file, policy)
+            }
+            Action::SetDeviceName(name) => {
+                let modified = self.files.set_device_name(&name);
+                self.device_name = self.files.get_device().borrow().name.clone();
+                modified
+            }
+            Action::AddPeer(peer) => self.files.add_manual_peer(&peer),
+            Action::RemovePeer(peer) => self.files.remove_manual_peer(&peer),
+            Action::Download(remote_file, path, password) => {
+                self.files.add_download(Download {
+                    remote_file,
+                    path,
+                    password,
+                    conflict: self.conflict,
+                });
+                false
+            }
+            Action::FetchText(remote_file, password) => {
+                self.files.fetch_text(remote_file, password);
+                false
+            }
+            Action::Pair(addrs, fingerprint) => {
+                self.files.pair(addrs, fingerprint);
+                false
+            }
+            Action::DecidePairing(accepted) => {
+                self.files.decide_pairing(accepted);
+                false
+            }
+            Action::ClearPairing => {
+                self.files.clear_pairing();
+                false
+            }
+            Action::ClearHistory => {
+                self.files.clear_history();
+                false
+            }
+            Action::Offer(offer) => {
+                self.files.offer_file(offer);
+                false
+            }
+            Action::DecideOffer(id, path) => {
+                self.files
+                    .decide_offer(id, path.map(|path| (path, self.conflict)));
+                false
+            }
+            Action::PauseOffer(id) => {
+                self.files.pause_offer(id);
+                false
+            }
+            Action::ResumeOffer(id) => {
+                self.files.resume_offer(id);
+                false
+            }
+            Action::CancelOffer(id) => {
+                self.files.cancel_offer(id);
+                false
+            }
+            Action::RemoveOffer(id) => {
+                self.files.remove_offer(id);
+                false
+            }
+            Action::SetAcceptRule(rule) => {
+                self.files.set_accept_rule(rule);
+                false
+            }
+            Action::RemoveAcceptRule(fingerprint) => {
+                self.files.remove_accept_rule(&fingerprint);
                 false
             }
         }
     }
 }
 
+/// Draws who may download a local file and a menu to change it.
+fn draw_policy(
+    ui: &mut Ui,
+    local_file: &LocalFile,
+    paired_peers: &[PairedPeer],
+    actions: &mut Vec<Action>,
+) {
+    let policy = local_file.policy();
+    let title = match policy {
+        SharePolicy::Public => String::from("Anyone"),
+        SharePolicy::Peers(peers) => format!("{} paired", peers.len()),
+        SharePolicy::Password { .. } => String::from("Password"),
+    };
+    ui.menu_button(title, |ui| {
+        if ui
+            .radio(matches!(policy, SharePolicy::Public), "Anyone")
+            .clicked()
+        {
+            actions.push(Action::SetPolicy(local_file.clone(), SharePolicy::Public));
+            ui.close_menu();
+        }
+        let has_password = matches!(policy, SharePolicy::Password { .. });
+        if ui.radio(has_password, "One-time password").clicked() && !has_password {
+            actions.push(Action::SetPolicy(
+                local_file.clone(),
+                SharePolicy::password(),
+            ));
+            ui.close_menu();
+        }
+        ui.separator();
+        if paired_peers.is_empty() {
+            ui.label("Pair with a device to share only with it.");
+        }
+        let selected = match policy {
+            SharePolicy::Peers(peers) => peers.clone(),
+            _ => vec![],
+        };
+        for peer in paired_peers {
+            let mut checked = selected.contains(&peer.fingerprint);
+            if ui
+                .checkbox(&mut checked, format_fingerprint(&peer.fingerprint))
+                .changed()
+            {
+                let mut peers = selected.clone();
+                peers.retain(|p| *p != peer.fingerprint);
+                if checked {
+                    peers.push(peer.fingerprint.clone());
+                }
+                actions.push(Action::SetPolicy(
+                    local_file.clone(),
+                    SharePolicy::Peers(peers),
+                ));
+            }
+        }
+    });
+    if let SharePolicy::Password { password, .. } = policy {
+        ui.label(egui::RichText::new(password).monospace());
+    }
+}
+
+/// Draws a menu with the peers that a local file can be sent to.
+fn draw_send_to(
+    ui: &mut Ui,
+    local_file: &LocalFile,
+    devices: &[RemoteDevice],
+    actions: &mut Vec<Action>,
+) {
+    ui.menu_button("Send to", |ui| {
+        if devices.is_empty() {
+            ui.label("No peer that can receive files was found.");
+        }
+        for peer in devices {
+            if ui.button(&peer.device_name).clicked() {
+                actions.push(Action::Offer(OutgoingOffer {
+                    peer: peer.device_name.clone(),
+                    addrs: peer.addrs.clone(),
+                    fingerprint: Some(peer.fingerprint.clone()),
+                    local_file: local_file.clone(),
+                }));
+                ui.close_menu();
+            }
+        }
+    });
+}
+
+/// Draws the files that are offered to or by peers.
+fn draw_offers(ctx: &egui::Context, offers: &[Offer]) -> Vec<Action> {
+    let mut actions = vec![];
+    egui::Window::new("Offers")
+        .collapsible(false)
+        .resizable(false)
+        .anchor(egui::Align2::CENTER_BOTTOM, egui::Vec2::ZERO)
+        .show(ctx, |ui| {
+            for offer in offers {
+                let title = if offer.outgoing {
+                    format!("{} to {}", offer.file, offer.peer)
+                } else {
+                    format!("{} from {}", offer.file, offer.peer)
+                };
+                ui.horizontal(|ui| {
+                    ui.label(egui::RichText::new(title).strong());
+                    ui.label(egui::RichText::new(format_meta(&offer.meta)).small());
+                });
+                ui.horizontal(|ui| {
+                    match &offer.status {
+                        OfferStatus::Pending if offer.outgoing => {
+                            ui.spinner();
+                            ui.label("Waiting for the peer to accept");
+                        }
+                        OfferStatus::Pending => {
+                            ui.label(
+                                egui::RichText::new(format_fingerprint(&offer.fingerprint)).small(),
+                            )
+                            .on_hover_text(&offer.fingerprint);
+                            if ui.button("Accept").clicked() {
+                                if let Some(path) = FileDialog::new().pick_folder() {
+                                    actions.push(Action::DecideOffer(offer.id, Some(path)));
+                                }
+                            }
+                            if ui.button("Decline").clicked() {
+                                actions.push(Action::DecideOffer(offer.id, None));
+                            }
+                        }
+                        OfferStatus::Accepted(..) | OfferStatus::Transferring(None) => {
+                            ui.spinner();
+                        }
+                        OfferStatus::Queued => {
+                            ui.label("Queued");
+                            if ui.button("Cancel").clicked() {
+                                actions.push(Action::CancelOffer(offer.id));
+                            }
+                        }
+                        OfferStatus::Transferring(Some(progress)) => {
+                            ui.add(egui::ProgressBar::new(progress.fraction()).show_percentage());
+                            if ui.button("Pause").clicked() {
+                                actions.push(Action::PauseOffer(offer.id));
+                            }
+                            if ui.button("Cancel").clicked() {
+                                actions.push(Action::CancelOffer(offer.id));
+                            }
+                        }
+                        OfferStatus::Paused(progress) => {
+                            ui.add(egui::ProgressBar::new(progress.fraction()));
+                            if ui.button("Resume").clicked() {
+                                actions.push(Action::ResumeOffer(offer.id));
+                            }
+                            if ui.button("Cancel").clicked() {
+                                actions.push(Action::CancelOffer(offer.id));
+                            }
+                        }
+                        OfferStatus::Cancelled => {
+                            ui.label("Cancelled");
+                        }
+                        OfferStatus::Completed(Some(hash)) => {
+                            ui.colored_label(
+                                egui::Color32::GREEN,
+                                format!("✔ {}", format_hash(hash)),
+                            )
+                            .on_hover_text(format!("BLAKE3 {}", hash));
+                        }
+                        OfferStatus::Completed(None) => {
+                            ui.colored_label(egui::Color32::GREEN, "✔");
+                        }
+                        OfferStatus::Declined => {
+                            ui.label("Declined");
+                        }
+                        OfferStatus::Failed(msg) => {
+                            ui.label(format!("Failed: {}", msg));
+                        }
+                    }
+                    if offer.status.is_finished() && ui.button("OK").clicked() {
+                        actions.push(Action::RemoveOffer(offer.id));
+                    }
+                });
+                ui.separator();
+            }
+        });
+    actions
+}
+
+fn draw_pairing(ctx: &egui::Context, pairing: &Pairing) -> Vec<Action> {
+    let mut actions = vec![];
+    egui::Window::new("Pairing")
+        .collapsible(false)
+        .resizable(false)
+        .anchor(egui::Align2::CENTER_CENTER, egui::Vec2::ZERO)
+        .show(ctx, |ui| {
+            ui.vertical_centered(|ui| {
+                ui.label(egui::RichText::new(format_fingerprint(&pairing.fingerprint)).small())
+                    .on_hover_text(&pairing.fingerprint);
+                if let Some(code) = &pairing.code {
+                    ui.label(egui::RichText::new(code).size(32f32).monospace());
+                }
+                match &pairing.status {
+                    PairingStatus::Connecting => {
+                        ui.spinner();
+                    }
+                    PairingStatus::Confirming => {
+                        ui.label("Confirm that the other device shows the same code.");
+                        ui.horizontal(|ui| {
+                            if ui.button("Confirm").clicked() {
+                                actions.push(Action::DecidePairing(true));
+                            }
+                            if ui.button("Cancel").clicked() {
+                                actions.push(Action::DecidePairing(false));
+                            }
+                        });
+                    }
+                    PairingStatus::Waiting => {
+                        ui.label("Waiting for the other device to confirm.");
+                        ui.spinner();
+                    }
+                    PairingStatus::Paired => {
+                        ui.label("Paired successfully");
+                        if ui.button("OK").clicked() {
+                            actions.push(Action::ClearPairing);
+                        }
+                    }
+                    PairingStatus::Failed(msg) => {
+                        ui.label("Pairing failed:");
+                        ui.label(msg);
+                        if ui.button("OK").clicked() {
+                            actions.push(Action::ClearPairing);
+                        }
+                    }
+                }
+            });
+        });
+    actions
+}
+
+/// Draws the finished downloads, newest first.
+fn draw_history(ctx: &egui::Context, history: &[Transfer], open: &mut bool) -> Vec<Action> {
+    let mut actions = vec![];
+    egui::Window::new("History")
+        .open(open)
+        .collapsible(false)
+        .anchor(egui::Align2::CENTER_CENTER, egui::Vec2::ZERO)
+        .show(ctx, |ui| {
+            if history.is_empty() {
+                ui.label("Nothing has been downloaded yet.");
+                return;
+            }
+            if ui.button("Clear").clicked() {
+                actions.push(Action::ClearHistory);
+            }
+            let now = SystemTime::now();
+            egui::ScrollArea::vertical().show(ui, |ui| {
+                for transfer in history.iter().rev() {
+                    let (icon, color) = match transfer.outcome {
+                        TransferOutcome::Completed => ("✔", egui::Color32::GREEN),
+                        TransferOutcome::Cancelled => ("⏹", egui::Color32::GRAY),
+                        _ => ("✖", egui::Color32::RED),
+                    };
+                    let mut details = transfer.outcome.to_string();
+                    details.push_str(&format!("\n{}", transfer.path.display()));
+                    if let Some(hash) = &transfer.hash {
+                        details.push_str(&format!("\nBLAKE3 {}", hash));
+                    }
+                    ui.horizontal(|ui| {
+                        ui.colored_label(color, icon);
+                        ui.label(format!("{} from {}", transfer.file, transfer.peer))
+                            .on_hover_text(details);
+                    });
+                    let ago = now.duration_since(transfer.finished).unwrap_or_default();
+                    let duration = format_duration(transfer.duration);
+                    let summary = match transfer.size {
+                        Some(size) => format!("{} in {}", format_bytes(size), duration),
+                        None => duration,
+                    };
+                    ui.label(
+                        egui::RichText::new(format!("{}, {} ago", summary, format_duration(ago)))
+                            .small(),
+                    );
+                }
+            });
+        });
+    actions
+}
+
 fn cell<R>(ui: &mut Ui, add_contents: impl FnOnce(&mut Ui) -> R) -> InnerResponse<R> {
     ui.group(|ui| {
         let width = ui.available_width();
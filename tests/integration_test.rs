@@ -1,4 +1,173 @@
+use std::{
+    net::{Ipv4Addr, TcpListener, TcpStream, UdpSocket},
+    path::{Path, PathBuf},
+    process::{Child, Command, Output, Stdio},
+    thread::sleep,
+    time::{Duration, Instant},
+};
+
+/// A `shary` process that is killed when it is dropped.
+struct Running(Child);
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+fn temp_dir() -> PathBuf {
+    let name = random_string::generate(12, "abcdefghijklmnopqrstuvwxyz");
+    let dir = std::env::temp_dir().join(format!("shary-cli-test-{}", name));
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// A port that the system picks, so that tests that run at the same time do not share one. The file
+/// server and discovery both use it, so it has to be free for TCP and UDP.
+fn free_port() -> u16 {
+    loop {
+        let listener = TcpListener::bind((Ipv4Addr::UNSPECIFIED, 0)).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        if UdpSocket::bind((Ipv4Addr::UNSPECIFIED, port)).is_ok() {
+            return port;
+        }
+    }
+}
+
+/// A `shary` command with its own home folder, so that it has its own identity and settings.
+fn shary(home: &Path, port: u16) -> Command {
+    let mut command = Command::new(env!("CARGO_BIN_EXE_shary"));
+    command
+        .env("HOME", home)
+        .env("XDG_DATA_HOME", home.join("data"))
+        .env("XDG_CONFIG_HOME", home.join("config"))
+        .args(["--port", &port.to_string()]);
+    command
+}
+
+/// Starts sharing and returns once the file server accepts connections.
+fn share(home: &Path, port: u16, args: &[&str]) -> Running {
+    let child = shary(home, port)
+        .arg("share")
+        .args(args)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    let mut running = Running(child);
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while TcpStream::connect((Ipv4Addr::LOCALHOST, port)).is_err() {
+        let status = running.0.try_wait().unwrap();
+        assert!(status.is_none(), "shary share exited with {:?}", status);
+        assert!(Instant::now() < deadline, "shary share is not listening");
+        sleep(Duration::from_millis(50));
+    }
+    running
+}
+
+fn run(home: &Path, port: u16, peer_port: u16, args: &[&str]) -> Output {
+    shary(home, port)
+        .args(["--peer", &format!("127.0.0.1:{}", peer_port)])
+        .args(args)
+        .output()
+        .unwrap()
+}
+
 #[test]
-fn fun_times() {
+fn cli_list_and_get() {
+    let shared = temp_dir().join("shared.txt");
+    std::fs::write(&shared, "Shared with the command line").unwrap();
+    let sharer_port = free_port();
+    let _sharer = share(&temp_dir(), sharer_port, &[shared.to_str().unwrap()]);
+
+    let (home, port) = (temp_dir(), free_port());
+    let output = run(&home, port, sharer_port, &["list", "--wait", "6"]);
+    assert!(output.status.success(), "{:?}", output);
+    let listed = String::from_utf8(output.stdout).unwrap();
+    let line = listed.lines().find(|line| line.ends_with("\tshared.txt"));
+    assert!(line.is_some(), "shared.txt is not listed: {}", listed);
+    assert!(line.unwrap().contains("\tunpaired\t"));
+
+    let destination = temp_dir();
+    let output = run(
+        &home,
+        port,
+        sharer_port,
+        &[
+            "get",
+            "127.0.0.1",
+            "shared.txt",
+            "--output",
+            destination.to_str().unwrap(),
+        ],
+    );
+    assert!(output.status.success(), "{:?}", output);
+    assert!(String::from_utf8(output.stdout)
+        .unwrap()
+        .contains("Downloaded shared.txt"));
+    assert_eq!(
+        std::fs::read_to_string(destination.join("shared.txt")).unwrap(),
+        "Shared with the command line"
+    );
+
+    // The download is kept in the history, which is stored without leaving temporary files behind.
+    let output = shary(&home, port).arg("history").output().unwrap();
+    assert!(String::from_utf8(output.stdout)
+        .unwrap()
+        .contains("\tshared.txt\t"));
+    let data = home.join("data").join("shary");
+    let stored: Vec<_> = std::fs::read_dir(&data)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+        .collect();
+    assert!(
+        stored.contains(&String::from("history.json")),
+        "{:?}",
+        stored
+    );
+    assert!(
+        stored.iter().all(|name| !name.ends_with(".tmp")),
+        "{:?}",
+        stored
+    );
+}
+
+#[test]
+fn cli_get_missing_file() {
+    let shared = temp_dir().join("shared.txt");
+    std::fs::write(&shared, "Shared with the command line").unwrap();
+    let sharer_port = free_port();
+    let _sharer = share(&temp_dir(), sharer_port, &[shared.to_str().unwrap()]);
+
+    let output = run(
+        &temp_dir(),
+        free_port(),
+        sharer_port,
+        &["get", "127.0.0.1", "missing.txt", "--timeout", "4"],
+    );
+    assert!(!output.status.success());
+    assert!(String::from_utf8(output.stderr)
+        .unwrap()
+        .contains("missing.txt is not shared by 127.0.0.1"));
+}
+
+#[test]
+fn cli_get_text() {
+    let sharer_port = free_port();
+    let _sharer = share(&temp_dir(), sharer_port, &["--text", "A shared text"]);
+
+    let (home, port) = (temp_dir(), free_port());
+    let output = run(&home, port, sharer_port, &["list", "--wait", "6"]);
+    assert!(output.status.success(), "{:?}", output);
+    let listed = String::from_utf8(output.stdout).unwrap();
+    let name = listed
+        .lines()
+        .next()
+        .and_then(|line| line.rsplit('\t').next());
+    let name = name.unwrap_or_else(|| panic!("no text is listed: {}", listed));
 
-}
\ No newline at end of file
+    let output = run(&home, port, sharer_port, &["get", "127.0.0.1", name]);
+    assert!(output.status.success(), "{:?}", output);
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "A shared text\n");
+}